@@ -0,0 +1,117 @@
+use std::io::{self, Read, BufRead, ErrorKind};
+use std::cmp;
+use std::str;
+
+///Reads the input in fixed size blocks, one read call per block, and splits the lines out of them for --stdin-block-size.  
+///A line that does not end in its block is carried over to the front of the next block, the block grows if a single line does not fit in it
+pub struct BlockReader<R> {
+    inner: R,
+    block: Vec<u8>,
+    ///the unread bytes of the block are block[start..end]
+    start: usize,
+    end: usize
+}
+
+impl<R: Read> BlockReader<R> {
+    pub fn new(inner: R, block_size: usize) -> BlockReader<R> {
+        BlockReader {
+            inner,
+            block: vec![0; block_size],
+            start: 0,
+            end: 0
+        }
+    }
+
+    ///Moves the unread bytes to the front and reads the next block after them.  
+    ///Returns the number of bytes read, 0 at the end of the input
+    fn read_block(&mut self) -> io::Result<usize> {
+        self.block.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        if self.end == self.block.len() {
+            let grown = self.block.len() * 2;
+            self.block.resize(grown, 0);
+        }
+        let read = loop {
+            match self.inner.read(&mut self.block[self.end..]) {
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                result => break result?
+            }
+        };
+        self.end += read;
+        Ok(read)
+    }
+
+    ///Appends the unread bytes up to line_end to buf
+    fn take_line(&mut self, line_end: usize, buf: &mut String) -> io::Result<usize> {
+        let line = str::from_utf8(&self.block[self.start..line_end])
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "stream did not contain valid UTF-8"))?;
+        buf.push_str(line);
+        let read = line_end - self.start;
+        self.start = line_end;
+        Ok(read)
+    }
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let amount = cmp::min(out.len(), available.len());
+        out[..amount].copy_from_slice(&available[..amount]);
+        self.consume(amount);
+        Ok(amount)
+    }
+}
+
+impl<R: Read> BufRead for BlockReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.start == self.end {
+            self.read_block()?;
+        }
+        Ok(&self.block[self.start..self.end])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.start = cmp::min(self.start + amount, self.end);
+    }
+
+    ///Splits the next line out of the current block, the next block is only read if the line does not end in this one
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        //the bytes after start that are already known to have no newline
+        let mut searched = 0;
+        loop {
+            if let Some(newline) = self.block[self.start + searched..self.end].iter().position(|&byte| byte == b'\n') {
+                return self.take_line(self.start + searched + newline + 1, buf);
+            }
+            searched = self.end - self.start;
+            if self.read_block()? == 0 {
+                return self.take_line(self.end, buf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_block_reader() {
+        //blocks of 4 bytes split "second" and the long line across blocks
+        let input = "a\nsecond\n\nthis line is longer than a block\nlast";
+        let mut reader = BlockReader::new(Cursor::new(input), 4);
+        let mut lines = vec![];
+        let mut buf = String::new();
+        while reader.read_line(&mut buf).unwrap() > 0 {
+            lines.push(buf.clone());
+            buf.clear();
+        }
+        assert_eq!(lines, vec!["a\n", "second\n", "\n", "this line is longer than a block\n", "last"]);
+        let mut read = String::new();
+        BlockReader::new(Cursor::new(input), 3).read_to_string(&mut read).unwrap();
+        assert_eq!(read, input);
+        let invalid = BlockReader::new(Cursor::new(vec![b'a', 0xff, b'\n']), 2).read_line(&mut buf);
+        assert_eq!(invalid.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
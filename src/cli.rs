@@ -1,31 +1,131 @@
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::env::temp_dir;
+use std::env::{self, temp_dir};
+use std::ffi::OsString;
+use std::time::Duration;
+use std::collections::BTreeMap;
 use clap::{Arg, App};
 use anyhow::{Context, Result, anyhow};
+use super::generator::InputSpec;
+use super::parquet_output::{ParquetColumn, parse_columns};
 
 ///CLI options passed by the user
+#[derive(Clone)]
 pub struct CLIOptions {
     pub user_code: String,
+    pub lib_files: Vec<PathBuf>,
     pub read_buffer_size: usize,
+    pub consumer_buffer_size: usize,
     pub key_flush_size: usize,
     pub max_file_part_size: usize,
+    pub max_parts_per_key: usize,
     pub index_every: usize,
     pub workers: usize,
     pub order: String,
     pub temp_dir: PathBuf,
+    pub index_dir: Option<PathBuf>,
+    pub metrics_file: Option<PathBuf>,
+    pub reduce_passes: String,
+    pub ordered: bool,
+    pub with_values: bool,
+    pub rollup_separator: Option<String>,
+    pub max_values_retained: usize,
+    pub on_reduce_error: String,
+    pub debug_key: Option<String>,
+    pub temp_prefix: String,
+    pub reduce_max_inflight: usize,
+    pub reduce_chunk_values: Option<usize>,
+    pub verify_parts: bool,
+    pub keys_only: bool,
+    pub compact_index: bool,
+    pub null_value: String,
+    pub keep_empty_lines: bool,
+    pub dry_parse: Option<usize>,
+    pub dump_partitions: bool,
+    pub output_gzip: bool,
+    pub emit_partial: Option<PathBuf>,
+    pub coalesce_keys: Option<usize>,
+    pub reduce_warmup: bool,
+    pub no_index: bool,
+    pub abort_on_empty_input: bool,
+    pub part_read_order: String,
+    pub max_value_bytes: Option<usize>,
+    pub print_schema: bool,
+    pub schema_only: bool,
+    pub lines_per_task: Option<usize>,
+    pub final_reduce_key: String,
+    pub io_retries: usize,
+    pub passthrough: bool,
+    pub result_encoding: String,
+    pub max_open_parts: Option<usize>,
+    pub top_k: Option<usize>,
+    pub env: BTreeMap<String, String>,
+    pub single_file_parts: bool,
+    pub reduce_error_output: Option<PathBuf>,
+    pub index_every_bytes: Option<usize>,
+    pub trace_empties: bool,
+    pub stdin_block_size: Option<usize>,
+    pub expect_unique_keys: bool,
+    pub pre_map_transforms: Vec<String>,
+    pub collect_sorted: Option<usize>,
+    pub on_missing_part: String,
+    pub reduce_value_dedup: bool,
+    pub malloc_stats: Option<u64>,
+    pub malloc_background_threads: bool,
+    pub input_format: String,
+    pub reduce_queue_size: usize,
+    pub require_output: bool,
+    pub sort_values: bool,
+    pub float_precision: Option<usize>,
+    pub group_key_mode: String,
+    pub line_streaming: bool,
+    pub error_output: Option<PathBuf>,
+    pub single_value_reduce: String,
+    pub max_temp_bytes: Option<usize>,
+    ///the code of each --chain stage that runs after the one of FILE
+    pub chain: Vec<String>,
+    pub progress_format: Option<String>,
+    pub progress_fd: u32,
+    pub with_provenance: bool,
+    pub result_db: Option<PathBuf>,
+    pub result_cf: Option<String>,
+    pub offset_mode: String,
+    pub stream_sorted: bool,
+    pub max_key_bytes: Option<usize>,
+    pub on_invalid_key: String,
+    pub generate_input: Option<InputSpec>,
+    pub reduce_memory_report: Option<u64>,
+    pub output_terminator: String,
+    pub streaming_reduce: bool,
+    pub output: Option<PathBuf>,
+    pub input_files: Vec<PathBuf>,
+    pub replace_result_cf: bool,
+    pub output_format: String,
+    pub parquet_columns: Vec<ParquetColumn>,
 }
 
 impl CLIOptions {
     ///Parses CLI options and applies defaults
     pub fn new() -> Result<CLIOptions> {
+        CLIOptions::from_args(env::args_os())
+    }
+
+    ///Parses CLI options from args and applies defaults
+    pub fn from_args<I, T>(args: I) -> Result<CLIOptions>
+        where I: IntoIterator<Item = T>, T: Into<OsString> + Clone
+    {
         let cmd = App::new("Omnimap")
             .version("0.1.0")
             .arg(Arg::with_name("code")
                 .value_name("FILE")
-                .help("The map/reduce javascript file")
+                .help("The map/reduce javascript file, or an http(s) url with --allow-remote-code")
                 .required(true))
+            .arg(Arg::with_name("input_files")
+                .value_name("INPUT")
+                .multiple(true)
+                .conflicts_with("generate_input")
+                .help("Read the input lines from these files one after the other instead of stdin"))
             .arg(Arg::with_name("read_buffer_size")
                 .display_order(1)
                 .long("read-buffer-size")
@@ -60,23 +160,509 @@ impl CLIOptions {
                 .long("order")
                 .possible_value("asc")
                 .possible_value("desc")
+                .possible_value("custom")
                 .default_value("asc")
                 .value_name("ORDERING")
-                .help("Key ordering of the output"))
+                .help("Key ordering of the output, custom sorts the results with the js compare() function"))
             .arg(Arg::with_name("temp_dir")
                 .display_order(7)
                 .long("temp-dir")
                 .value_name("DIR")
                 .help("Use a different temp dir [default: system tmp]"))
-            .get_matches();
+            .arg(Arg::with_name("on_reduce_error")
+                .display_order(8)
+                .long("on-reduce-error")
+                .possible_value("skip")
+                .possible_value("error")
+                .default_value("error")
+                .value_name("POLICY")
+                .help("Skip the keys that reduce() fails for or abort the run"))
+            .arg(Arg::with_name("debug_key")
+                .display_order(9)
+                .long("debug-key")
+                .value_name("KEY")
+                .help("Write the values passed to reduce() for KEY to stderr"))
+            .arg(Arg::with_name("temp_prefix")
+                .display_order(10)
+                .long("temp-prefix")
+                .value_name("PREFIX")
+                .default_value("omnimap")
+                .help("Name prefix of the created temp directory"))
+            .arg(Arg::with_name("reduce_max_inflight")
+                .display_order(11)
+                .long("reduce-max-inflight")
+                .value_name("NUMBER")
+                .help("Maximum reduce tasks scheduled at once [default: workers]"))
+            .arg(Arg::with_name("verify_parts")
+                .display_order(12)
+                .long("verify-parts")
+                .help("Verify the checksum of each file part before reducing it"))
+            .arg(Arg::with_name("keys_only")
+                .display_order(13)
+                .long("keys-only")
+                .help("Only output the distinct keys, skips reduce()"))
+            .arg(Arg::with_name("compact_index")
+                .display_order(14)
+                .long("compact-index")
+                .help("Compact the index after mapping and before the reduce scan"))
+            .arg(Arg::with_name("null_value")
+                .display_order(15)
+                .long("null-value")
+                .value_name("STRING")
+                .default_value("")
+                .hide_default_value(true)
+                .help("Output placeholder for empty reduce results, eg. null or \\N"))
+            .arg(Arg::with_name("max_parts_per_key")
+                .display_order(16)
+                .long("max-parts-per-key")
+                .value_name("NUMBER")
+                .help("Grow the last file part of a key instead of creating more [default: unlimited]"))
+            .arg(Arg::with_name("keep_empty_lines")
+                .display_order(17)
+                .long("keep-empty-lines")
+                .help("Pass empty input lines to map() instead of skipping them"))
+            .arg(Arg::with_name("index_dir")
+                .display_order(18)
+                .long("index-dir")
+                .value_name("DIR")
+                .help("Keep the index in a different dir than the file parts [default: temp dir]"))
+            .arg(Arg::with_name("metrics_file")
+                .display_order(19)
+                .long("metrics-file")
+                .value_name("FILE")
+                .help("Write run metrics to FILE in the prometheus text format"))
+            .arg(Arg::with_name("reduce_passes")
+                .display_order(20)
+                .long("reduce-passes")
+                .possible_value("multi")
+                .possible_value("single")
+                .possible_value("auto")
+                .default_value("multi")
+                .value_name("PASSES")
+                .help("Use rereduce or reduce all values of a key at once, auto picks based on a sample"))
+            .arg(Arg::with_name("lib_dir")
+                .display_order(21)
+                .long("lib-dir")
+                .value_name("DIR")
+                .help("Load all the .js files in DIR before the map/reduce file"))
+            .arg(Arg::with_name("ordered")
+                .display_order(22)
+                .long("ordered")
+                .help("Guarantee that reduce() sees the values in input order"))
+            .arg(Arg::with_name("with_values")
+                .display_order(23)
+                .long("with-values")
+                .help("Output json lines of {key, result, values} that include the input values of each key"))
+            .arg(Arg::with_name("max_values_retained")
+                .display_order(24)
+                .long("max-values-retained")
+                .default_value("100")
+                .value_name("NUMBER")
+                .help("How many input values per key are kept for --with-values"))
+            .arg(Arg::with_name("rollup_separator")
+                .display_order(25)
+                .long("rollup-separator")
+                .value_name("SEPARATOR")
+                .help("Also reduce the values of each key under all of its prefixes split by SEPARATOR"))
+            .arg(Arg::with_name("consumer_buffer_size")
+                .display_order(26)
+                .long("consumer-buffer-size")
+                .value_name("KILOBYTES")
+                .default_value("8")
+                .help("How many kb to buffer when reading file parts for reduce"))
+            .arg(Arg::with_name("reduce_chunk_values")
+                .display_order(27)
+                .long("reduce-chunk-values")
+                .value_name("NUMBER")
+                .help("Rereduce the results of every NUMBER lines of a file part instead of keeping them until the whole part is reduced"))
+            .arg(Arg::with_name("dry_parse")
+                .display_order(28)
+                .long("dry-parse")
+                .value_name("LINES")
+                .help("Only run map() on the first LINES lines and report the errors it throws"))
+            .arg(Arg::with_name("dump_partitions")
+                .display_order(29)
+                .long("dump-partitions")
+                .help("Write the distinct keys and bytes indexed by each partition to stderr after the map phase, the keys are kept in memory to count them"))
+            .arg(Arg::with_name("output_gzip")
+                .display_order(30)
+                .long("output-gzip")
+                .help("Compress the output with gzip"))
+            .arg(Arg::with_name("emit_partial")
+                .display_order(31)
+                .long("emit-partial")
+                .value_name("FILE")
+                .help("Write the partial result of a key to FILE every time one of its file parts is reduced, followed by its final result"))
+            .arg(Arg::with_name("coalesce_keys")
+                .display_order(32)
+                .long("coalesce-keys")
+                .value_name("BYTES")
+                .help("Reduce keys with less than BYTES of values together in batches of up to BYTES"))
+            .arg(Arg::with_name("reduce_warmup")
+                .display_order(33)
+                .long("reduce-warmup")
+                .help("Create the js context of every worker before the map and reduce tasks start"))
+            .arg(Arg::with_name("no_index")
+                .display_order(34)
+                .long("no-index")
+                .help("Group the map results in memory instead of the on-disk index, for inputs that fit in memory"))
+            .arg(Arg::with_name("abort_on_empty_input")
+                .display_order(35)
+                .long("abort-on-empty-input")
+                .help("Exit with code 3 if no input lines were read"))
+            .arg(Arg::with_name("part_read_order")
+                .display_order(36)
+                .long("part-read-order")
+                .possible_value("asc")
+                .possible_value("desc")
+                .possible_value("index-first")
+                .default_value("asc")
+                .value_name("ORDERING")
+                .help("The order the file parts and index values of a key are passed to reduce()"))
+            .arg(Arg::with_name("max_value_bytes")
+                .display_order(37)
+                .long("max-value-bytes")
+                .value_name("BYTES")
+                .help("Fail the keys that reduce to a value larger than BYTES, follows --on-reduce-error"))
+            .arg(Arg::with_name("print_schema")
+                .display_order(38)
+                .long("print-schema")
+                .help("Write a JSON Schema inferred from a sample of the results to stderr"))
+            .arg(Arg::with_name("schema_only")
+                .display_order(39)
+                .long("schema-only")
+                .requires("print_schema")
+                .help("Only print the schema of --print-schema, without the results"))
+            .arg(Arg::with_name("lines_per_task")
+                .display_order(40)
+                .long("lines-per-task")
+                .value_name("NUMBER")
+                .help("Pass this many lines to each map task instead of --read-buffer-size kb"))
+            .arg(Arg::with_name("final_reduce_key")
+                .display_order(41)
+                .long("final-reduce-key")
+                .value_name("KEY")
+                .default_value("_total")
+                .help("The key of the finalReduce() result in the output"))
+            .arg(Arg::with_name("io_retries")
+                .display_order(42)
+                .long("io-retries")
+                .value_name("NUMBER")
+                .default_value("0")
+                .help("Retry failed index and file part writes this many times with a backoff"))
+            .arg(Arg::with_name("passthrough")
+                .display_order(43)
+                .long("passthrough")
+                .help("Write the input lines that map() calls keep() for as they are, without reducing"))
+            .arg(Arg::with_name("result_encoding")
+                .display_order(44)
+                .long("result-encoding")
+                .possible_value("json")
+                .possible_value("bincode")
+                .default_value("json")
+                .value_name("ENCODING")
+                .help("How the reduce results are stored until they are written to the output"))
+            .arg(Arg::with_name("max_open_parts")
+                .display_order(45)
+                .long("max-open-parts")
+                .value_name("NUMBER")
+                .help("The maximum number of file parts that are open at the same time [default: unlimited]"))
+            .arg(Arg::with_name("top_k")
+                .display_order(46)
+                .long("top-k")
+                .value_name("K")
+                .help("Only output the K keys with the largest numeric results, from the largest to the smallest"))
+            .arg(Arg::with_name("env_allow")
+                .display_order(47)
+                .long("env-allow")
+                .value_name("NAMES")
+                .help("Comma separated environment variables that are readable from the js `env` object"))
+            .arg(Arg::with_name("single_file_parts")
+                .display_order(48)
+                .long("single-file-parts")
+                .help("Write all the file parts of a key to a single file and read them by their byte ranges"))
+            .arg(Arg::with_name("reduce_error_output")
+                .display_order(49)
+                .long("reduce-error-output")
+                .value_name("FILE")
+                .help("Write the keys that reduce() fails for to FILE as json lines with their error (and their values with --with-values)"))
+            .arg(Arg::with_name("index_every_bytes")
+                .display_order(50)
+                .long("index-every-bytes")
+                .value_name("KILOBYTES")
+                .conflicts_with("index_every")
+                .help("Index once the buffered map results reach this size instead of every --index-every map tasks"))
+            .arg(Arg::with_name("trace_empties")
+                .display_order(51)
+                .long("trace-empties")
+                .help("Report to stderr how many map tasks emitted nothing and how many keys had no values, with a few samples"))
+            .arg(Arg::with_name("stdin_block_size")
+                .display_order(52)
+                .long("stdin-block-size")
+                .value_name("KILOBYTES")
+                .help("Read the input in fixed blocks of this size and split the lines from them, partial lines are carried over to the next block, without it the input is read through a default buffered reader"))
+            .arg(Arg::with_name("expect_unique_keys")
+                .display_order(53)
+                .long("expect-unique-keys")
+                .help("Fail if any key receives more than one value"))
+            .arg(Arg::with_name("pre_map_transform")
+                .display_order(54)
+                .long("pre-map-transform")
+                .value_name("TRANSFORM")
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with("passthrough")
+                .help("Transform every line before map() with trim, lowercase, collapse-whitespace, field:N or field:N:DELIMITER, can be repeated to apply more in order"))
+            .arg(Arg::with_name("collect_sorted")
+                .display_order(55)
+                .long("collect-sorted")
+                .conflicts_with("reduce_passes")
+                .help("Collect all the values of each key, sort them numerically and reduce them in a single call"))
+            .arg(Arg::with_name("max_collected_size")
+                .display_order(56)
+                .long("max-collected-size")
+                .value_name("KILOBYTES")
+                .default_value("262144")
+                .help("Fail the keys that collect more values than this with --collect-sorted"))
+            .arg(Arg::with_name("on_missing_part")
+                .display_order(57)
+                .long("on-missing-part")
+                .possible_value("skip")
+                .possible_value("error")
+                .default_value("error")
+                .value_name("POLICY")
+                .help("Skip the file parts of a key that are missing from the temp directory or abort the run"))
+            .arg(Arg::with_name("reduce_value_dedup")
+                .display_order(58)
+                .long("reduce-value-dedup")
+                .help("Drop duplicate values before reduce(), within each batch of values or across the whole key with a single reduce pass"))
+            .arg(Arg::with_name("malloc_stats")
+                .display_order(59)
+                .long("malloc-stats")
+                .value_name("SECONDS")
+                .help("Print the allocated and resident memory of the allocator to stderr every SECONDS"))
+            .arg(Arg::with_name("malloc_background_threads")
+                .display_order(60)
+                .long("malloc-background-threads")
+                .help("Enable the background threads of the allocator that return unused memory to the OS"))
+            .arg(Arg::with_name("input_format")
+                .display_order(61)
+                .long("input-format")
+                .possible_value("lines")
+                .possible_value("length-prefixed")
+                .value_name("FORMAT")
+                .conflicts_with_all(&["passthrough", "pre_map_transform", "collapse_whitespace"])
+                .help("Read newline delimited lines or records of a 4-byte big-endian length and that many bytes, passed to map() base64 encoded [default: lines]"))
+            .arg(Arg::with_name("reduce_queue_size")
+                .display_order(62)
+                .long("reduce-queue-size")
+                .value_name("NUMBER")
+                .help("Maximum part lines and key batches queued between reading the parts and reducing them [default: workers]"))
+            .arg(Arg::with_name("require_output")
+                .display_order(63)
+                .long("require-output")
+                .conflicts_with_all(&["keys_only", "passthrough"])
+                .help("Exit with code 4 if no results were produced after reduce"))
+            .arg(Arg::with_name("sort_values")
+                .display_order(64)
+                .long("sort-values")
+                .conflicts_with_all(&["single_file_parts", "max_open_parts", "max_parts_per_key"])
+                .help("Pass the values of each key to reduce() in numeric order, the file parts are kept sorted and merged without loading them whole"))
+            .arg(Arg::with_name("float_precision")
+                .display_order(65)
+                .long("float-precision")
+                .value_name("DECIMALS")
+                .help("Print the float results, and the floats inside json results, rounded to this many decimals"))
+            .arg(Arg::with_name("group_key_mode")
+                .display_order(66)
+                .long("group-key-mode")
+                .possible_value("exact")
+                .possible_value("trimmed")
+                .possible_value("lowercase")
+                .default_value("exact")
+                .value_name("MODE")
+                .help("Group the keys by their exact, trimmed or lowercased form, they are printed in the first form seen"))
+            .arg(Arg::with_name("line_streaming")
+                .display_order(67)
+                .long("line-streaming")
+                .conflicts_with("lines_per_task")
+                .help("Pass each line to a map task as soon as it is read, lower latency on slow streams but less throughput"))
+            .arg(Arg::with_name("error_output")
+                .display_order(68)
+                .long("error-output")
+                .value_name("FILE")
+                .help("Write the records rejected with emitError() to FILE as json lines instead of stderr"))
+            .arg(Arg::with_name("single_value_reduce")
+                .display_order(69)
+                .long("single-value-reduce")
+                .possible_value("initial")
+                .possible_value("rereduce")
+                .default_value("rereduce")
+                .value_name("MODE")
+                .help("With multiple reduce passes, reduce the keys that have a single value only once with rereduce=false (initial) or also rereduce their result"))
+            .arg(Arg::with_name("allow_remote_code")
+                .display_order(70)
+                .long("allow-remote-code")
+                .help("Allow FILE to be an http(s) url, the code is fetched before the run"))
+            .arg(Arg::with_name("max_temp_bytes")
+                .display_order(71)
+                .long("max-temp-bytes")
+                .value_name("BYTES")
+                .conflicts_with("no_index")
+                .help("Abort the run once the file parts of all the keys take more than BYTES of disk"))
+            .arg(Arg::with_name("chain")
+                .display_order(72)
+                .long("chain")
+                .value_name("FILE")
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["with_values", "with_provenance", "keys_only", "passthrough", "schema_only", "dry_parse", "result_db"])
+                .help("Run FILE as the next stage, the key\\tvalue results of the previous stage are its input lines, can be repeated"))
+            .arg(Arg::with_name("progress_format")
+                .display_order(73)
+                .long("progress-format")
+                .possible_value("json")
+                .value_name("FORMAT")
+                .help("Write progress events (stages, lines read, index cycles, keys reduced) as json lines to --progress-fd"))
+            .arg(Arg::with_name("progress_fd")
+                .display_order(74)
+                .long("progress-fd")
+                .value_name("FD")
+                .requires("progress_format")
+                .help("The file descriptor the progress events are written to [default: 2]"))
+            .arg(Arg::with_name("with_provenance")
+                .display_order(75)
+                .long("with-provenance")
+                .conflicts_with_all(&["keys_only", "passthrough"])
+                .help("Print the results as json lines with the indexer partition and the number of file parts of each key"))
+            .arg(Arg::with_name("result_db")
+                .display_order(76)
+                .long("result-db")
+                .value_name("PATH")
+                .requires("result_cf")
+                .conflicts_with_all(&["passthrough", "dry_parse"])
+                .help("Write the results to the rocksdb in PATH (created if missing) instead of the temp dir, it stays locked until the run ends"))
+            .arg(Arg::with_name("result_cf")
+                .display_order(77)
+                .long("result-cf")
+                .value_name("NAME")
+                .requires("result_db")
+                .help("The column family of --result-db the results are written to, it is created if missing. The results are added to the existing entries of the column family"))
+            .arg(Arg::with_name("offset_mode")
+                .display_order(78)
+                .long("offset-mode")
+                .possible_value("lines")
+                .possible_value("bytes")
+                .default_value("lines")
+                .value_name("MODE")
+                .help("Pass the line number or the byte offset of each line to map() as its key, bytes only works with lines input"))
+            .arg(Arg::with_name("collapse_whitespace")
+                .display_order(79)
+                .long("collapse-whitespace")
+                .conflicts_with("passthrough")
+                .help("Turn every run of spaces and tabs into a single space before map() and the --pre-map-transform transforms"))
+            .arg(Arg::with_name("stream_sorted")
+                .display_order(80)
+                .long("stream-sorted")
+                .conflicts_with_all(&["top_k", "keys_only", "passthrough", "schema_only", "dry_parse"])
+                .help("Print each result in ascending key order as soon as it and every key before it are reduced, instead of after the whole reduce"))
+            .arg(Arg::with_name("max_key_bytes")
+                .display_order(81)
+                .long("max-key-bytes")
+                .value_name("BYTES")
+                .help("Treat the emitted keys longer than BYTES as invalid, keys that are not valid UTF-8 always are"))
+            .arg(Arg::with_name("on_invalid_key")
+                .display_order(82)
+                .long("on-invalid-key")
+                .possible_value("error")
+                .possible_value("skip")
+                .default_value("error")
+                .value_name("POLICY")
+                .help("Fail the run on an invalid emitted key or skip its value with a warning"))
+            .arg(Arg::with_name("generate_input")
+                .display_order(83)
+                .long("generate-input")
+                .value_name("SPEC")
+                .conflicts_with_all(&["input_format", "stdin_block_size"])
+                .help("Read deterministic key\\tvalue lines from SPEC (eg. lines=100000,keys=1000,dist=zipf,skew=1.2,values=100,seed=7) instead of stdin"))
+            .arg(Arg::with_name("reduce_memory_report")
+                .display_order(84)
+                .long("reduce-memory-report")
+                .value_name("SECONDS")
+                .conflicts_with_all(&["keys_only", "passthrough", "dry_parse"])
+                .help("Print the keys that hold the most buffered values and results in the reducer to stderr every SECONDS"))
+            .arg(Arg::with_name("output_terminator")
+                .display_order(85)
+                .long("output-terminator")
+                .possible_value("newline")
+                .possible_value("nul")
+                .possible_value("none")
+                .default_value("newline")
+                .value_name("TERMINATOR")
+                .help("End each printed row with a newline, a NUL byte, or separate the rows with newlines without one after the last row"))
+            .arg(Arg::with_name("streaming_reduce")
+                .display_order(86)
+                .long("streaming-reduce")
+                .conflicts_with_all(&["no_index", "with_values", "with_provenance", "collect_sorted", "passthrough", "dry_parse", "reduce_value_dedup"])
+                .help("Reduce the values as they are mapped and only keep a running result per key in memory, nothing is indexed. reduce() must be rereduce safe"))
+            .arg(Arg::with_name("output")
+                .display_order(87)
+                .long("output")
+                .value_name("FILE")
+                .help("Write the results to FILE instead of stdout, the file is truncated if it exists"))
+            .arg(Arg::with_name("replace_result_cf")
+                .display_order(88)
+                .long("replace-result-cf")
+                .requires("result_cf")
+                .help("Drop and recreate the --result-cf column family if it exists, so it only holds the results of this run"))
+            .arg(Arg::with_name("output_format")
+                .display_order(89)
+                .long("output-format")
+                .possible_value("lines")
+                .possible_value("parquet")
+                .default_value("lines")
+                .value_name("FORMAT")
+                .help("Print the results as lines or write them to the --output file as parquet, parquet needs a build with the parquet feature"))
+            .arg(Arg::with_name("parquet_columns")
+                .display_order(90)
+                .long("parquet-columns")
+                .value_name("SPEC")
+                .help("The value columns of the parquet output taken from the fields of json object results (eg. name:string,count:int64,avg:double,ok:boolean), a single value column with the whole result without it"))
+            .get_matches_from(args);
         //the following unwraps are safe since clap has already checked for required arguments and defaults
         let user_code_file = cmd.value_of("code").unwrap();
-        let user_code = get_user_code(user_code_file).context("Could not read javascript file")?;
+        let allow_remote_code = cmd.is_present("allow_remote_code");
+        let user_code = get_user_code(user_code_file, allow_remote_code).context("Could not read javascript file")?;
+
+        let (lib_code, lib_files) = match cmd.value_of("lib_dir") {
+            Some(lib_dir) => {
+                let lib_files = get_lib_files(lib_dir).context("Could not read library directory")?;
+                let mut lib_code = String::new();
+                for lib_file in &lib_files {
+                    lib_code.push_str(&fs::read_to_string(lib_file).with_context(|| format!("Could not read library file: {}", lib_file.display()))?);
+                    lib_code.push('\n');
+                }
+                (lib_code, lib_files)
+            },
+            None => (String::new(), vec![])
+        };
+        let user_code = format!("{}{}", lib_code, user_code);
+
+        //every stage is read up front so a missing file fails before the first stage runs
+        let mut chain = vec![];
+        for stage_file in cmd.values_of("chain").into_iter().flatten() {
+            let stage_code = get_user_code(stage_file, allow_remote_code).with_context(|| format!("Could not read chain stage: {}", stage_file))?;
+            chain.push(format!("{}{}", lib_code, stage_code));
+        }
 
         let read_buffer_size = cmd.value_of("read_buffer_size").unwrap().parse::<usize>().context("Invalid read buffer size")?;
         if read_buffer_size == 0 { return Err(anyhow!("Invalid read buffer size")) };
         let read_buffer_size = 1024 * read_buffer_size;
 
+        let consumer_buffer_size = cmd.value_of("consumer_buffer_size").unwrap().parse::<usize>().context("Invalid consumer buffer size")?;
+        if consumer_buffer_size == 0 { return Err(anyhow!("Invalid consumer buffer size")) };
+        let consumer_buffer_size = 1024 * consumer_buffer_size;
+
         let key_flush_size = cmd.value_of("key_flush_size").unwrap().parse::<usize>().context("Invalid key flush size")?;
         if key_flush_size == 0 { return Err(anyhow!("Invalid key flush size")) };
         let key_flush_size = 1024 * key_flush_size;
@@ -85,9 +671,22 @@ impl CLIOptions {
         if max_file_part_size == 0 { return Err(anyhow!("Invalid file part size")) };
         let max_file_part_size = 1024 * max_file_part_size;
 
+        let max_parts_per_key = if cmd.is_present("max_parts_per_key") {
+            cmd.value_of("max_parts_per_key").unwrap().parse::<usize>().context("Invalid file part count")?
+        } else {
+            usize::MAX
+        };
+        if max_parts_per_key == 0 { return Err(anyhow!("Invalid file part count")) };
+
         let index_every = cmd.value_of("index_every").unwrap().parse::<usize>().context("Invalid index cycle")?;
         if index_every == 0 { return Err(anyhow!("Invalid index cycle")) };
 
+        let index_every_bytes = match cmd.value_of("index_every_bytes") {
+            Some(size) => Some(1024 * size.parse::<usize>().context("Invalid index cycle size")?),
+            None => None
+        };
+        if let Some(0) = index_every_bytes { return Err(anyhow!("Invalid index cycle size")) };
+
         let workers = if cmd.is_present("workers") {
             cmd.value_of("workers").unwrap().parse::<usize>().context("Invalid worker count")?
         } else {
@@ -95,29 +694,384 @@ impl CLIOptions {
         };
         if workers == 0 { return Err(anyhow!("Invalid worker count")) };
 
+        let reduce_max_inflight = if cmd.is_present("reduce_max_inflight") {
+            cmd.value_of("reduce_max_inflight").unwrap().parse::<usize>().context("Invalid reduce inflight limit")?
+        } else {
+            workers
+        };
+        if reduce_max_inflight == 0 { return Err(anyhow!("Invalid reduce inflight limit")) };
+
+        let reduce_queue_size = if cmd.is_present("reduce_queue_size") {
+            cmd.value_of("reduce_queue_size").unwrap().parse::<usize>().context("Invalid reduce queue size")?
+        } else {
+            workers
+        };
+        if reduce_queue_size == 0 { return Err(anyhow!("Invalid reduce queue size")) };
+
+        let reduce_chunk_values = match cmd.value_of("reduce_chunk_values") {
+            Some(chunk_values) => Some(chunk_values.parse::<usize>().context("Invalid reduce chunk size")?),
+            None => None
+        };
+        if let Some(0..=1) = reduce_chunk_values { return Err(anyhow!("Invalid reduce chunk size, it must be at least 2")) };
+
+        let verify_parts = cmd.is_present("verify_parts");
+
+        let keys_only = cmd.is_present("keys_only");
+
+        let compact_index = cmd.is_present("compact_index");
+
+        let null_value = cmd.value_of("null_value").unwrap().to_owned();
+
+        let keep_empty_lines = cmd.is_present("keep_empty_lines");
+
+        let dump_partitions = cmd.is_present("dump_partitions");
+
+        let output_gzip = cmd.is_present("output_gzip");
+
+        let emit_partial = cmd.value_of("emit_partial").map(PathBuf::from);
+        let reduce_error_output = cmd.value_of("reduce_error_output").map(PathBuf::from);
+
+        let reduce_warmup = cmd.is_present("reduce_warmup");
+
+        let no_index = cmd.is_present("no_index");
+
+        let abort_on_empty_input = cmd.is_present("abort_on_empty_input");
+
+        let part_read_order = cmd.value_of("part_read_order").unwrap().to_owned();
+
+        let max_value_bytes = match cmd.value_of("max_value_bytes") {
+            Some(bytes) => Some(bytes.parse::<usize>().context("Invalid max value size")?),
+            None => None
+        };
+        if let Some(0) = max_value_bytes { return Err(anyhow!("Invalid max value size")) };
+
+        let print_schema = cmd.is_present("print_schema");
+
+        let schema_only = cmd.is_present("schema_only");
+
+        let lines_per_task = match cmd.value_of("lines_per_task") {
+            Some(lines) => Some(lines.parse::<usize>().context("Invalid lines per task")?),
+            None => None
+        };
+        if let Some(0) = lines_per_task { return Err(anyhow!("Invalid lines per task")) };
+
+        let final_reduce_key = cmd.value_of("final_reduce_key").unwrap().to_owned();
+
+        let io_retries = cmd.value_of("io_retries").unwrap().parse::<usize>().context("Invalid io retry count")?;
+
+        let passthrough = cmd.is_present("passthrough");
+
+        let result_encoding = cmd.value_of("result_encoding").unwrap().to_owned();
+
+        let max_open_parts = match cmd.value_of("max_open_parts") {
+            Some(parts) => Some(parts.parse::<usize>().context("Invalid open file parts limit")?),
+            None => None
+        };
+        if let Some(0) = max_open_parts { return Err(anyhow!("Invalid open file parts limit")) };
+
+        let top_k = match cmd.value_of("top_k") {
+            Some(k) => Some(k.parse::<usize>().context("Invalid top k")?),
+            None => None
+        };
+        if let Some(0) = top_k { return Err(anyhow!("Invalid top k")) };
+
+        //only the allowed variables are exposed so secrets in the environment do not leak to the js code
+        let env = match cmd.value_of("env_allow") {
+            Some(names) => allowed_env(names, |name| env::var(name).ok()),
+            None => BTreeMap::new()
+        };
+
+        let single_file_parts = cmd.is_present("single_file_parts");
+        let trace_empties = cmd.is_present("trace_empties");
+        let expect_unique_keys = cmd.is_present("expect_unique_keys");
+
+        let max_collected_size = cmd.value_of("max_collected_size").unwrap().parse::<usize>().context("Invalid max collected size")?;
+        if max_collected_size == 0 { return Err(anyhow!("Invalid max collected size")) };
+        let collect_sorted = if cmd.is_present("collect_sorted") { Some(1024 * max_collected_size) } else { None };
+        let mut pre_map_transforms: Vec<String> = cmd.values_of("pre_map_transform").map(|specs| specs.map(String::from).collect()).unwrap_or_default();
+        //collapsed first so field:N: can split on single spaces
+        if cmd.is_present("collapse_whitespace") {
+            pre_map_transforms.insert(0, String::from("collapse-whitespace"));
+        }
+
+        let stdin_block_size = match cmd.value_of("stdin_block_size") {
+            Some(size) => Some(1024 * size.parse::<usize>().context("Invalid stdin block size")?),
+            None => None
+        };
+        if let Some(0) = stdin_block_size { return Err(anyhow!("Invalid stdin block size")) };
+
+        let malloc_stats = match cmd.value_of("malloc_stats") {
+            Some(seconds) => Some(seconds.parse::<u64>().context("Invalid malloc stats interval")?),
+            None => None
+        };
+        if let Some(0) = malloc_stats { return Err(anyhow!("Invalid malloc stats interval")) };
+        let malloc_background_threads = cmd.is_present("malloc_background_threads");
+        let require_output = cmd.is_present("require_output");
+        let sort_values = cmd.is_present("sort_values");
+        let group_key_mode = cmd.value_of("group_key_mode").unwrap().to_owned();
+
+        let line_streaming = cmd.is_present("line_streaming");
+
+        let error_output = cmd.value_of("error_output").map(PathBuf::from);
+
+        let single_value_reduce = cmd.value_of("single_value_reduce").unwrap().to_owned();
+
+        let max_temp_bytes = match cmd.value_of("max_temp_bytes") {
+            Some(bytes) => Some(bytes.parse::<usize>().context("Invalid max temp size")?),
+            None => None
+        };
+        if let Some(0) = max_temp_bytes { return Err(anyhow!("Invalid max temp size")) };
+
+        let progress_format = cmd.value_of("progress_format").map(String::from);
+        let progress_fd = cmd.value_of("progress_fd").unwrap_or("2").parse::<u32>().context("Invalid progress fd")?;
+
+        let with_provenance = cmd.is_present("with_provenance");
+
+        let result_db = cmd.value_of("result_db").map(PathBuf::from);
+        let result_cf = cmd.value_of("result_cf").map(String::from);
+        if result_cf.as_deref() == Some("default") { return Err(anyhow!("The default column family can not be used for the results")) };
+        let replace_result_cf = cmd.is_present("replace_result_cf");
+
+        let output_format = cmd.value_of("output_format").unwrap().to_owned();
+        let parquet_columns = match cmd.value_of("parquet_columns") {
+            Some(spec) => parse_columns(spec).context("Invalid --parquet-columns spec")?,
+            None => vec![]
+        };
+        if output_format == "parquet" {
+            if cmd.value_of("output").is_none() { return Err(anyhow!("--output-format parquet needs an --output file")) };
+            let conflicting = ["with_values", "with_provenance", "keys_only", "passthrough", "output_gzip", "stream_sorted", "dry_parse", "schema_only"];
+            if let Some(flag) = conflicting.iter().find(|flag| cmd.is_present(flag)) {
+                return Err(anyhow!("--output-format parquet can not be used with --{}", flag.replace('_', "-")));
+            }
+        } else if !parquet_columns.is_empty() {
+            return Err(anyhow!("--parquet-columns can only be used with --output-format parquet"));
+        }
+
+        let max_key_bytes = match cmd.value_of("max_key_bytes") {
+            Some(bytes) => Some(bytes.parse::<usize>().context("Invalid max key size")?),
+            None => None
+        };
+        if let Some(0) = max_key_bytes { return Err(anyhow!("Invalid max key size")) };
+        let on_invalid_key = cmd.value_of("on_invalid_key").unwrap().to_owned();
+
+        let reduce_memory_report = match cmd.value_of("reduce_memory_report") {
+            Some(seconds) => Some(seconds.parse::<u64>().context("Invalid reduce memory report interval")?),
+            None => None
+        };
+        if let Some(0) = reduce_memory_report { return Err(anyhow!("Invalid reduce memory report interval")) };
+
+        let output_terminator = cmd.value_of("output_terminator").unwrap().to_owned();
+
+        let generate_input = match cmd.value_of("generate_input") {
+            Some(spec) => Some(InputSpec::new(spec).context("Invalid --generate-input spec")?),
+            None => None
+        };
+
+        let offset_mode = cmd.value_of("offset_mode").unwrap().to_owned();
+        if offset_mode == "bytes" && passthrough { return Err(anyhow!("--offset-mode bytes can not be used with --passthrough")) };
+
+        let float_precision = match cmd.value_of("float_precision") {
+            Some(decimals) => Some(decimals.parse::<usize>().context("Invalid float precision")?),
+            None => None
+        };
+        //no clap default, it would always conflict with --passthrough and --pre-map-transform
+        let input_format = cmd.value_of("input_format").unwrap_or("lines").to_owned();
+        //the byte offsets of length-prefixed records would point inside the base64 that map() gets
+        if offset_mode == "bytes" && input_format != "lines" { return Err(anyhow!("--offset-mode bytes only works with lines input")) };
+
+        let coalesce_keys = match cmd.value_of("coalesce_keys") {
+            Some(size) => Some(size.parse::<usize>().context("Invalid key coalescing size")?),
+            None => None
+        };
+        if let Some(0) = coalesce_keys { return Err(anyhow!("Invalid key coalescing size")) };
+
+        let dry_parse = match cmd.value_of("dry_parse") {
+            Some(lines) => Some(lines.parse::<usize>().context("Invalid dry parse line count")?),
+            None => None
+        };
+
         let order = cmd.value_of("order").unwrap().to_owned();
 
+        //the keys are reduced in the ascending order of the index, which is only the output order for exact keys
+        let stream_sorted = cmd.is_present("stream_sorted");
+        if stream_sorted && order != "asc" { return Err(anyhow!("--stream-sorted only works with --order asc")) };
+        if stream_sorted && group_key_mode != "exact" { return Err(anyhow!("--stream-sorted only works with --group-key-mode exact")) };
+
         let temp_dir = if cmd.is_present("temp_dir") {
             PathBuf::from(cmd.value_of("temp_dir").unwrap())
         } else {
             temp_dir()
         };
 
+        let index_dir = cmd.value_of("index_dir").map(PathBuf::from);
+
+        let metrics_file = cmd.value_of("metrics_file").map(PathBuf::from);
+        let output = cmd.value_of("output").map(PathBuf::from);
+        let input_files: Vec<PathBuf> = cmd.values_of("input_files").into_iter().flatten().map(PathBuf::from).collect();
+        //fail before anything runs instead of when the stream reaches a missing file
+        for path in &input_files {
+            fs::File::open(path).with_context(|| format!("Could not open input file: {}", path.display()))?;
+        }
+        //the newline added to a file without one would shift the offsets of the files after it
+        if offset_mode == "bytes" && input_files.len() > 1 { return Err(anyhow!("--offset-mode bytes can not be used with more than one input file")) };
+
+        let reduce_passes = cmd.value_of("reduce_passes").unwrap().to_owned();
+        let streaming_reduce = cmd.is_present("streaming_reduce");
+        if streaming_reduce && reduce_passes == "single" { return Err(anyhow!("--streaming-reduce needs a rereduce safe reduce(), it can not be used with --reduce-passes single")) };
+
+        let ordered = cmd.is_present("ordered");
+
+        let with_values = cmd.is_present("with_values");
+
+        let rollup_separator = cmd.value_of("rollup_separator").map(String::from);
+        if rollup_separator.as_deref() == Some("") { return Err(anyhow!("Invalid rollup separator")) };
+
+        let max_values_retained = cmd.value_of("max_values_retained").unwrap().parse::<usize>().context("Invalid retained values count")?;
+
+        let on_reduce_error = cmd.value_of("on_reduce_error").unwrap().to_owned();
+        if streaming_reduce && on_reduce_error == "skip" { return Err(anyhow!("--streaming-reduce can not skip the keys that fail to reduce, it can not be used with --on-reduce-error skip")) };
+        let on_missing_part = cmd.value_of("on_missing_part").unwrap().to_owned();
+        let reduce_value_dedup = cmd.is_present("reduce_value_dedup");
+
+        let debug_key = cmd.value_of("debug_key").map(String::from);
+
+        let temp_prefix = cmd.value_of("temp_prefix").unwrap().to_owned();
+        if temp_prefix.is_empty() || temp_prefix.contains('/') { return Err(anyhow!("Invalid temp prefix")) };
+
         Ok(CLIOptions {
             user_code,
+            lib_files,
             read_buffer_size,
+            consumer_buffer_size,
             key_flush_size,
             max_file_part_size,
+            max_parts_per_key,
             index_every,
             workers,
             order,
-            temp_dir
+            temp_dir,
+            index_dir,
+            metrics_file,
+            reduce_passes,
+            ordered,
+            with_values,
+            max_values_retained,
+            rollup_separator,
+            on_reduce_error,
+            debug_key,
+            temp_prefix,
+            reduce_max_inflight,
+            reduce_chunk_values,
+            verify_parts,
+            keys_only,
+            compact_index,
+            null_value,
+            keep_empty_lines,
+            dry_parse,
+            dump_partitions,
+            output_gzip,
+            emit_partial,
+            coalesce_keys,
+            reduce_warmup,
+            no_index,
+            abort_on_empty_input,
+            part_read_order,
+            max_value_bytes,
+            print_schema,
+            schema_only,
+            lines_per_task,
+            final_reduce_key,
+            io_retries,
+            passthrough,
+            result_encoding,
+            max_open_parts,
+            top_k,
+            env,
+            single_file_parts,
+            reduce_error_output,
+            index_every_bytes,
+            trace_empties,
+            stdin_block_size,
+            expect_unique_keys,
+            pre_map_transforms,
+            collect_sorted,
+            on_missing_part,
+            reduce_value_dedup,
+            malloc_stats,
+            malloc_background_threads,
+            input_format,
+            reduce_queue_size,
+            require_output,
+            sort_values,
+            float_precision,
+            group_key_mode,
+            line_streaming,
+            error_output,
+            single_value_reduce,
+            max_temp_bytes,
+            chain,
+            progress_format,
+            progress_fd,
+            with_provenance,
+            result_db,
+            result_cf,
+            offset_mode,
+            stream_sorted,
+            max_key_bytes,
+            on_invalid_key,
+            generate_input,
+            reduce_memory_report,
+            output_terminator,
+            streaming_reduce,
+            output,
+            input_files,
+            replace_result_cf,
+            output_format,
+            parquet_columns
         })
     }
 }
 
-///loads the user's code file
-fn get_user_code(file_path: &str) -> Result<String, io::Error> {
-    let path = Path::new(file_path);
-    fs::read_to_string(path)
+///how long fetching the code from a url can take
+const REMOTE_CODE_TIMEOUT: Duration = Duration::from_secs(30);
+
+///Looks up the comma separated variable names of --env-allow with lookup, the ones that are not set are left out
+pub fn allowed_env<F: Fn(&str) -> Option<String>>(names: &str, lookup: F) -> BTreeMap<String, String> {
+    names.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| lookup(name).map(|value| (name.to_owned(), value)))
+        .collect()
+}
+
+///loads the user's code file, or fetches it if it is an http(s) url and allow_remote is set.  
+///File descriptor paths like /dev/fd/3 are read like any other file
+fn get_user_code(source: &str, allow_remote: bool) -> Result<String> {
+    if !source.starts_with("http://") && !source.starts_with("https://") {
+        return Ok(fs::read_to_string(Path::new(source))?);
+    }
+    if !allow_remote {
+        return Err(anyhow!("Fetching the code from {} requires --allow-remote-code", source));
+    }
+    let response = ureq::get(source).timeout(REMOTE_CODE_TIMEOUT).call();
+    if let Some(err) = response.synthetic_error() {
+        return Err(anyhow!("Could not fetch {}: {}", source, err));
+    }
+    if !response.ok() {
+        return Err(anyhow!("Could not fetch {}: {} {}", source, response.status(), response.status_text()));
+    }
+    Ok(response.into_string()?)
+}
+
+///lists the .js files of a library directory in sorted order
+fn get_lib_files(dir: &str) -> Result<Vec<PathBuf>, io::Error> {
+    let mut lib_files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().and_then(|ext| ext.to_str()) == Some("js") {
+            lib_files.push(path);
+        }
+    }
+    lib_files.sort();
+    Ok(lib_files)
 }
@@ -1,32 +1,157 @@
 use std::collections::HashMap;
+use std::borrow::Cow;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::sync::{Arc, RwLock};
-use super::{js::MapResult, map_container::MapContainer};
+use anyhow::{Result, anyhow};
+use super::{js::{MapResult, ContextBuilder}, map_container::MapContainer};
 
 type Bucket = Arc<RwLock<HashMap<String, MapContainer>>>;
 type BucketList = Vec<Bucket>;
 
+///How the map results are grouped by their key
+pub struct KeyGrouping {
+    ///also add every value under each ancestor prefix of its key, eg. "a/b/c" to "a/b" and "a"
+    pub rollup_separator: Option<String>,
+    pub mode: GroupKeyMode,
+    pub key_check: KeyCheck,
+    ///the js partition() if it is defined, keys it returns undefined for are hashed
+    pub partition_fn: Option<Arc<ContextBuilder>>
+}
+
+///Which emitted keys are invalid and what happens to them, used with --max-key-bytes and --on-invalid-key.  
+///Keys with lone surrogates have no UTF-8 form so they are always invalid
+pub struct KeyCheck {
+    pub max_key_bytes: Option<usize>,
+    pub on_invalid: InvalidKeyPolicy
+}
+
+#[derive(Clone, Copy)]
+pub enum InvalidKeyPolicy {
+    ///fail the run
+    Error,
+    ///drop the emitted value with a warning
+    Skip
+}
+
+impl InvalidKeyPolicy {
+    pub fn new(policy: &str) -> InvalidKeyPolicy {
+        if policy == "skip" {
+            InvalidKeyPolicy::Skip
+        } else {
+            InvalidKeyPolicy::Error
+        }
+    }
+}
+
+impl KeyCheck {
+    ///Returns why the key of a map result is invalid
+    fn violation(&self, result: &MapResult) -> Option<String> {
+        if result.invalid_key {
+            return Some(String::from("it is not valid UTF-8"));
+        }
+        match self.max_key_bytes {
+            Some(max_key_bytes) if result.key.len() > max_key_bytes => Some(format!("it is {} bytes, over the limit of {} bytes", result.key.len(), max_key_bytes)),
+            _ => None
+        }
+    }
+}
+
+///The key equality of the grouping, keys are grouped by their normalized form but printed in the first form seen
+#[derive(Clone, Copy)]
+pub enum GroupKeyMode {
+    Exact,
+    Trimmed,
+    Lowercase
+}
+
+impl GroupKeyMode {
+    pub fn new(mode: &str) -> GroupKeyMode {
+        match mode {
+            "trimmed" => GroupKeyMode::Trimmed,
+            "lowercase" => GroupKeyMode::Lowercase,
+            _ => GroupKeyMode::Exact
+        }
+    }
+
+    fn normalize(self, key: &str) -> Cow<'_, str> {
+        match self {
+            GroupKeyMode::Exact => Cow::Borrowed(key),
+            GroupKeyMode::Trimmed => Cow::Borrowed(key.trim()),
+            GroupKeyMode::Lowercase => Cow::Owned(key.to_lowercase())
+        }
+    }
+}
+
 ///Combines the raw map results based on their key.  
+///Fails on the first invalid key unless they are skipped
 pub fn combine_map_results(
     bucket_list: &mut BucketList,
     raw_results: Vec<MapResult>,
-    partitions: usize
-) {
+    partitions: usize,
+    grouping: &KeyGrouping
+) -> Result<()> {
     for r in raw_results {
-        let bucket_index = calculate_hash(&r.key) as usize % partitions;
-        let mut bucket = bucket_list[bucket_index].write().unwrap();
-        match bucket.get_mut(&r.key) {
-            Some(existing) => {
-                existing.add_value(r.value);
-            },
-            None => {
-                let mut container = MapContainer::new(&r.key);
-                container.add_value(r.value);
-                bucket.insert(r.key, container);
+        if let Some(violation) = grouping.key_check.violation(&r) {
+            //the key can be a whole document so only its start is shown
+            let key: String = r.key.chars().take(100).collect();
+            match grouping.key_check.on_invalid {
+                InvalidKeyPolicy::Error => return Err(anyhow!("Invalid key {}: {}", key, violation)),
+                InvalidKeyPolicy::Skip => {
+                    eprintln!("Skipping invalid key {}: {}", key, violation);
+                    continue;
+                }
+            }
+        }
+        if let Some(separator) = &grouping.rollup_separator {
+            for (position, _) in r.key.rmatch_indices(separator.as_str()) {
+                if position > 0 {
+                    add_value(bucket_list, &r.key[..position], r.value.clone(), partitions, grouping)?;
+                }
             }
         }
+        add_value(bucket_list, &r.key, r.value, partitions, grouping)?;
     }
+    Ok(())
+}
+
+fn add_value(bucket_list: &mut BucketList, key: &str, value: String, partitions: usize, grouping: &KeyGrouping) -> Result<()> {
+    let group_key = grouping.mode.normalize(key);
+    let bucket_index = assign_partition(&group_key, partitions, grouping.partition_fn.as_deref())?;
+    let mut bucket = bucket_list[bucket_index].write().unwrap();
+    match bucket.get_mut(group_key.as_ref()) {
+        Some(existing) => {
+            existing.add_value(value);
+        },
+        None => {
+            let mut container = MapContainer::with_display_key(&group_key, key);
+            container.add_value(value);
+            bucket.insert(group_key.into_owned(), container);
+        }
+    }
+    Ok(())
+}
+
+///Returns the partition of key from the js partition() if it is defined, or from its hash.  
+///Fails if partition() returns an index out of range
+pub fn assign_partition(key: &str, partitions: usize, partition_fn: Option<&ContextBuilder>) -> Result<usize> {
+    let context_builder = match partition_fn {
+        //every key is in the only partition
+        Some(context_builder) if partitions > 1 => context_builder,
+        _ => return Ok(partition_of(key, partitions))
+    };
+    let mut assigned = Ok(None);
+    context_builder.reuse(|context| assigned = context.run_partition(key));
+    match assigned? {
+        Some(partition) if partition >= 0 && (partition as usize) < partitions => Ok(partition as usize),
+        Some(partition) => Err(anyhow!("partition() returned {} for key {}, it must be from 0 to {}", partition, key, partitions - 1)),
+        None => Ok(partition_of(key, partitions))
+    }
+}
+
+///Returns the partition the values of key are combined and indexed in
+pub fn partition_of(key: &str, partitions: usize) -> usize {
+    calculate_hash(&key) as usize % partitions
 }
 
 fn calculate_hash<T: Hash>(t: &T) -> u64 {
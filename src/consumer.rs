@@ -1,68 +1,479 @@
-use std::thread::{spawn, JoinHandle};
-use std::io::{BufReader, prelude::*};
-use std::fs::File;
+use std::thread::{Builder, JoinHandle};
+use std::io::prelude::*;
 use std::sync::{Arc, mpsc::SyncSender};
-use std::path::Path;
-use anyhow::{Result, anyhow};
-use super::map_container::ContainerState;
+use std::path::{Path, PathBuf};
+use std::mem;
+use std::cmp;
+use anyhow::{Context, Result, anyhow};
+use super::map_container::{MapContainer, ContainerState};
 use super::reducer::{Reduction, ReduceValue};
+use super::indexer::Groups;
+use super::streaming_reduce::Accumulated;
 use super::index::Index;
+use super::json_line::to_json_line;
+use super::semaphore::Semaphore;
+use super::js::{ContextBuilder, Context as JsContext};
+use super::empty_trace::EmptyTrace;
+use super::sorted_merge::{SortedMerge, Run, sort_numeric};
+use super::provenance::Provenance;
+use super::sorted_stream::SortedStream;
 
-pub fn spawn_consumer(index: Arc<Index>, sender: SyncSender<Reduction>, flush_size: usize) -> JoinHandle<Result<()>> {
-    let consumer = spawn(move|| -> Result<()> {
+pub fn spawn_consumer(
+    index: Arc<Index>,
+    sender: SyncSender<Reduction>,
+    options: ConsumerOptions
+) -> Result<JoinHandle<Result<()>>> {
+    let ConsumerOptions { flush_size, buffer_size, debug_key, verify_parts, coalesce_size, part_read_order, part_slots, key_filter, empty_trace, missing_parts, sort_values, provenance, sorted_stream } = options;
+    let consumer = Builder::new().name(String::from("omnimap-consumer")).spawn(move|| -> Result<()> {
         let mut line_buffer = String::with_capacity(flush_size);
+        let mut memory_keys = MemoryKeys::new(coalesce_size);
+        let key_filter = KeyFilter::new(key_filter)?;
         for pair in index.iter() {
-            let (key, container) = pair?;
+            let (index_key, mut container) = pair?;
+            //normalized keys are reduced and printed in their first seen form
+            let key = container.display_key(&index_key).to_owned();
+            if !key_filter.selects(&key)? {
+                continue;
+            }
+            if let Some(provenance) = &provenance {
+                provenance.record(&index_key, &key, container.parts().count())?;
+            }
+            if let Some(empty_trace) = &empty_trace {
+                empty_trace.record(matches!(container.state(), ContainerState::NoData), || key.clone());
+            }
+            let debug = debug_key.as_ref() == Some(&key);
             let key = Arc::new(key);
-            let total_parts = container.parts().count();
             match container.state() {
-                ContainerState::IndexAndFile => {
-                    sender.send(Reduction::KeyInit(key.clone(), total_parts + 1))?;
-                    sender.send(Reduction::FilePartInit(key.clone()))?;
-                    for part in container.parts() {
-                        let file_path = container.part_file_path(&index.root(), part)?;
-                        if !Path::new(&file_path).exists() {
-                            return Err(anyhow!("Temp directory modified while running"));
-                        }
-                        sender.send(Reduction::FileLineInit(key.clone(), part, container.part_line_count(part)?))?;
-                        let mut reader = BufReader::new(File::open(&file_path)?);
-                        while reader.read_line(&mut line_buffer)? > 0 {
-                            sender.send(Reduction::FileLine(key.clone(), part, ReduceValue::FromFile(line_buffer.drain(..).collect())))?;
+                state @ ContainerState::IndexAndFile | state @ ContainerState::FileOnly => {
+                    //parts are numbered by their read position for the reducer, so their results are merged in the read order
+                    let sources = part_read_order.sources(container.parts().collect(), matches!(state, ContainerState::IndexAndFile));
+                    //missing parts are dropped before the key is sent so the reducer only waits for the remaining ones
+                    let sources = existing_sources(&key, sources, &container, &index.root(), missing_parts)?;
+                    if sources.is_empty() {
+                        continue;
+                    }
+                    if let Some(sorted_stream) = &sorted_stream {
+                        sorted_stream.register(key.clone());
+                    }
+                    //the sorted parts and index values are merged into a single part
+                    if sort_values {
+                        let mut runs = Vec::with_capacity(sources.len());
+                        let (mut total_values, mut total_lines) = (0, 0);
+                        for source in sources {
+                            match source {
+                                PartSource::File(part) => {
+                                    if verify_parts {
+                                        container.verify_part(&index.root(), part)?;
+                                    }
+                                    total_values += container.part_value_count(part)?;
+                                    total_lines += container.part_line_count(part)?;
+                                    runs.push(Run::lines(container.read_part(&index.root(), part, buffer_size)?));
+                                },
+                                PartSource::Index => {
+                                    total_values += container.values.len();
+                                    total_lines += 1;
+                                    runs.push(Run::values(sort_numeric(&key, mem::take(&mut container.values))?));
+                                }
+                            }
                         }
+                        send_sorted(&sender, &key, SortedMerge::new(&key, runs)?, total_values, total_lines, debug)?;
+                        continue;
                     }
-                    //index values are treated as a new file part with only 1 line
-                    let new_part = container.parts().last().unwrap() + 1;
-                    sender.send(Reduction::FileLineInit(key.clone(), new_part, 1))?;
-                    sender.send(Reduction::FileLine(key.clone(), new_part, ReduceValue::FromIndex(container.values)))?;
-                },
-                ContainerState::FileOnly => {
-                    sender.send(Reduction::KeyInit(key.clone(), total_parts))?;
+                    sender.send(Reduction::KeyInit(key.clone(), sources.len()))?;
                     sender.send(Reduction::FilePartInit(key.clone()))?;
-                    for part in container.parts() {
-                        let file_path = container.part_file_path(&index.root(), part)?;
-                        if !Path::new(&file_path).exists() {
-                            return Err(anyhow!("Temp directory modified while running"));
-                        }
-                        sender.send(Reduction::FileLineInit(key.clone(), part, container.part_line_count(part)?))?;
-                        let mut reader = BufReader::new(File::open(&file_path)?);
-                        while reader.read_line(&mut line_buffer)? > 0 {
-                            sender.send(Reduction::FileLine(key.clone(), part, ReduceValue::FromFile(line_buffer.drain(..).collect())))?;
+                    for (position, source) in sources.into_iter().enumerate() {
+                        match source {
+                            PartSource::File(part) => {
+                                let _permit = part_slots.as_ref().map(|slots| slots.acquire());
+                                if verify_parts {
+                                    container.verify_part(&index.root(), part)?;
+                                }
+                                sender.send(Reduction::FileLineInit(key.clone(), position, container.part_line_count(part)?))?;
+                                let mut reader = container.read_part(&index.root(), part, buffer_size)?;
+                                while reader.read_line(&mut line_buffer)? > 0 {
+                                    if debug {
+                                        debug_values(&key, part, &line_buffer);
+                                    }
+                                    sender.send(Reduction::FileLine(key.clone(), position, ReduceValue::FromFile(line_buffer.drain(..).collect())))?;
+                                }
+                            },
+                            //index values are treated as a new file part with only 1 line
+                            PartSource::Index => {
+                                if debug {
+                                    debug_values(&key, container.parts().last().unwrap() + 1, &to_json_line(&container.values));
+                                }
+                                sender.send(Reduction::FileLineInit(key.clone(), position, 1))?;
+                                sender.send(Reduction::FileLine(key.clone(), position, ReduceValue::FromIndex(mem::take(&mut container.values))))?;
+                            }
                         }
                     }
                 },
                 ContainerState::IndexOnly => {
-                    //index values are treated as a single file part with only 1 line
-                    sender.send(Reduction::KeyInit(key.clone(), 1))?;
-                    sender.send(Reduction::FilePartInit(key.clone()))?;
-                    sender.send(Reduction::FileLineInit(key.clone(), 0, 1))?;
-                    sender.send(Reduction::FileLine(key.clone(), 0, ReduceValue::FromIndex(container.values)))?;
+                    let values = if sort_values { sort_numeric(&key, container.values)? } else { container.values };
+                    if debug {
+                        debug_values(&key, 0, &to_json_line(&values));
+                    }
+                    if let Some(sorted_stream) = &sorted_stream {
+                        sorted_stream.register(key.clone());
+                    }
+                    memory_keys.send(&sender, key, values, container.buffered_size)?;
                 },
                 ContainerState::NoData => {
                     continue;
                 }
             }
         }
+        memory_keys.finish(&sender)
+    }).context("Could not spawn consumer thread")?;
+    Ok(consumer)
+}
+
+///Settings of the index consumer
+pub struct ConsumerOptions {
+    ///the initial capacity of the line buffer
+    pub flush_size: usize,
+    ///the read buffer size of the file parts
+    pub buffer_size: usize,
+    pub debug_key: Option<String>,
+    ///check the checksum of each file part before reading it
+    pub verify_parts: bool,
+    ///batch the keys that have all of their values in the index until they reach this size
+    pub coalesce_size: Option<usize>,
+    pub part_read_order: PartReadOrder,
+    ///limits how many file parts are open at the same time, shared with the indexer
+    pub part_slots: Option<Semaphore>,
+    ///only the keys that the js filter() returns true for are reduced
+    pub key_filter: Option<Arc<ContextBuilder>>,
+    ///count the keys that have no values
+    pub empty_trace: Option<EmptyTrace>,
+    pub missing_parts: MissingPartPolicy,
+    ///merge the sorted file parts of each key so reduce() gets its values in numeric order
+    pub sort_values: bool,
+    ///record the partition and file parts of each key for the reducer
+    pub provenance: Option<Provenance>,
+    ///register the keys in the order they are sent so their results can be printed as soon as they are reduced
+    pub sorted_stream: Option<SortedStream>
+}
+
+///Settings of the in memory groups consumer, the same as ConsumerOptions without the ones for the file parts
+pub struct MemoryConsumerOptions {
+    pub debug_key: Option<String>,
+    ///batch the keys until they reach this size
+    pub coalesce_size: Option<usize>,
+    ///only the keys that the js filter() returns true for are reduced
+    pub key_filter: Option<Arc<ContextBuilder>>,
+    ///sort the values of each key so reduce() gets them in numeric order
+    pub sort_values: bool,
+    ///record the partition of each key for the reducer
+    pub provenance: Option<Provenance>,
+    ///register the keys in the order they are sent so their results can be printed as soon as they are reduced
+    pub sorted_stream: Option<SortedStream>
+}
+
+///Spawns the consumer of the in memory groups of --no-index
+pub fn spawn_memory_consumer(
+    groups: Groups,
+    sender: SyncSender<Reduction>,
+    options: MemoryConsumerOptions
+) -> Result<JoinHandle<Result<()>>> {
+    let MemoryConsumerOptions { debug_key, coalesce_size, key_filter, sort_values, provenance, sorted_stream } = options;
+    let consumer = Builder::new().name(String::from("omnimap-consumer")).spawn(move|| -> Result<()> {
+        let mut memory_keys = MemoryKeys::new(coalesce_size);
+        let key_filter = KeyFilter::new(key_filter)?;
+        for (key, values) in groups {
+            if !key_filter.selects(&key)? {
+                continue;
+            }
+            //nothing is spilled to file parts without the index
+            if let Some(provenance) = &provenance {
+                provenance.record(&key, &key, 0)?;
+            }
+            let values = if sort_values { sort_numeric(&key, values)? } else { values };
+            if debug_key.as_ref() == Some(&key) {
+                debug_values(&key, 0, &to_json_line(&values));
+            }
+            let size = values.iter().map(|value| value.len()).sum();
+            let key = Arc::new(key);
+            if let Some(sorted_stream) = &sorted_stream {
+                sorted_stream.register(key.clone());
+            }
+            memory_keys.send(&sender, key, values, size)?;
+        }
+        memory_keys.finish(&sender)
+    }).context("Could not spawn consumer thread")?;
+    Ok(consumer)
+}
+
+///How many keys that were already reduced are sent to the reducer together
+const REDUCED_BATCH_KEYS: usize = 1000;
+
+///Spawns the consumer of the results of --streaming-reduce, they are sent to the reducer in batches only to be finalized and saved
+pub fn spawn_reduced_consumer(
+    results: Accumulated,
+    sender: SyncSender<Reduction>,
+    key_filter: Option<Arc<ContextBuilder>>,
+    sorted_stream: Option<SortedStream>
+) -> Result<JoinHandle<Result<()>>> {
+    let consumer = Builder::new().name(String::from("omnimap-consumer")).spawn(move|| -> Result<()> {
+        let key_filter = KeyFilter::new(key_filter)?;
+        let mut batch = vec![];
+        for (key, result) in results {
+            if !key_filter.selects(&key)? {
+                continue;
+            }
+            let key = Arc::new(key);
+            if let Some(sorted_stream) = &sorted_stream {
+                sorted_stream.register(key.clone());
+            }
+            batch.push((key, result));
+            if batch.len() >= REDUCED_BATCH_KEYS {
+                sender.send(Reduction::Reduced(mem::take(&mut batch)))?;
+            }
+        }
+        if !batch.is_empty() {
+            sender.send(Reduction::Reduced(batch))?;
+        }
+        Ok(())
+    }).context("Could not spawn consumer thread")?;
+    Ok(consumer)
+}
+
+///The order the file parts of a key are read in, the index values are always the most recent part
+#[derive(Clone, Copy)]
+pub enum PartReadOrder {
+    ///file parts from the oldest to the newest, then the index values
+    Asc,
+    ///the index values, then the file parts from the newest to the oldest
+    Desc,
+    ///the index values, then the file parts from the oldest to the newest
+    IndexFirst
+}
+
+impl PartReadOrder {
+    pub fn new(order: &str) -> PartReadOrder {
+        match order {
+            "desc" => PartReadOrder::Desc,
+            "index-first" => PartReadOrder::IndexFirst,
+            _ => PartReadOrder::Asc
+        }
+    }
+
+    ///Returns the parts of a key in the order they should be read
+    fn sources(self, parts: Vec<usize>, with_index: bool) -> Vec<PartSource> {
+        let mut sources: Vec<PartSource> = parts.into_iter().map(PartSource::File).collect();
+        if let PartReadOrder::Desc = self {
+            sources.reverse();
+        }
+        if with_index {
+            match self {
+                PartReadOrder::Asc => sources.push(PartSource::Index),
+                PartReadOrder::Desc | PartReadOrder::IndexFirst => sources.insert(0, PartSource::Index)
+            }
+        }
+        sources
+    }
+}
+
+enum PartSource {
+    File(usize),
+    Index
+}
+
+///What to do when a file part of an indexed key does not exist
+#[derive(Clone, Copy)]
+pub enum MissingPartPolicy {
+    Skip,
+    Error
+}
+
+impl MissingPartPolicy {
+    pub fn new(policy: &str) -> MissingPartPolicy {
+        if policy == "skip" {
+            MissingPartPolicy::Skip
+        } else {
+            MissingPartPolicy::Error
+        }
+    }
+}
+
+///Drops the file parts that do not exist with MissingPartPolicy::Skip, fails on the first one otherwise
+fn existing_sources(
+    key: &str,
+    sources: Vec<PartSource>,
+    container: &MapContainer,
+    root: &PathBuf,
+    policy: MissingPartPolicy
+) -> Result<Vec<PartSource>> {
+    let mut existing = Vec::with_capacity(sources.len());
+    for source in sources {
+        if let PartSource::File(part) = source {
+            let file_path = container.part_file_path(root, part)?;
+            if !Path::new(&file_path).exists() {
+                match policy {
+                    MissingPartPolicy::Skip => {
+                        eprintln!("Skipping missing file part {} of key {}: {}", part, key, file_path);
+                        continue;
+                    },
+                    MissingPartPolicy::Error => return Err(anyhow!("Temp directory modified while running"))
+                }
+            }
+        }
+        existing.push(source);
+    }
+    Ok(existing)
+}
+
+///The js filter() of the consumer thread, every key is selected without it
+struct KeyFilter {
+    context: Option<JsContext>
+}
+
+impl KeyFilter {
+    fn new(context_builder: Option<Arc<ContextBuilder>>) -> Result<KeyFilter> {
+        let context = match context_builder {
+            Some(context_builder) => Some(context_builder.build()?),
+            None => None
+        };
+        Ok(KeyFilter { context })
+    }
+
+    fn selects(&self, key: &str) -> Result<bool> {
+        match &self.context {
+            Some(context) => context.run_key_filter(key),
+            None => Ok(true)
+        }
+    }
+}
+
+///Sends keys that have all of their values in memory, small keys are batched together when coalescing
+struct MemoryKeys {
+    coalesce_size: Option<usize>,
+    batch: Vec<(Arc<String>, Vec<String>)>,
+    batch_size: usize
+}
+
+impl MemoryKeys {
+    fn new(coalesce_size: Option<usize>) -> MemoryKeys {
+        MemoryKeys {
+            coalesce_size,
+            batch: vec![],
+            batch_size: 0
+        }
+    }
+
+    fn send(&mut self, sender: &SyncSender<Reduction>, key: Arc<String>, values: Vec<String>, size: usize) -> Result<()> {
+        match self.coalesce_size {
+            Some(max_size) if size < max_size => {
+                self.batch_size += size;
+                self.batch.push((key, values));
+                if self.batch_size >= max_size {
+                    sender.send(Reduction::KeyBatch(mem::take(&mut self.batch)))?;
+                    self.batch_size = 0;
+                }
+            },
+            _ => {
+                //the values are treated as a single file part with only 1 line
+                sender.send(Reduction::KeyInit(key.clone(), 1))?;
+                sender.send(Reduction::FilePartInit(key.clone()))?;
+                sender.send(Reduction::FileLineInit(key.clone(), 0, 1))?;
+                sender.send(Reduction::FileLine(key, 0, ReduceValue::FromIndex(values)))?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Sends the last batch
+    fn finish(self, sender: &SyncSender<Reduction>) -> Result<()> {
+        if !self.batch.is_empty() {
+            sender.send(Reduction::KeyBatch(self.batch))?;
+        }
         Ok(())
-    });
-    consumer
-}
\ No newline at end of file
+    }
+}
+
+///Sends the merged values of a key as a single part.  
+///The values are split into at most as many lines as the key had, so every line stays about as large as the file part lines
+fn send_sorted(
+    sender: &SyncSender<Reduction>,
+    key: &Arc<String>,
+    mut merge: SortedMerge,
+    total_values: usize,
+    total_lines: usize,
+    debug: bool
+) -> Result<()> {
+    let line_size = cmp::max(1, ceil_div(total_values, total_lines));
+    //a key without values is still sent as one empty line so the reducer can finish it
+    let lines = cmp::max(1, ceil_div(total_values, line_size));
+    sender.send(Reduction::KeyInit(key.clone(), 1))?;
+    sender.send(Reduction::FilePartInit(key.clone()))?;
+    sender.send(Reduction::FileLineInit(key.clone(), 0, lines))?;
+    let mut line = Vec::with_capacity(line_size);
+    let mut sent = 0;
+    loop {
+        let value = merge.next()?;
+        let is_done = value.is_none();
+        if let Some(value) = value {
+            line.push(value);
+        }
+        if line.len() == line_size || (is_done && (!line.is_empty() || sent == 0)) {
+            if sent == lines {
+                return Err(anyhow!("Key {} has more values than its file parts recorded", key));
+            }
+            if debug {
+                debug_values(key, 0, &to_json_line(&line));
+            }
+            sender.send(Reduction::FileLine(key.clone(), 0, ReduceValue::FromIndex(mem::take(&mut line))))?;
+            sent += 1;
+        }
+        if is_done {
+            break;
+        }
+    }
+    if sent != lines {
+        return Err(anyhow!("Key {} has fewer values than its file parts recorded", key));
+    }
+    Ok(())
+}
+
+fn ceil_div(a: usize, b: usize) -> usize {
+    let quotient = a / b;
+    if quotient * b < a { quotient + 1 } else { quotient }
+}
+
+///Writes a batch of values that will be passed to reduce() for the debugged key to stderr
+fn debug_values(key: &str, part: usize, json_line: &str) {
+    eprint!("{}\t{}\t{}", key, part, json_line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::env::temp_dir;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_existing_sources() {
+        let mut dir = temp_dir();
+        dir.push(format!("omnimap-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut container = MapContainer::new("k");
+        for n in 0..3 {
+            container.add_value(n.to_string());
+            container.flush_to_file_part(&dir, 1, usize::MAX, 0, false, false).unwrap();
+        }
+        fs::remove_file(container.part_file_path(&dir, 1).unwrap()).unwrap();
+
+        let sources = PartReadOrder::Asc.sources(container.parts().collect(), false);
+        let existing = existing_sources("k", sources, &container, &dir, MissingPartPolicy::Skip).unwrap();
+        let parts: Vec<usize> = existing.iter().filter_map(|source| match source {
+            PartSource::File(part) => Some(*part),
+            PartSource::Index => None
+        }).collect();
+        assert_eq!(parts, vec![0, 2]);
+
+        let sources = PartReadOrder::Asc.sources(container.parts().collect(), false);
+        assert!(existing_sources("k", sources, &container, &dir, MissingPartPolicy::Error).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::{Arc, Mutex};
+
+///How many of the empty ones are reported
+const MAX_SAMPLES: usize = 5;
+
+///Counts the map tasks or keys that produced nothing and keeps a few samples of them, used with --trace-empties
+pub struct EmptyTrace {
+    counts: Arc<Mutex<EmptyCounts>>
+}
+
+#[derive(Default)]
+struct EmptyCounts {
+    total: usize,
+    empty: usize,
+    samples: Vec<String>
+}
+
+impl Clone for EmptyTrace {
+    fn clone(&self) -> EmptyTrace {
+        EmptyTrace {
+            counts: self.counts.clone()
+        }
+    }
+}
+
+impl EmptyTrace {
+    pub fn new() -> EmptyTrace {
+        EmptyTrace {
+            counts: Arc::new(Mutex::new(EmptyCounts::default()))
+        }
+    }
+
+    ///Counts one more map task or key, sample is only called for the first few empty ones
+    pub fn record<F: FnOnce() -> String>(&self, empty: bool, sample: F) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total += 1;
+        if empty {
+            counts.empty += 1;
+            if counts.samples.len() < MAX_SAMPLES {
+                counts.samples.push(sample());
+            }
+        }
+    }
+
+    ///Writes the counts and the samples to stderr, what names the traced items (eg. "map tasks")
+    pub fn report(&self, what: &str) {
+        eprintln!("{}", self.summary(what));
+    }
+
+    fn summary(&self, what: &str) -> String {
+        let counts = self.counts.lock().unwrap();
+        if counts.samples.is_empty() {
+            format!("{} of {} {} were empty", counts.empty, counts.total, what)
+        } else {
+            format!("{} of {} {} were empty, eg. {}", counts.empty, counts.total, what, counts.samples.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let trace = EmptyTrace::new();
+        for n in 0..10 {
+            trace.record(n % 2 == 0, || format!("key {}", n));
+        }
+        assert_eq!(trace.summary("keys"), "5 of 10 keys were empty, eg. key 0, key 2, key 4, key 6, key 8");
+        assert_eq!(EmptyTrace::new().summary("keys"), "0 of 0 keys were empty");
+    }
+}
@@ -0,0 +1,39 @@
+use std::io::{stderr, Write};
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use anyhow::{Result, Context};
+
+///Writes the records that the js code rejected with emitError() as json lines of {reason, record}.  
+///Shared by all the js contexts, each line is written whole so lines from different threads never interleave
+pub struct ErrorOutput {
+    output: Arc<Mutex<Box<dyn Write + Send>>>
+}
+
+impl Clone for ErrorOutput {
+    fn clone(&self) -> ErrorOutput {
+        ErrorOutput {
+            output: self.output.clone()
+        }
+    }
+}
+
+impl ErrorOutput {
+    ///Writes to path, or to stderr if there is no path
+    pub fn new(path: Option<&PathBuf>) -> Result<ErrorOutput> {
+        let output: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(File::create(path).with_context(|| format!("Could not create error output file: {}", path.display()))?),
+            None => Box::new(stderr())
+        };
+        Ok(ErrorOutput {
+            output: Arc::new(Mutex::new(output))
+        })
+    }
+
+    pub fn write(&self, reason: &str, record: &str) -> Result<()> {
+        let line = serde_json::json!({"reason": reason, "record": record});
+        self.output.lock().unwrap()
+            .write_all(format!("{}\n", line).as_bytes())
+            .context("Could not write to error output")
+    }
+}
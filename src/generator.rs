@@ -0,0 +1,198 @@
+use std::io::{self, Read, BufRead};
+use std::cmp;
+use anyhow::{Result, Context, anyhow};
+
+///What --generate-input synthesizes, parsed from a comma separated list of NAME=VALUE settings
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputSpec {
+    ///the number of generated lines
+    pub lines: usize,
+    ///the number of distinct keys
+    pub keys: usize,
+    pub distribution: KeyDistribution,
+    ///values are integers from 1 up to this
+    pub max_value: u64,
+    pub seed: u64
+}
+
+///How often each key is picked
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyDistribution {
+    ///every key is equally likely
+    Uniform,
+    ///the key of rank r is picked with a probability proportional to 1 / r^skew, so the first keys are hot
+    Zipf(f64)
+}
+
+impl InputSpec {
+    ///Parses a spec like "lines=100000,keys=1000,dist=zipf,skew=1.2,values=100,seed=7", every setting is optional
+    pub fn new(spec: &str) -> Result<InputSpec> {
+        let mut input_spec = InputSpec {
+            lines: 1000,
+            keys: 100,
+            distribution: KeyDistribution::Uniform,
+            max_value: 100,
+            seed: 0
+        };
+        let mut zipf = false;
+        let mut skew: f64 = 1.0;
+        for setting in spec.split(',').filter(|setting| !setting.is_empty()) {
+            let (name, value) = match setting.find('=') {
+                Some(position) => (&setting[..position], &setting[position + 1..]),
+                None => return Err(anyhow!("Invalid input spec setting: {}", setting))
+            };
+            match name {
+                "lines" => input_spec.lines = value.parse().with_context(|| format!("Invalid generated line count: {}", value))?,
+                "keys" => input_spec.keys = value.parse().with_context(|| format!("Invalid generated key count: {}", value))?,
+                "values" => input_spec.max_value = value.parse().with_context(|| format!("Invalid generated max value: {}", value))?,
+                "seed" => input_spec.seed = value.parse().with_context(|| format!("Invalid generator seed: {}", value))?,
+                "skew" => skew = value.parse().with_context(|| format!("Invalid zipf skew: {}", value))?,
+                "dist" => zipf = match value {
+                    "uniform" => false,
+                    "zipf" => true,
+                    _ => return Err(anyhow!("Invalid key distribution: {}", value))
+                },
+                _ => return Err(anyhow!("Unknown input spec setting: {}", name))
+            }
+        }
+        if input_spec.keys == 0 { return Err(anyhow!("Invalid generated key count: 0")) };
+        if input_spec.max_value == 0 { return Err(anyhow!("Invalid generated max value: 0")) };
+        if skew.is_nan() || skew <= 0.0 { return Err(anyhow!("Invalid zipf skew: {}", skew)) };
+        if zipf {
+            input_spec.distribution = KeyDistribution::Zipf(skew);
+        }
+        Ok(input_spec)
+    }
+}
+
+///Generates the lines of an InputSpec as a reader, each line is "keyN\tvalue"
+pub struct GeneratedInput {
+    spec: InputSpec,
+    random: SplitMix64,
+    ///the cumulative probability of each key rank for zipf, empty for uniform
+    cdf: Vec<f64>,
+    generated: usize,
+    line: Vec<u8>,
+    position: usize
+}
+
+impl GeneratedInput {
+    pub fn new(spec: InputSpec) -> GeneratedInput {
+        let cdf = match spec.distribution {
+            KeyDistribution::Uniform => vec![],
+            KeyDistribution::Zipf(skew) => {
+                let weights: Vec<f64> = (1..=spec.keys).map(|rank| 1.0 / (rank as f64).powf(skew)).collect();
+                let total: f64 = weights.iter().sum();
+                let mut cumulative = 0.0;
+                weights.iter().map(|weight| {
+                    cumulative += weight / total;
+                    cumulative
+                }).collect()
+            }
+        };
+        GeneratedInput {
+            random: SplitMix64(spec.seed),
+            spec,
+            cdf,
+            generated: 0,
+            line: vec![],
+            position: 0
+        }
+    }
+
+    fn next_key(&mut self) -> usize {
+        match self.spec.distribution {
+            KeyDistribution::Uniform => (self.random.next_u64() % self.spec.keys as u64) as usize,
+            KeyDistribution::Zipf(_) => {
+                let sample = self.random.next_f64();
+                //the first key with a cumulative probability above sample, the comparator never returns Equal so it is always Err
+                let first_above = self.cdf
+                    .binary_search_by(|probability| if *probability <= sample { cmp::Ordering::Less } else { cmp::Ordering::Greater })
+                    .unwrap_or_else(|index| index);
+                //rounding can leave the last cumulative probability just under 1
+                cmp::min(first_above, self.spec.keys - 1)
+            }
+        }
+    }
+
+    fn generate_line(&mut self) {
+        let key = self.next_key();
+        let value = self.random.next_u64() % self.spec.max_value + 1;
+        self.line.clear();
+        self.line.extend_from_slice(format!("key{}\t{}\n", key + 1, value).as_bytes());
+        self.position = 0;
+        self.generated += 1;
+    }
+}
+
+impl Read for GeneratedInput {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let amount = cmp::min(out.len(), available.len());
+        out[..amount].copy_from_slice(&available[..amount]);
+        self.consume(amount);
+        Ok(amount)
+    }
+}
+
+impl BufRead for GeneratedInput {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position >= self.line.len() && self.generated < self.spec.lines {
+            self.generate_line();
+        }
+        Ok(&self.line[self.position..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position = cmp::min(self.position + amount, self.line.len());
+    }
+}
+
+///A small seedable generator, the same seed always gives the same numbers on every platform
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    ///A float in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn generate(spec: &str) -> Vec<String> {
+        GeneratedInput::new(InputSpec::new(spec).unwrap()).lines().map(|line| line.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_generated_input() {
+        let lines = generate("lines=5000,keys=10,values=3,seed=7");
+        assert_eq!(lines.len(), 5000);
+        assert_eq!(lines, generate("lines=5000,keys=10,values=3,seed=7"));
+        assert_ne!(lines, generate("lines=5000,keys=10,values=3,seed=8"));
+        let mut counts = HashMap::new();
+        for line in &lines {
+            let mut fields = line.split('\t');
+            *counts.entry(fields.next().unwrap().to_owned()).or_insert(0) += 1;
+            assert!((1..=3).contains(&fields.next().unwrap().parse::<u64>().unwrap()));
+        }
+        assert_eq!(counts.len(), 10);
+        //the first key of a zipf distribution is the hottest
+        let zipf = generate("lines=5000,keys=100,dist=zipf,skew=1.5");
+        let hot = zipf.iter().filter(|line| line.starts_with("key1\t")).count();
+        assert!(hot > 5000 / 3);
+        assert!(InputSpec::new("lines=10,shape=round").is_err());
+        assert!(InputSpec::new("keys=0").is_err());
+    }
+}
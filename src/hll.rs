@@ -0,0 +1,114 @@
+///Bits of the hash that pick the register, 4096 registers give a standard error of about 1.6%
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+///A HyperLogLog sketch for approximate distinct counts, used by the hll(), hllMerge() and hllCount() js helpers.  
+///Sketches are passed between reduce passes as base64 strings, merging them gives the same sketch as inserting all their values
+pub struct HyperLogLog {
+    registers: Vec<u8>
+}
+
+impl HyperLogLog {
+    pub fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; REGISTERS]
+        }
+    }
+
+    pub fn insert(&mut self, value: &str) {
+        let hash = stable_hash(value);
+        let register = (hash >> (64 - PRECISION)) as usize;
+        //the guard bit caps the rank when the remaining bits are all zero
+        let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[register] {
+            self.registers[register] = rank;
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, other_register) in self.registers.iter_mut().zip(&other.registers) {
+            if *other_register > *register {
+                *register = *other_register;
+            }
+        }
+    }
+
+    ///Returns the estimated number of distinct values, small counts use linear counting
+    pub fn count(&self) -> f64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|register| 2f64.powi(-i32::from(*register))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|register| **register == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round()
+        } else {
+            estimate.round()
+        }
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::encode(&self.registers)
+    }
+
+    pub fn from_base64(sketch: &str) -> Result<HyperLogLog, String> {
+        let registers = base64::decode(sketch).map_err(|_| String::from("Invalid hll sketch"))?;
+        if registers.len() != REGISTERS {
+            return Err(String::from("Invalid hll sketch"));
+        }
+        Ok(HyperLogLog { registers })
+    }
+}
+
+///The hash of a sketch value, it must never change so sketches saved by any build can be merged.  
+///64-bit FNV-1a of the utf-8 bytes, followed by the murmur3 fmix64 finalizer so the register bits are well mixed for short values
+fn stable_hash(value: &str) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in value.as_bytes() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_and_merge() {
+        let mut all = HyperLogLog::new();
+        let mut even = HyperLogLog::new();
+        let mut odd = HyperLogLog::new();
+        for n in 0..100_000 {
+            all.insert(&n.to_string());
+            if n % 2 == 0 { even.insert(&n.to_string()) } else { odd.insert(&n.to_string()) };
+        }
+        assert!((all.count() - 100_000.0).abs() < 3_000.0);
+        even.merge(&odd);
+        assert_eq!(even.count(), all.count());
+
+        let mut small = HyperLogLog::new();
+        for value in &["a", "b", "c", "a"] {
+            small.insert(value);
+        }
+        assert_eq!(HyperLogLog::from_base64(&small.to_base64()).unwrap().count(), 3.0);
+        assert!(HyperLogLog::from_base64("bm90IGEgc2tldGNo").is_err());
+    }
+
+    #[test]
+    fn test_stable_hash() {
+        //saved sketches depend on these staying the same across builds
+        assert_eq!(stable_hash(""), 0xefd0_1f60_ba99_2926);
+        assert_eq!(stable_hash("a"), 0x82a2_a958_a9be_ce5b);
+        assert_eq!(stable_hash("omnimap"), 0xc446_3096_28ac_7f8b);
+    }
+}
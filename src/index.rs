@@ -1,63 +1,166 @@
 use rocksdb;
 use std::path::PathBuf;
+use std::io::prelude::*;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock, atomic::{AtomicUsize, Ordering}};
-use anyhow::{Context, Result};
-use super::map_container::MapContainer;
+use std::mem;
+use anyhow::{Context, Result, Error, anyhow};
+use super::map_container::{MapContainer, ContainerState, DEFAULT_BUFFER_SIZE};
+use super::json_line::from_json;
+use super::result_table::ResultsOrdering;
+use super::retry::with_retries;
+use super::semaphore::Semaphore;
+use super::sorted_merge::sort_numeric;
+
+///Settings of the index and the file parts of its keys
+pub struct IndexOptions {
+    ///keys are flushed to file parts when their buffered values reach this size
+    pub flush_size: usize,
+    pub max_part_size: usize,
+    pub max_parts: usize,
+    ///failed index and file part writes are retried this many times
+    pub io_retries: usize,
+    ///limits how many file parts are written at the same time
+    pub part_slots: Option<Semaphore>,
+    ///all the parts of a key are written to a single file
+    pub single_file_parts: bool,
+    ///fail on the first key with more than one value
+    pub expect_unique_keys: bool,
+    ///keep every file part sorted numerically
+    pub sort_values: bool,
+    ///fail once the file parts of all the keys take more than this many bytes
+    pub max_temp_bytes: Option<usize>,
+    ///keep the already indexed values of a key ahead of the new ones
+    pub ordered: bool
+}
+
 pub struct Index {
     db: rocksdb::DB,
     root_dir: PathBuf,
+    flush_size: usize,
+    max_part_size: usize,
+    max_parts: usize,
+    io_retries: usize,
+    ///limits how many file parts are written at the same time
+    part_slots: Option<Semaphore>,
+    single_file_parts: bool,
+    expect_unique_keys: bool,
+    sort_values: bool,
+    max_temp_bytes: Option<usize>,
+    ordered: bool,
     total_keys: AtomicUsize,
+    total_parts: AtomicUsize,
+    spilled_bytes: AtomicUsize,
 }
 
 impl Index {
-    pub fn new(path: &PathBuf) -> Result<Index> {
-        let root_dir = path.clone();
-        let mut index_path = root_dir.clone();
+    ///Creates the index database under db_dir, file parts are kept under root_dir
+    pub fn new(db_dir: &PathBuf, root_dir: &PathBuf, options: IndexOptions) -> Result<Index> {
+        let IndexOptions { flush_size, max_part_size, max_parts, io_retries, part_slots, single_file_parts, expect_unique_keys, sort_values, max_temp_bytes, ordered } = options;
+        let root_dir = root_dir.clone();
+        let mut index_path = db_dir.clone();
         index_path.push("index");
         let mut opts = rocksdb::Options::default();
         opts.create_if_missing(true);
         let db = rocksdb::DB::open_default(&index_path).with_context(|| format!("Could not create index in: {}", index_path.display()))?;
 
-        Ok(Index {db, root_dir, total_keys: AtomicUsize::new(0)})
+        Ok(Index {
+            db,
+            root_dir,
+            flush_size,
+            max_part_size,
+            max_parts,
+            io_retries,
+            part_slots,
+            single_file_parts,
+            expect_unique_keys,
+            sort_values,
+            max_temp_bytes,
+            ordered,
+            total_keys: AtomicUsize::new(0),
+            total_parts: AtomicUsize::new(0),
+            spilled_bytes: AtomicUsize::new(0)
+        })
     }
 
-    pub fn merge(&self, map_results: &Arc<RwLock<HashMap<String, MapContainer>>>, flush_size: usize, max_part_size: usize) -> Result<()> {
-        let mut batch = rocksdb::WriteBatch::default();
+    ///Merges the map results into the index, in ordered mode the already indexed values of a key are kept ahead of the new ones
+    pub fn merge(&self, map_results: &Arc<RwLock<HashMap<String, MapContainer>>>) -> Result<()> {
+        let mut entries = vec![];
         let mut map_results = map_results.write().unwrap();
         for (key, mut memory_container) in map_results.drain() {
-            match self.get(&key)? {
+            match with_retries(self.io_retries, || self.get(&key))? {
                 Some(index_container) => {
+                    //an indexed key has at least one value, even if they were all flushed to its file parts
+                    self.check_unique(&key, index_container.values.len().max(1) + memory_container.values.len())?;
                     let mut merged_container = MapContainer::new(&key);
-                    merged_container.add_values(memory_container.values);
-                    merged_container.transfer_data(index_container);
-                    if merged_container.buffered_size >= flush_size {
-                        merged_container.flush_to_file_part(&self.root_dir, max_part_size)?;
+                    if self.ordered {
+                        merged_container.transfer_data(index_container);
+                        merged_container.add_values(memory_container.values);
+                    } else {
+                        merged_container.add_values(memory_container.values);
+                        merged_container.transfer_data(index_container);
+                    }
+                    if merged_container.buffered_size >= self.flush_size {
+                        self.flush(&key, &mut merged_container)?;
                         let bytes = MapContainer::serialize(&merged_container)?;
-                        batch.put(&key, bytes);
+                        entries.push((key, bytes));
                     } else {
                         let bytes = MapContainer::serialize(&merged_container)?;
-                        batch.put(&key, bytes);
+                        entries.push((key, bytes));
                     }
                 },
                 None => {
+                    self.check_unique(&key, memory_container.values.len())?;
                     self.total_keys.fetch_add(1, Ordering::SeqCst);
-                    if memory_container.buffered_size >= flush_size {
-                        memory_container.flush_to_file_part(&self.root_dir, max_part_size)?;
+                    if memory_container.buffered_size >= self.flush_size {
+                        self.flush(&key, &mut memory_container)?;
                         let bytes = MapContainer::serialize(&memory_container)?;
-                        batch.put(&key, bytes);
+                        entries.push((key, bytes));
                     } else {
                         let bytes = MapContainer::serialize(&memory_container)?;
-                        batch.put(&key, bytes);
+                        entries.push((key, bytes));
                     }
                 }
             }
         }
-        self.db.write(batch).context("Could not write to index")?;
+        //the batch is consumed by the write so it is rebuilt on every attempt
+        with_retries(self.io_retries, || {
+            let mut batch = rocksdb::WriteBatch::default();
+            for (key, bytes) in &entries {
+                batch.put(key, bytes);
+            }
+            self.db.write(batch).context("Could not write to index")
+        })?;
 
         Ok(())
     }
 
+    ///Fails for a key with more than one value when unique keys are expected
+    fn check_unique(&self, key: &str, values: usize) -> Result<()> {
+        if self.expect_unique_keys && values > 1 {
+            return Err(unique_key_error(key, values));
+        }
+        Ok(())
+    }
+
+    ///Flushes a container to its file parts while keeping track of the spilled data
+    fn flush(&self, key: &str, container: &mut MapContainer) -> Result<()> {
+        if self.sort_values {
+            container.values = sort_numeric(key, mem::take(&mut container.values))?;
+        }
+        let parts_before = container.parts().count();
+        let _permit = self.part_slots.as_ref().map(|slots| slots.acquire());
+        let written = container.flush_to_file_part(&self.root_dir, self.max_part_size, self.max_parts, self.io_retries, self.single_file_parts, self.sort_values)?;
+        self.total_parts.fetch_add(container.parts().count() - parts_before, Ordering::SeqCst);
+        let spilled_bytes = self.spilled_bytes.fetch_add(written, Ordering::SeqCst) + written;
+        match self.max_temp_bytes {
+            Some(max_bytes) if spilled_bytes > max_bytes => {
+                Err(anyhow!("The file parts take {} bytes, over the --max-temp-bytes limit of {} bytes", spilled_bytes, max_bytes))
+            },
+            _ => Ok(())
+        }
+    }
+
     pub fn get(&self, key: &str) -> Result<Option<MapContainer>> {
         let container = match self.db.get(&key).context("Could not read from the index")? {
             Some(bytes) => Some(MapContainer::deserialize(&bytes)?),
@@ -67,19 +170,83 @@ impl Index {
         Ok(container)
     }
 
-    #[allow(dead_code)]
+    ///Returns up to max values of the first key with more than one value (or any value if there is no such key).  
+    ///Used to test the behavior of reduce() before the actual reduce phase.
+    pub fn sample(&self, max: usize) -> Result<Option<(String, Vec<String>)>> {
+        let mut fallback = None;
+        for pair in self.iter() {
+            let (key, container) = pair?;
+            if let ContainerState::NoData = container.state() {
+                continue;
+            }
+            let mut values = vec![];
+            for part in container.parts() {
+                let reader = container.read_part(&self.root_dir, part, DEFAULT_BUFFER_SIZE)?;
+                for line in reader.lines() {
+                    let mut line_values: Vec<String> = from_json(&line?)?;
+                    values.append(&mut line_values);
+                    if values.len() >= max {
+                        break;
+                    }
+                }
+                if values.len() >= max {
+                    break;
+                }
+            }
+            values.extend(container.values);
+            values.truncate(max);
+            if values.len() > 1 {
+                return Ok(Some((key, values)));
+            } else if fallback.is_none() {
+                fallback = Some((key, values));
+            }
+        }
+
+        Ok(fallback)
+    }
+
     pub fn total_keys(&self) -> usize {
         self.total_keys.load(Ordering::SeqCst)
     }
 
+    pub fn total_parts(&self) -> usize {
+        self.total_parts.load(Ordering::SeqCst)
+    }
+
+    pub fn spilled_bytes(&self) -> usize {
+        self.spilled_bytes.load(Ordering::SeqCst)
+    }
+
+    ///Compacts the whole index so the full scan during reduce reads fewer files
+    pub fn compact(&self) {
+        self.db.compact_range::<&[u8], &[u8]>(None, None);
+    }
+
     pub fn root(&self) -> PathBuf {
         self.root_dir.clone()
     }
 
     ///Creates an iterator over index entries
     pub fn iter(&self) -> IndexIterator {
-        IndexIterator { iterator: self.db.iterator(rocksdb::IteratorMode::Start) }
+        self.iter_ordered(ResultsOrdering::Asc)
     }
+
+    ///Creates an iterator over index entries in the given key order
+    pub fn iter_ordered(&self, order: ResultsOrdering) -> IndexIterator {
+        match order {
+            ResultsOrdering::Asc => {
+                IndexIterator { iterator: self.db.iterator(rocksdb::IteratorMode::Start) }
+            },
+            ResultsOrdering::Desc => {
+                IndexIterator { iterator: self.db.iterator(rocksdb::IteratorMode::End) }
+            }
+        }
+    }
+}
+
+///The error of a key that collided with --expect-unique-keys
+pub fn unique_key_error(key: &str, values: usize) -> Error {
+    anyhow!("Key {} received {} values but unique keys are expected", key, values)
 }
 
 ///Wrapper around the rocksdb iterator to create a higher level iterator that also deserializes the entries
@@ -1,68 +1,306 @@
-use std::thread::{spawn, JoinHandle};
-use std::collections::HashMap;
+use std::thread::{Builder, JoinHandle};
+use std::collections::{HashMap, HashSet, BTreeMap};
 use std::sync::{Arc, RwLock, mpsc::{Sender, channel}, Mutex, Condvar, Barrier};
-use std::path::PathBuf;
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use super::combiner::combine_map_results;
+use super::combiner::{combine_map_results, KeyGrouping};
 use super::js::MapResult;
-use super::index::Index;
+use super::index::{Index, unique_key_error};
+use super::map_container::MapContainer;
 use super::thread_pool::ThreadPool;
+use super::progress::Progress;
+use super::streaming_reduce::Accumulated;
 
-///Creates the index on disk and spawns the indexer thread
+///Spawns the indexer thread that merges the map results into the index.  
+///Map results are tagged with the sequence of their map task, in ordered mode they are combined in that order.
 pub fn spawn_indexer(
-    index_dir: &PathBuf,
+    index: Arc<Index>,
     pool: ThreadPool,
     partitions: usize,
-    key_flush_size: usize,
-    max_file_part_size: usize,
-    index_every: usize
-) -> Result<(JoinHandle<()>, Sender<Vec<MapResult>>, Arc<Index>, IndexGuard)> {
-    let index = Arc::new(Index::new(index_dir)?);
-    let thread_index = index.clone();
+    options: IndexerOptions
+) -> Result<(JoinHandle<Result<()>>, Sender<(usize, Vec<MapResult>)>, IndexGuard)> {
+    let IndexerOptions { index_cycle, ordered, grouping, dump_partitions, progress } = options;
+    let thread_index = index;
     let (sender, receiver) = channel();
     let index_guard = IndexGuard::new();
     let thread_index_guard = index_guard.clone();
-    let handle = spawn(move|| {
+    let handle = Builder::new().name(String::from("omnimap-indexer")).spawn(move|| -> Result<()> {
+        //the first merge error, the rest of the map results are only drained after it
+        let merge_error = Arc::new(Mutex::new(None));
         //setup the bucket list
         let mut bucket_list = Vec::with_capacity(partitions);
         for _ in 0..partitions {
             bucket_list.push(Arc::new(RwLock::new(HashMap::new())));
         }
+        //distinct keys and bytes merged to the index by each partition, a key is seen again in every cycle it gets values
+        let mut partition_stats: Vec<(HashSet<String>, usize)> = vec![(HashSet::new(), 0); partitions];
         let mut map_iterations: usize = 0;
-        for results in receiver.iter() {
-            map_iterations += 1;
-            combine_map_results(&mut bucket_list, results, partitions);
-            if map_iterations >= index_every {
-                let active_buckets = bucket_list.iter().filter(|b| b.read().unwrap().len() > 0);
-                let b = Arc::new(Barrier::new(active_buckets.clone().count() + 1));
-                thread_index_guard.start_indexing();
-                map_iterations = 0;
-                for bucket in active_buckets {
-                    let index = thread_index.clone();
-                    let bucket = Arc::clone(bucket);
-                    let b = b.clone();
-                    pool.execute(move|| {
-                        index.merge(&bucket, key_flush_size, max_file_part_size).unwrap();
-                        b.wait();
-                    });
+        //out of order results waiting for the earlier ones in ordered mode
+        let mut pending = BTreeMap::new();
+        let mut next_sequence = 0;
+        for (sequence, results) in receiver.iter() {
+            if merge_error.lock().unwrap().is_some() {
+                continue;
+            }
+            let ready = if ordered {
+                take_ready(&mut pending, &mut next_sequence, sequence, results)
+            } else {
+                vec![results]
+            };
+            for results in ready {
+                map_iterations += 1;
+                if let Err(err) = combine_map_results(&mut bucket_list, results, partitions, &grouping) {
+                    merge_error.lock().unwrap().get_or_insert(err);
+                    break;
+                }
+                let cycle_done = match index_cycle {
+                    IndexCycle::Every(index_every) => map_iterations >= index_every,
+                    IndexCycle::Bytes(index_bytes) => buffered_bytes(&bucket_list) >= index_bytes
+                };
+                if cycle_done {
+                    if dump_partitions {
+                        record_partition_stats(&mut partition_stats, &bucket_list);
+                    }
+                    let active_buckets = bucket_list.iter().filter(|b| b.read().unwrap().len() > 0);
+                    let b = Arc::new(Barrier::new(active_buckets.clone().count() + 1));
+                    thread_index_guard.start_indexing();
+                    map_iterations = 0;
+                    for bucket in active_buckets {
+                        let index = thread_index.clone();
+                        let bucket = Arc::clone(bucket);
+                        let b = b.clone();
+                        let merge_error = merge_error.clone();
+                        pool.execute(move|| {
+                            if let Err(err) = index.merge(&bucket) {
+                                merge_error.lock().unwrap().get_or_insert(err);
+                            }
+                            b.wait();
+                        });
+                    }
+                    b.wait();
+                    thread_index_guard.finish_indexing();
+                    if let Some(progress) = &progress {
+                        progress.index_cycle();
+                    }
                 }
-                b.wait();
-                thread_index_guard.finish_indexing();
+            }
+        }
+        //results after a missing sequence (a failed map task) are still combined
+        for (_, results) in pending {
+            if merge_error.lock().unwrap().is_some() {
+                break;
+            }
+            if let Err(err) = combine_map_results(&mut bucket_list, results, partitions, &grouping) {
+                merge_error.lock().unwrap().get_or_insert(err);
             }
         }
         //do a last index
-        let active_buckets = bucket_list.iter().filter(|b| b.read().unwrap().len() > 0);
-        for bucket in active_buckets {
-            let index = thread_index.clone();
-            let bucket = Arc::clone(bucket);
-            pool.execute(move|| {
-                index.merge(&bucket, key_flush_size, max_file_part_size).unwrap();
-            });
+        if dump_partitions {
+            record_partition_stats(&mut partition_stats, &bucket_list);
+            for (partition, (keys, bytes)) in partition_stats.iter().enumerate() {
+                eprintln!("Partition {}: {} keys, {} bytes indexed", partition, keys.len(), bytes);
+            }
+        }
+        if merge_error.lock().unwrap().is_none() {
+            let active_buckets = bucket_list.iter().filter(|b| b.read().unwrap().len() > 0);
+            let b = Arc::new(Barrier::new(active_buckets.clone().count() + 1));
+            for bucket in active_buckets {
+                let index = thread_index.clone();
+                let bucket = Arc::clone(bucket);
+                let b = b.clone();
+                let merge_error = merge_error.clone();
+                pool.execute(move|| {
+                    if let Err(err) = index.merge(&bucket) {
+                        merge_error.lock().unwrap().get_or_insert(err);
+                    }
+                    b.wait();
+                });
+            }
+            b.wait();
+        }
+        let merge_error = merge_error.lock().unwrap().take();
+        match merge_error {
+            Some(err) => Err(err.context("Could not index the map results")),
+            None => Ok(())
+        }
+    }).context("Could not spawn indexer thread")?;
+
+    Ok((handle, sender, index_guard))
+}
+
+///Settings of the indexer thread
+pub struct IndexerOptions {
+    pub index_cycle: IndexCycle,
+    ///combine the map results in the order of their map tasks
+    pub ordered: bool,
+    pub grouping: KeyGrouping,
+    ///print the keys and bytes indexed by each partition at the end
+    pub dump_partitions: bool,
+    pub progress: Option<Progress>
+}
+
+///The values of each key sorted by key
+pub type Groups = BTreeMap<String, Vec<String>>;
+
+///Spawns the grouper thread that keeps all the map results in memory grouped by key, used instead of the indexer with --no-index.  
+///Returns the values of each key sorted by key when joined.
+pub fn spawn_grouper(
+    ordered: bool,
+    grouping: KeyGrouping,
+    expect_unique_keys: bool
+) -> Result<(JoinHandle<Result<Groups>>, Sender<(usize, Vec<MapResult>)>, IndexGuard)> {
+    let (sender, receiver) = channel();
+    let handle = Builder::new().name(String::from("omnimap-grouper")).spawn(move|| -> Result<Groups> {
+        let mut bucket_list = vec![Arc::new(RwLock::new(HashMap::new()))];
+        let mut pending = BTreeMap::new();
+        let mut next_sequence = 0;
+        //the first invalid key, the rest of the map results are only drained after it
+        let mut key_error = None;
+        for (sequence, results) in receiver.iter() {
+            if key_error.is_some() {
+                continue;
+            }
+            let ready = if ordered {
+                take_ready(&mut pending, &mut next_sequence, sequence, results)
+            } else {
+                vec![results]
+            };
+            for results in ready {
+                if let Err(err) = combine_map_results(&mut bucket_list, results, 1, &grouping) {
+                    key_error = Some(err);
+                    break;
+                }
+            }
+        }
+        if let Some(err) = key_error {
+            return Err(err);
+        }
+        for (_, results) in pending {
+            combine_map_results(&mut bucket_list, results, 1, &grouping)?;
+        }
+        let mut bucket = bucket_list[0].write().unwrap();
+        //normalized keys are printed in their first seen form
+        let groups: Groups = bucket.drain().map(|(key, container)| (container.display_key(&key).to_owned(), container.values)).collect();
+        if expect_unique_keys {
+            if let Some((key, values)) = groups.iter().find(|(_, values)| values.len() > 1) {
+                return Err(unique_key_error(key, values.len()));
+            }
         }
-    });
+        Ok(groups)
+    }).context("Could not spawn grouper thread")?;
+
+    //nothing is indexed so the mapper never has to wait
+    Ok((handle, sender, IndexGuard::new()))
+}
+
+///The running indexer, grouper or streaming reducer
+pub enum Indexer {
+    Index(JoinHandle<Result<()>>, Arc<Index>),
+    Memory(JoinHandle<Result<Groups>>),
+    Streaming(JoinHandle<Result<Accumulated>>)
+}
+
+impl Indexer {
+    ///Waits for all the map results to be grouped, fails if they could not be indexed
+    pub fn join(self) -> Result<Grouped> {
+        match self {
+            Indexer::Index(handle, index) => {
+                handle.join().unwrap()?;
+                Ok(Grouped::Index(index))
+            },
+            Indexer::Memory(handle) => Ok(Grouped::Memory(handle.join().unwrap()?)),
+            Indexer::Streaming(handle) => Ok(Grouped::Reduced(handle.join().unwrap()?))
+        }
+    }
+}
 
-    Ok((handle, sender, index, index_guard))
+///The map results grouped by key, in the index or in memory with --no-index, or already reduced with --streaming-reduce
+pub enum Grouped {
+    Index(Arc<Index>),
+    Memory(Groups),
+    Reduced(Accumulated)
+}
+
+impl Grouped {
+    pub fn total_keys(&self) -> usize {
+        match self {
+            Grouped::Index(index) => index.total_keys(),
+            Grouped::Memory(groups) => groups.len(),
+            Grouped::Reduced(results) => results.len()
+        }
+    }
+
+    pub fn total_parts(&self) -> usize {
+        match self {
+            Grouped::Index(index) => index.total_parts(),
+            Grouped::Memory(_) | Grouped::Reduced(_) => 0
+        }
+    }
+
+    pub fn spilled_bytes(&self) -> usize {
+        match self {
+            Grouped::Index(index) => index.spilled_bytes(),
+            Grouped::Memory(_) | Grouped::Reduced(_) => 0
+        }
+    }
+
+    ///Returns up to max values of the first key with more than one value (or any value if there is no such key)
+    pub fn sample(&self, max: usize) -> Result<Option<(String, Vec<String>)>> {
+        match self {
+            Grouped::Index(index) => index.sample(max),
+            Grouped::Memory(groups) => {
+                let sampled = groups.iter().find(|(_, values)| values.len() > 1).or_else(|| groups.iter().next());
+                Ok(sampled.map(|(key, values)| (key.clone(), values.iter().take(max).cloned().collect())))
+            },
+            //only the results are kept
+            Grouped::Reduced(_) => Ok(None)
+        }
+    }
+}
+
+///Adds results to the pending ones and returns the results that are next in sequence
+pub fn take_ready<T>(
+    pending: &mut BTreeMap<usize, T>,
+    next_sequence: &mut usize,
+    sequence: usize,
+    results: T
+) -> Vec<T> {
+    pending.insert(sequence, results);
+    let mut ready = vec![];
+    while let Some(results) = pending.remove(next_sequence) {
+        ready.push(results);
+        *next_sequence += 1;
+    }
+    ready
+}
+
+///When the indexer merges the combined map results into the index
+#[derive(Clone, Copy)]
+pub enum IndexCycle {
+    ///after this many map tasks
+    Every(usize),
+    ///once the buffered values of all the buckets reach this many bytes
+    Bytes(usize)
+}
+
+///The bytes of the values buffered in all the buckets
+fn buffered_bytes(bucket_list: &[Arc<RwLock<HashMap<String, MapContainer>>>]) -> usize {
+    bucket_list.iter()
+        .map(|bucket| bucket.read().unwrap().values().map(|container| container.buffered_size).sum::<usize>())
+        .sum()
+}
+
+///Adds the keys and buffered bytes of each bucket to the stats of its partition, keys already seen in an earlier cycle are only counted once
+fn record_partition_stats(partition_stats: &mut [(HashSet<String>, usize)], bucket_list: &[Arc<RwLock<HashMap<String, MapContainer>>>]) {
+    for (stats, bucket) in partition_stats.iter_mut().zip(bucket_list) {
+        let bucket = bucket.read().unwrap();
+        for key in bucket.keys() {
+            if !stats.0.contains(key) {
+                stats.0.insert(key.clone());
+            }
+        }
+        stats.1 += bucket.values().map(|container| container.buffered_size).sum::<usize>();
+    }
 }
 
 pub struct IndexGuard {
@@ -108,3 +346,24 @@ impl IndexGuard {
         ).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_stats() {
+        let bucket_list = vec![Arc::new(RwLock::new(HashMap::new())), Arc::new(RwLock::new(HashMap::new()))];
+        let mut partition_stats = vec![(HashSet::new(), 0); 2];
+        //a key that gets values in two index cycles is still a single key
+        for keys in &[vec!["a", "b"], vec!["a", "c"]] {
+            for key in keys {
+                bucket_list[0].write().unwrap().insert(key.to_string(), MapContainer::new(key));
+            }
+            record_partition_stats(&mut partition_stats, &bucket_list);
+            bucket_list[0].write().unwrap().clear();
+        }
+        assert_eq!(partition_stats[0].0.len(), 3);
+        assert_eq!(partition_stats[1].0.len(), 0);
+    }
+}
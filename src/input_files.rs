@@ -0,0 +1,111 @@
+use std::io::{self, Read, BufRead, BufReader};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::collections::VecDeque;
+use std::cmp;
+
+///Reads the input files one after the other as a single stream, used instead of stdin when input files are given.  
+///Each file is opened once the previous one ends, a file that does not end with a newline gets one so its last line is not joined with the next file
+pub struct InputFiles {
+    paths: VecDeque<PathBuf>,
+    current: Option<BufReader<File>>,
+    ///the last byte read from the current file
+    last_byte: Option<u8>,
+    pending_newline: bool
+}
+
+impl InputFiles {
+    pub fn new(paths: Vec<PathBuf>) -> InputFiles {
+        InputFiles {
+            paths: paths.into(),
+            current: None,
+            last_byte: None,
+            pending_newline: false
+        }
+    }
+
+    fn open(path: &Path) -> io::Result<BufReader<File>> {
+        let file = File::open(path).map_err(|err| io::Error::new(err.kind(), format!("Could not open input file {}: {}", path.display(), err)))?;
+        Ok(BufReader::new(file))
+    }
+}
+
+impl Read for InputFiles {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let amount = cmp::min(out.len(), available.len());
+        out[..amount].copy_from_slice(&available[..amount]);
+        self.consume(amount);
+        Ok(amount)
+    }
+}
+
+impl BufRead for InputFiles {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while !self.pending_newline {
+            if self.current.is_none() {
+                match self.paths.pop_front() {
+                    Some(path) => {
+                        self.current = Some(InputFiles::open(&path)?);
+                        self.last_byte = None;
+                    },
+                    None => return Ok(&[])
+                }
+            }
+            let file = self.current.as_mut().unwrap();
+            if !file.fill_buf()?.is_empty() {
+                break;
+            }
+            self.pending_newline = matches!(self.last_byte, Some(byte) if byte != b'\n');
+            self.current = None;
+        }
+        if self.pending_newline {
+            return Ok(&b"\n"[..]);
+        }
+        self.current.as_mut().unwrap().fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        if amount == 0 {
+            return;
+        }
+        if self.pending_newline {
+            self.pending_newline = false;
+            return;
+        }
+        if let Some(file) = &mut self.current {
+            let amount = cmp::min(amount, file.buffer().len());
+            if amount > 0 {
+                self.last_byte = Some(file.buffer()[amount - 1]);
+            }
+            file.consume(amount);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_input_files() {
+        let contents = ["a\nb", "", "c\n", "d"];
+        let paths: Vec<PathBuf> = contents.iter().map(|content| {
+            let path = temp_dir().join(format!("omnimap-test-{}.txt", Uuid::new_v4()));
+            fs::write(&path, content).unwrap();
+            path
+        }).collect();
+        let lines: Vec<String> = InputFiles::new(paths.clone()).lines().map(|line| line.unwrap()).collect();
+        assert_eq!(lines, vec!["a", "b", "c", "d"]);
+        let mut missing = paths.clone();
+        missing.push(temp_dir().join(format!("omnimap-test-{}.txt", Uuid::new_v4())));
+        let err = InputFiles::new(missing).lines().find_map(|line| line.err()).unwrap();
+        assert!(err.to_string().starts_with("Could not open input file"));
+        for path in paths {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
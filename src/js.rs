@@ -1,21 +1,41 @@
 use quick_js;
 use serde::{Serialize, Deserialize};
 use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, BTreeMap};
 use anyhow::{Result, Context as ErrorContext, anyhow};
+use super::json_line::from_json;
+use super::line_transform::{LineTransform, transform_line};
+use super::hll::HyperLogLog;
+use super::error_output::ErrorOutput;
+
+///How many times a pending promise is polled before giving up, every poll runs the jobs that were queued before it
+const MAX_PROMISE_ROUNDS: usize = 10000;
 
 thread_local! {
     static THREAD_CONTEXT: RefCell<Option<Context>> = RefCell::new(None);
 }
 
 pub struct ContextBuilder {
-    user_code: String
+    ///the user code prefixed with the `env` object
+    user_code: String,
+    ///where emitError() writes the rejected records
+    error_output: ErrorOutput
 }
 
 impl ContextBuilder {
-    ///Creates a new builder that will create contexts preloaded with user_code
-    pub fn new(user_code: &str) -> ContextBuilder {
+    ///Creates a new builder that will create contexts preloaded with user_code and an `env` object of the env variables
+    pub fn new(user_code: &str, env: &BTreeMap<String, String>, error_output: ErrorOutput) -> ContextBuilder {
+        //a json string is also a valid js string, kept on the first line so error line numbers match the js file.
+        //The binding is configurable and skipped if the script declares its own env so existing scripts still evaluate, only the object is frozen
+        let env = serde_json::to_string(&serde_json::json!(env).to_string()).unwrap();
         ContextBuilder {
-            user_code: String::from(user_code)
+            user_code: format!(
+                "if (!('env' in globalThis)) Object.defineProperty(globalThis, 'env', {{value: Object.freeze(JSON.parse({})), writable: true, configurable: true}});{}",
+                env,
+                user_code
+            ),
+            error_output
         }
     }
 
@@ -23,6 +43,18 @@ impl ContextBuilder {
     pub fn build(&self) -> Result<Context> {
         let context = quick_js::Context::new().context("Could not create js context")?;
         ContextBuilder::add_runtime_to_context(&context)?;
+        //emitError() of every context writes to the same output
+        let error_output = self.error_output.clone();
+        context.add_callback("writeErrorRecord", move |args: quick_js::Arguments| -> Result<quick_js::JsValue, String> {
+            let args = args.into_vec();
+            match (args.first(), args.get(1)) {
+                (Some(quick_js::JsValue::String(reason)), Some(quick_js::JsValue::String(record))) => {
+                    error_output.write(reason, record).map_err(|err| format!("{:#}", err))?;
+                    Ok(quick_js::JsValue::Null)
+                },
+                _ => Err(String::from("emitError() expects a reason and a record"))
+            }
+        }).context("Could not create js context runtime")?;
         context.eval(&self.user_code).context("Could not evaluate js file")?;
 
         Ok(Context {
@@ -46,6 +78,20 @@ impl ContextBuilder {
     fn add_runtime_to_context(context: &quick_js::Context) -> Result<()> {
         context.eval("
             let emited = [];
+            const surrogates = /[\\uD800-\\uDFFF]/;
+            const loneSurrogates = /[\\uD800-\\uDBFF](?![\\uDC00-\\uDFFF])|(?<![\\uD800-\\uDBFF])[\\uDC00-\\uDFFF]/g;
+            //a key with lone surrogates has no UTF-8 form, it is replaced and flagged so it can be rejected
+            function emitted(key, value) {
+                //most keys have no surrogates at all so the replace is skipped for them
+                if (!surrogates.test(key)) {
+                    return {key: key, value: value};
+                }
+                const wellFormed = key.replace(loneSurrogates, '\\uFFFD');
+                if (wellFormed !== key) {
+                    return {key: wellFormed, value: value, invalidKey: true};
+                }
+                return {key: key, value: value};
+            }
             function emit(key, value) {
                 if (typeof key !== 'string') {
                     key = JSON.stringify(key);
@@ -53,26 +99,202 @@ impl ContextBuilder {
                 if (typeof value !== 'string') {
                     value = JSON.stringify(value);
                 }
-                emited.push({key: key, value: value});
+                emited.push(emitted(key, value));
+            }
+            let kept = null;
+            let currentLine = null;
+            function keep() {
+                if (kept === null) {
+                    throw new Error('keep() can only be used with --passthrough');
+                }
+                kept.push(currentLine);
+            }
+            function emitMany(key, values) {
+                if (typeof key !== 'string') {
+                    key = JSON.stringify(key);
+                }
+                for (let i = 0; i < values.length; i++) {
+                    let value = values[i];
+                    if (typeof value !== 'string') {
+                        value = JSON.stringify(value);
+                    }
+                    emited.push(emitted(key, value));
+                }
+            }
+            function emitError(reason, record) {
+                if (typeof record !== 'string') {
+                    record = String(JSON.stringify(record));
+                }
+                writeErrorRecord(String(reason), record);
+            }
+            function get(obj, path) {
+                const segments = String(path)
+                    .replace(/\\[[\"']?([^\\]\"']*)[\"']?\\]/g, '.$1')
+                    .split('.')
+                    .filter(s => s.length > 0);
+                let current = obj;
+                for (let i = 0; i < segments.length; i++) {
+                    if (current === null || current === undefined) {
+                        return undefined;
+                    }
+                    current = current[segments[i]];
+                }
+                return current;
+            }
+            function isPromise(value) {
+                return value !== null && typeof value === 'object' && typeof value.then === 'function';
+            }
+            //the state of the promise returned by the last async call, rust polls it until it settles
+            let pendingResult = null;
+            function awaitResult(promise) {
+                const state = {settled: false, failed: false, value: null};
+                pendingResult = state;
+                promise.then(
+                    value => { state.settled = true; state.value = value; },
+                    err => { state.settled = true; state.failed = true; state.value = err; }
+                );
+                return null;
+            }
+            //returning a promise makes quick-js run the jobs queued before it, null while the result is still pending
+            function pollPending() {
+                return Promise.resolve().then(() => {
+                    const state = pendingResult;
+                    if (state === null || !state.settled) {
+                        return null;
+                    }
+                    pendingResult = null;
+                    if (state.failed) {
+                        throw state.value;
+                    }
+                    return state.value;
+                });
+            }
+            function toResultString(value) {
+                if (typeof value !== 'string') {
+                    return JSON.stringify(value);
+                } else {
+                    return value;
+                }
             }
-            function mapWrapper(first_line_number, lines) {
+            function mapWrapper(first_line_number, lines, offsets) {
                 first_line_number = parseInt(first_line_number);
-                for (let i = 0; i < lines.length; i++) {
-                    map(String(first_line_number), lines[i]);
-                    first_line_number += 1;
-
+                const pending = [];
+                try {
+                    for (let i = 0; i < lines.length; i++) {
+                        //with --offset-mode bytes the key is the byte offset of the line instead of its number
+                        const mapped = map(offsets.length > 0 ? offsets[i] : String(first_line_number), lines[i]);
+                        if (isPromise(mapped)) {
+                            pending.push(mapped);
+                        }
+                        first_line_number += 1;
+                    }
+                } catch (err) {
+                    //don't leak the values of a failed call to the next one
+                    emited = [];
+                    throw err;
+                }
+                const collect = () => {
+                    const result = JSON.stringify(emited);
+                    emited = [];
+                    return result;
+                };
+                if (pending.length > 0) {
+                    return awaitResult(Promise.all(pending).then(collect, err => { emited = []; throw err; }));
+                }
+                return collect();
+            }
+            function filterWrapper(first_line_number, lines) {
+                first_line_number = parseInt(first_line_number);
+                kept = [];
+                try {
+                    for (let i = 0; i < lines.length; i++) {
+                        currentLine = lines[i];
+                        const mapped = map(String(first_line_number), lines[i]);
+                        if (isPromise(mapped)) {
+                            throw new Error('map() can not be async with --passthrough');
+                        }
+                        first_line_number += 1;
+                    }
+                    return JSON.stringify(kept);
+                } finally {
+                    //values emitted by the shared map() are not used
+                    emited = [];
+                    kept = null;
+                    currentLine = null;
+                }
+            }
+            function finalizeWrapper(key, value) {
+                if (typeof finalize !== 'function') {
+                    return value;
+                }
+                const finalized = finalize(key, value);
+                if (isPromise(finalized)) {
+                    return awaitResult(finalized.then(toResultString));
+                }
+                return toResultString(finalized);
+            }
+            function selectReduce(key) {
+                if (typeof reduceByPrefix === 'object' && reduceByPrefix !== null) {
+                    let selectedPrefix = null;
+                    for (const prefix of Object.keys(reduceByPrefix)) {
+                        if (key.startsWith(prefix) && (selectedPrefix === null || prefix.length > selectedPrefix.length)) {
+                            selectedPrefix = prefix;
+                        }
+                    }
+                    if (selectedPrefix !== null) {
+                        return reduceByPrefix[selectedPrefix];
+                    }
+                }
+                if (typeof reduce === 'function') {
+                    return reduce;
                 }
-                const result = JSON.stringify(emited);
-                emited = [];
-                return result;
+                throw new Error('No reduce function matches key: ' + key);
             }
             function reduceWrapper(key, arrayAsString, rereduce) {
-                const reduced = reduce(key, JSON.parse(arrayAsString), JSON.parse(rereduce));
-                if (typeof reduced !== 'string') {
-                    return JSON.stringify(reduced);
-                } else {
-                    return reduced;
+                const values = JSON.parse(arrayAsString);
+                const reduced = selectReduce(key)(key, values, JSON.parse(rereduce), values.length);
+                if (isPromise(reduced)) {
+                    return awaitResult(reduced.then(toResultString));
+                }
+                return toResultString(reduced);
+            }
+            function finalReduceWrapper(arrayAsString) {
+                const reduced = finalReduce(JSON.parse(arrayAsString));
+                if (isPromise(reduced)) {
+                    return awaitResult(reduced.then(toResultString));
                 }
+                return toResultString(reduced);
+            }
+            function keyFilterWrapper(key) {
+                const selected = filter(key);
+                if (isPromise(selected)) {
+                    throw new Error('filter() can not be async');
+                }
+                return Boolean(selected);
+            }
+            function transformWrapper(value) {
+                const transformed = transform(value);
+                if (isPromise(transformed)) {
+                    return awaitResult(transformed.then(toResultString));
+                }
+                return toResultString(transformed);
+            }
+            function partitionWrapper(key) {
+                const partitioned = partition(key);
+                if (partitioned === undefined) {
+                    return null;
+                }
+                if (!Number.isInteger(partitioned)) {
+                    throw new Error('partition() must return an integer, got: ' + partitioned);
+                }
+                return partitioned;
+            }
+            function bucketWrapper(key, value) {
+                return String(bucket(key, value));
+            }
+            function compareWrapper(aKey, aValue, bKey, bValue) {
+                const compared = Number(compare(aKey, aValue, bKey, bValue));
+                return compared < 0 ? -1 : (compared > 0 ? 1 : 0);
             }
         ").context("Could not create js context runtime")?;
 
@@ -108,10 +330,191 @@ impl ContextBuilder {
                 }
             }
         }).context("Could not create js context runtime")?;
+
+        //a sumStrict() helper
+        //works like sum() but throws on values that are not integers (or their strings) and on overflow instead of counting them as 0
+        context.add_callback("sumStrict", |args: quick_js::Arguments| -> Result<i32, String> {
+            let args = args.into_vec();
+            let values = match args.first() {
+                Some(quick_js::JsValue::Array(arr)) => arr,
+                _ => &args
+            };
+            values.iter().try_fold(0i32, |total, value| {
+                total.checked_add(to_strict_int(value)?).ok_or_else(|| String::from("sumStrict() overflowed"))
+            })
+        }).context("Could not create js context runtime")?;
+
+        //a reduceTuples() helper
+        //sums arrays of numbers (or their json strings) element by element so reduceTuples(values) works the same for reduce and rereduce
+        context.add_callback("reduceTuples", |args: quick_js::Arguments| -> Result<quick_js::JsValue, String> {
+            let args = args.into_vec();
+            let tuples = match args.first() {
+                Some(quick_js::JsValue::Array(values)) => values.iter().map(to_tuple).collect::<Result<Vec<Vec<f64>>, String>>()?,
+                _ => return Err(String::from("reduceTuples() expects an array of tuples"))
+            };
+            let sums = sum_tuples(&tuples)?;
+            Ok(quick_js::JsValue::Array(sums.into_iter().map(quick_js::JsValue::Float).collect()))
+        }).context("Could not create js context runtime")?;
+
+        //a stats() helper
+        //derives the mean, variance and standard deviation (of the population) from a [count, sum, sumSq] tuple
+        context.add_callback("stats", |args: quick_js::Arguments| -> Result<quick_js::JsValue, String> {
+            let args = args.into_vec();
+            let tuple = match args.first() {
+                Some(value) => to_tuple(value)?,
+                None => return Err(String::from("stats() expects a [count, sum, sumSq] tuple"))
+            };
+            let (count, mean, variance) = tuple_stats(&tuple)?;
+            let mut stats = HashMap::new();
+            stats.insert(String::from("count"), quick_js::JsValue::Float(count));
+            stats.insert(String::from("mean"), quick_js::JsValue::Float(mean));
+            stats.insert(String::from("variance"), quick_js::JsValue::Float(variance));
+            stats.insert(String::from("stddev"), quick_js::JsValue::Float(variance.sqrt()));
+            Ok(quick_js::JsValue::Object(stats))
+        }).context("Could not create js context runtime")?;
+
+        //the hll(), hllMerge() and hllCount() helpers
+        //hll(values) builds a HyperLogLog sketch of the values, hllMerge(sketches) merges the sketches on rereduce and hllCount(sketch) estimates the distinct values
+        context.add_callback("hll", |args: quick_js::Arguments| -> Result<quick_js::JsValue, String> {
+            let args = args.into_vec();
+            let values = match args.first() {
+                Some(quick_js::JsValue::Array(values)) => values,
+                _ => return Err(String::from("hll() expects an array of values"))
+            };
+            let mut sketch = HyperLogLog::new();
+            for value in values {
+                sketch.insert(&to_hll_value(value)?);
+            }
+            Ok(quick_js::JsValue::String(sketch.to_base64()))
+        }).context("Could not create js context runtime")?;
+        context.add_callback("hllMerge", |args: quick_js::Arguments| -> Result<quick_js::JsValue, String> {
+            let args = args.into_vec();
+            let sketches = match args.first() {
+                Some(quick_js::JsValue::Array(sketches)) => sketches,
+                _ => return Err(String::from("hllMerge() expects an array of sketches"))
+            };
+            let mut merged = HyperLogLog::new();
+            for sketch in sketches {
+                merged.merge(&to_hll(sketch)?);
+            }
+            Ok(quick_js::JsValue::String(merged.to_base64()))
+        }).context("Could not create js context runtime")?;
+        context.add_callback("hllCount", |args: quick_js::Arguments| -> Result<quick_js::JsValue, String> {
+            let args = args.into_vec();
+            let sketch = match args.first() {
+                Some(sketch) => to_hll(sketch)?,
+                None => return Err(String::from("hllCount() expects a sketch"))
+            };
+            Ok(quick_js::JsValue::Float(sketch.count()))
+        }).context("Could not create js context runtime")?;
         Ok(())
     }
 }
 
+///Converts a value summed by sumStrict() to an integer, anything sum() would count as 0 is an error
+fn to_strict_int(value: &quick_js::JsValue) -> Result<i32, String> {
+    match value {
+        quick_js::JsValue::Int(n) => Ok(*n),
+        quick_js::JsValue::String(s) => s.parse::<i32>().map_err(|_| format!("sumStrict() got a non-numeric value: {}", s)),
+        _ => Err(String::from("sumStrict() can only sum integers and their strings"))
+    }
+}
+
+///Converts a js array of numbers or its json string to a tuple
+fn to_tuple(value: &quick_js::JsValue) -> Result<Vec<f64>, String> {
+    match value {
+        quick_js::JsValue::String(json) => serde_json::from_str(json).map_err(|_| format!("Invalid tuple: {}", json)),
+        quick_js::JsValue::Array(items) => items.iter().map(|item| {
+            match item {
+                quick_js::JsValue::Int(n) => Ok(f64::from(*n)),
+                quick_js::JsValue::Float(n) => Ok(*n),
+                quick_js::JsValue::String(s) => s.parse::<f64>().map_err(|_| format!("Invalid tuple number: {}", s)),
+                _ => Err(String::from("Tuples can only contain numbers"))
+            }
+        }).collect(),
+        _ => Err(String::from("A tuple must be an array of numbers"))
+    }
+}
+
+///Converts a js value to the string that is counted by hll(), numbers count the same as their strings
+fn to_hll_value(value: &quick_js::JsValue) -> Result<String, String> {
+    match value {
+        quick_js::JsValue::String(s) => Ok(s.clone()),
+        quick_js::JsValue::Int(n) => Ok(n.to_string()),
+        quick_js::JsValue::Float(n) => Ok(n.to_string()),
+        quick_js::JsValue::Bool(b) => Ok(b.to_string()),
+        _ => Err(String::from("hll() can only count strings, numbers and booleans"))
+    }
+}
+
+///Converts a sketch returned by hll() or hllMerge() back to a HyperLogLog
+fn to_hll(value: &quick_js::JsValue) -> Result<HyperLogLog, String> {
+    match value {
+        quick_js::JsValue::String(sketch) => HyperLogLog::from_base64(sketch),
+        _ => Err(String::from("An hll sketch must be a string"))
+    }
+}
+
+///Sums tuples of the same length element by element.  
+///Uses compensated (Neumaier) summation so large and small values can be mixed without losing precision
+fn sum_tuples(tuples: &[Vec<f64>]) -> Result<Vec<f64>, String> {
+    let length = match tuples.first() {
+        Some(first) => first.len(),
+        None => return Ok(vec![])
+    };
+    let mut sums = vec![0.0; length];
+    let mut compensations = vec![0.0; length];
+    for tuple in tuples {
+        if tuple.len() != length {
+            return Err(format!("Tuples must have the same length, found {} and {}", length, tuple.len()));
+        }
+        for (i, value) in tuple.iter().enumerate() {
+            let sum = sums[i] + value;
+            if sums[i].abs() >= value.abs() {
+                compensations[i] += (sums[i] - sum) + value;
+            } else {
+                compensations[i] += (value - sum) + sums[i];
+            }
+            sums[i] = sum;
+        }
+    }
+    Ok(sums.iter().zip(compensations).map(|(sum, compensation)| sum + compensation).collect())
+}
+
+///Returns the count, mean and population variance of a [count, sum, sumSq] tuple
+fn tuple_stats(tuple: &[f64]) -> Result<(f64, f64, f64), String> {
+    match tuple {
+        [count, sum, sum_sq] if *count > 0.0 => {
+            let mean = sum / count;
+            //rounding can make the variance of equal values slightly negative
+            let variance = ((sum_sq - sum * mean) / count).max(0.0);
+            Ok((*count, mean, variance))
+        },
+        [_, _, _] => Err(String::from("stats() needs a count greater than 0")),
+        _ => Err(String::from("stats() expects a [count, sum, sumSq] tuple"))
+    }
+}
+
+///Splits a buffer of the input to its lines.  
+///Empty lines are skipped unless keep_empty_lines is set
+fn split_lines(buf: &str, keep_empty_lines: bool) -> Vec<&str> {
+    if keep_empty_lines {
+        let buf = if buf.ends_with('\n') { &buf[..buf.len() - 1] } else { buf };
+        buf.split('\n').collect()
+    } else {
+        buf.split("\n").filter(|l| !l.is_empty()).collect()
+    }
+}
+
+///Where the lines of a map task are in the input, map() gets the line number or the byte offset of each line as its key
+#[derive(Clone, Copy)]
+pub enum ChunkPosition {
+    ///the line number of the last line of the chunk
+    LastLine(usize),
+    ///the byte offset of the start of the chunk
+    FirstByte(usize)
+}
+
 pub struct Context {
     js_context: quick_js::Context
 }
@@ -121,11 +524,33 @@ pub struct Context {
 pub struct MapResult {
     pub key: String,
     pub value: String,
+    ///the emitted key had lone surrogates, they are replaced in key
+    #[serde(default, rename = "invalidKey")]
+    pub invalid_key: bool
 }
 
 impl Context {
-    ///Checks if the context has map() and reduce() functions defined
-    pub fn validate(&self) -> Result<()> {
+    ///Calls a wrapper that may return a pending promise and waits for its result.  
+    ///The job queue is drained from here until the promise settles, a promise that waits for anything else never settles
+    ///so it fails after MAX_PROMISE_ROUNDS polls
+    fn call_async(&self, function_name: &str, args: impl IntoIterator<Item = impl Into<quick_js::JsValue>>) -> Result<quick_js::JsValue> {
+        let value = self.js_context.call_function(function_name, args)?;
+        if !matches!(value, quick_js::JsValue::Null) || !self.js_context.eval_as::<bool>("pendingResult !== null")? {
+            return Ok(value);
+        }
+        for _ in 0..MAX_PROMISE_ROUNDS {
+            let polled = self.js_context.call_function("pollPending", Vec::<&str>::new())?;
+            if !matches!(polled, quick_js::JsValue::Null) {
+                return Ok(polled);
+            }
+        }
+        self.js_context.eval("pendingResult = null")?;
+        Err(anyhow!("Promise did not resolve, only promises of already available values are supported"))
+    }
+
+    ///Checks if the context has map() and reduce() functions (or a reduceByPrefix object) defined.  
+    ///reduce() is only checked if require_reduce is set
+    pub fn validate(&self, require_reduce: bool) -> Result<()> {
         let has_map = self.js_context.eval_as::<bool>("
             (function() {
                 if (typeof map !== 'function') {
@@ -140,46 +565,215 @@ impl Context {
         }
         let has_reduce = self.js_context.eval_as::<bool>("
             (function() {
-                if (typeof reduce !== 'function') {
+                if (typeof reduce !== 'function' && typeof reduceByPrefix === 'undefined') {
                     return false;
                 } else {
                     return true;
                 }
             })()
         ").context("Could not validate js context")?;
-        if !has_reduce {
-            return Err(anyhow!("No reduce() function defined in the js file"));
+        if !has_reduce && require_reduce {
+            return Err(anyhow!("No reduce() function or reduceByPrefix defined in the js file"));
+        }
+        let has_valid_prefixes = self.js_context.eval_as::<bool>("
+            (function() {
+                if (typeof reduceByPrefix === 'undefined') {
+                    return true;
+                }
+                if (typeof reduceByPrefix !== 'object' || reduceByPrefix === null) {
+                    return false;
+                }
+                return Object.keys(reduceByPrefix).every(prefix => typeof reduceByPrefix[prefix] === 'function');
+            })()
+        ").context("Could not validate js context")?;
+        if !has_valid_prefixes {
+            return Err(anyhow!("reduceByPrefix must be an object of prefix -> reduce function"));
         }
 
         Ok(())
     }
 
-    ///Runs the map task for this buffer and return the results
-    pub fn run_map(&self, line_number: usize, buf: &str) -> Result<Vec<MapResult>> {
-        let lines: Vec<&str> = buf.split("\n").filter(|l| !l.is_empty()).collect();
-        let first_line_number = format!("{}", line_number - lines.len() + 1);
+    ///Runs the map task for this buffer and return the results.  
+    ///Empty lines are skipped unless keep_empty_lines is set, the rest are transformed before map() sees them
+    pub fn run_map(&self, position: ChunkPosition, buf: &str, keep_empty_lines: bool, transforms: &[LineTransform]) -> Result<Vec<MapResult>> {
+        let lines = split_lines(buf, keep_empty_lines);
+        //the offsets are taken before the transforms change the lines
+        let offsets: Vec<String> = match position {
+            ChunkPosition::LastLine(_) => vec![],
+            ChunkPosition::FirstByte(first_byte) => lines.iter().map(|line| (first_byte + (line.as_ptr() as usize - buf.as_ptr() as usize)).to_string()).collect()
+        };
+        let transformed: Vec<String>;
+        let lines = if transforms.is_empty() {
+            lines
+        } else {
+            transformed = lines.iter().map(|line| transform_line(transforms, line)).collect();
+            transformed.iter().map(String::as_str).collect()
+        };
+        let first_line_number = match position {
+            ChunkPosition::LastLine(line_number) => format!("{}", line_number - lines.len() + 1),
+            ChunkPosition::FirstByte(_) => String::from("0")
+        };
         let first_line_number = vec![&first_line_number[..]];
-        match self.js_context.call_function(
+        let offsets: Vec<&str> = offsets.iter().map(String::as_str).collect();
+        match self.call_async(
             "mapWrapper",
-            vec![first_line_number, lines]
-        ).context("An error was throwed in map()")?.as_str() {
-            Some(js_result) => {
-                let v: Vec<MapResult> = serde_json::from_str(js_result).with_context(|| format!("Could parse map() result: {}", js_result))?;
+            vec![first_line_number, lines, offsets]
+        ).context("An error was throwed in map()")? {
+            quick_js::JsValue::String(js_result) => {
+                let v: Vec<MapResult> = serde_json::from_str(&js_result).with_context(|| format!("Could parse map() result: {}", js_result))?;
                 Ok(v)
             },
-            None => Ok(vec![])
+            other => Err(anyhow!("Unexpected map() result: {:?}", other))
+        }
+    }
+
+    ///Runs map() for each line of this buffer and returns the lines it called keep() for, emitted values are discarded
+    pub fn run_filter(&self, line_number: usize, buf: &str, keep_empty_lines: bool) -> Result<Vec<String>> {
+        let lines = split_lines(buf, keep_empty_lines);
+        let first_line_number = format!("{}", line_number - lines.len() + 1);
+        let first_line_number = vec![&first_line_number[..]];
+        match self.js_context.call_function(
+            "filterWrapper",
+            vec![first_line_number, lines]
+        ).context("An error was throwed in map()")? {
+            quick_js::JsValue::String(js_result) => from_json(&js_result),
+            other => Err(anyhow!("Unexpected map() result: {:?}", other))
         }
     }
 
     ///Runs reduce for key and return the results
     pub fn run_reduce(&self, key: &str, values: &Vec<String>, rereduce: bool) -> Result<String> {
         let js_value = serde_json::to_string(values)?;
-        let reduce_result = self.js_context
-            .call_function("reduceWrapper", vec![key, &js_value, &rereduce.to_string()])
+        self.run_reduce_json(key, &js_value, rereduce)
+    }
+
+    ///Runs reduce for key with the values already serialized as a json array
+    pub fn run_reduce_json(&self, key: &str, values: &str, rereduce: bool) -> Result<String> {
+        let reduce_result = self
+            .call_async("reduceWrapper", vec![key, values, &rereduce.to_string()])
             .context("An error was throwed in reduce()")?;
         match reduce_result.into_string() {
             Some(result) => Ok(result),
             None => Ok(String::from(""))
         }
     }
+
+    ///Checks if the optional finalReduce() function is defined
+    pub fn has_final_reduce(&self) -> Result<bool> {
+        self.js_context
+            .eval_as::<bool>("typeof finalReduce === 'function'")
+            .context("Could not validate js context")
+    }
+
+    ///Runs finalReduce() over the final results of all the keys and returns the result
+    pub fn run_final_reduce(&self, results: &[String]) -> Result<String> {
+        let js_value = serde_json::to_string(results)?;
+        let final_result = self
+            .call_async("finalReduceWrapper", vec![js_value])
+            .context("An error was throwed in finalReduce()")?;
+        match final_result.into_string() {
+            Some(result) => Ok(result),
+            None => Ok(String::from(""))
+        }
+    }
+
+    ///Checks if the optional filter() function is defined
+    pub fn has_key_filter(&self) -> Result<bool> {
+        self.js_context
+            .eval_as::<bool>("typeof filter === 'function'")
+            .context("Could not validate js context")
+    }
+
+    ///Runs filter() for key and returns if the key should be reduced
+    pub fn run_key_filter(&self, key: &str) -> Result<bool> {
+        let selected = self.js_context
+            .call_function("keyFilterWrapper", vec![key])
+            .context("An error was throwed in filter()")?;
+        Ok(matches!(selected, quick_js::JsValue::Bool(true)))
+    }
+
+    ///Checks if the optional transform() function is defined
+    pub fn has_transform(&self) -> Result<bool> {
+        self.js_context
+            .eval_as::<bool>("typeof transform === 'function'")
+            .context("Could not validate js context")
+    }
+
+    ///Runs transform() for a result right before it is printed
+    pub fn run_transform(&self, value: &str) -> Result<String> {
+        let transformed = self
+            .call_async("transformWrapper", vec![value])
+            .context("An error was throwed in transform()")?;
+        match transformed.into_string() {
+            Some(result) => Ok(result),
+            None => Ok(String::from(""))
+        }
+    }
+
+    ///Checks if the optional partition() function is defined
+    pub fn has_partition(&self) -> Result<bool> {
+        self.js_context
+            .eval_as::<bool>("typeof partition === 'function'")
+            .context("Could not validate js context")
+    }
+
+    ///Runs partition() for a key and returns its partition, None if it returned undefined
+    pub fn run_partition(&self, key: &str) -> Result<Option<i64>> {
+        let partition = self.js_context
+            .call_function("partitionWrapper", vec![key])
+            .context("An error was throwed in partition()")?;
+        match partition {
+            quick_js::JsValue::Int(n) => Ok(Some(n as i64)),
+            //integers out of the i32 range
+            quick_js::JsValue::Float(n) => Ok(Some(n as i64)),
+            _ => Ok(None)
+        }
+    }
+
+    ///Checks if the optional bucket() function is defined
+    pub fn has_bucket(&self) -> Result<bool> {
+        self.js_context
+            .eval_as::<bool>("typeof bucket === 'function'")
+            .context("Could not validate js context")
+    }
+
+    ///Runs bucket() for a key and its result and returns the name of its bucket
+    pub fn run_bucket(&self, key: &str, value: &str) -> Result<String> {
+        let bucket = self.js_context
+            .call_function("bucketWrapper", vec![key, value])
+            .context("An error was throwed in bucket()")?;
+        match bucket.into_string() {
+            Some(bucket) => Ok(bucket),
+            None => Ok(String::from(""))
+        }
+    }
+
+    ///Checks if the compare() function of --order custom is defined
+    pub fn has_compare(&self) -> Result<bool> {
+        self.js_context
+            .eval_as::<bool>("typeof compare === 'function'")
+            .context("Could not validate js context")
+    }
+
+    ///Runs compare() for two keys and their results
+    pub fn run_compare(&self, a: (&str, &str), b: (&str, &str)) -> Result<Ordering> {
+        let compared = self.js_context
+            .call_function("compareWrapper", vec![a.0, a.1, b.0, b.1])
+            .context("An error was throwed in compare()")?;
+        match compared {
+            quick_js::JsValue::Int(n) => Ok(n.cmp(&0)),
+            _ => Ok(Ordering::Equal)
+        }
+    }
+
+    ///Runs the optional finalize() for the final result of a key, returns the result as is if finalize() is not defined
+    pub fn run_finalize(&self, key: &str, value: &str) -> Result<String> {
+        let finalize_result = self
+            .call_async("finalizeWrapper", vec![key, value])
+            .context("An error was throwed in finalize()")?;
+        match finalize_result.into_string() {
+            Some(result) => Ok(result),
+            None => Ok(String::from(""))
+        }
+    }
 }
@@ -0,0 +1,91 @@
+use anyhow::{Result, Context, anyhow};
+
+///A cheap transform of an input line before it is passed to map()
+pub enum LineTransform {
+    Trim,
+    Lowercase,
+    ///every run of spaces and tabs becomes a single space
+    CollapseWhitespace,
+    ///the 1-based field of the line split by the delimiter, an empty string if the line has fewer fields
+    Field(usize, String)
+}
+
+impl LineTransform {
+    ///Parses a transform from "trim", "lowercase", "collapse-whitespace", "field:N" (tab delimited) or "field:N:DELIMITER"
+    pub fn new(spec: &str) -> Result<LineTransform> {
+        match spec {
+            "trim" => Ok(LineTransform::Trim),
+            "lowercase" => Ok(LineTransform::Lowercase),
+            "collapse-whitespace" => Ok(LineTransform::CollapseWhitespace),
+            _ if spec.starts_with("field:") => {
+                let mut parts = spec["field:".len()..].splitn(2, ':');
+                let field = parts.next().unwrap().parse::<usize>().with_context(|| format!("Invalid pre-map transform field: {}", spec))?;
+                if field == 0 { return Err(anyhow!("Invalid pre-map transform field: {}", spec)) };
+                let delimiter = match parts.next() {
+                    Some("") => return Err(anyhow!("Invalid pre-map transform delimiter: {}", spec)),
+                    Some(delimiter) => delimiter.to_owned(),
+                    None => String::from("\t")
+                };
+                Ok(LineTransform::Field(field, delimiter))
+            },
+            _ => Err(anyhow!("Invalid pre-map transform: {}", spec))
+        }
+    }
+
+    fn apply(&self, line: &str) -> String {
+        match self {
+            LineTransform::Trim => line.trim().to_owned(),
+            LineTransform::Lowercase => line.to_lowercase(),
+            LineTransform::CollapseWhitespace => collapse_whitespace(line),
+            LineTransform::Field(field, delimiter) => line.split(delimiter.as_str()).nth(field - 1).unwrap_or_default().to_owned()
+        }
+    }
+}
+
+fn collapse_whitespace(line: &str) -> String {
+    let mut collapsed = String::with_capacity(line.len());
+    let mut in_whitespace = false;
+    for c in line.chars() {
+        if c == ' ' || c == '\t' {
+            if !in_whitespace {
+                collapsed.push(' ');
+            }
+            in_whitespace = true;
+        } else {
+            collapsed.push(c);
+            in_whitespace = false;
+        }
+    }
+    collapsed
+}
+
+///Applies the transforms to a line in order
+pub fn transform_line(transforms: &[LineTransform], line: &str) -> String {
+    let mut line = line.to_owned();
+    for transform in transforms {
+        line = transform.apply(&line);
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform_with(specs: &[&str], line: &str) -> String {
+        let transforms: Vec<LineTransform> = specs.iter().map(|spec| LineTransform::new(spec).unwrap()).collect();
+        transform_line(&transforms, line)
+    }
+
+    #[test]
+    fn test_transform_line() {
+        assert_eq!(transform_with(&["trim", "lowercase"], "  Hello World "), "hello world");
+        assert_eq!(transform_with(&["field:2"], "a\tB\tc"), "B");
+        assert_eq!(transform_with(&["field:3:,", "trim"], "a, b, c "), "c");
+        assert_eq!(transform_with(&["field:4:,"], "a,b"), "");
+        assert_eq!(transform_with(&["collapse-whitespace"], " a  \t b\t\tc "), " a b c ");
+        assert_eq!(transform_with(&["collapse-whitespace", "field:2: "], "1.2.3.4   -  GET"), "-");
+        assert!(LineTransform::new("field:0").is_err());
+        assert!(LineTransform::new("upper").is_err());
+    }
+}
@@ -1,106 +1,664 @@
-use std::io::{stdin, stdout};
-use std::path::PathBuf;
-use std::fs::{create_dir_all, remove_dir_all};
-use std::sync::Arc;
+use std::io::{stdin, stdout, BufRead, BufReader, BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions, create_dir_all, remove_dir_all, remove_file};
+use std::time::{Instant, Duration};
+use std::sync::{Arc, Barrier, mpsc::Receiver};
+use std::process;
+use std::cmp;
 use uuid::Uuid;
-use anyhow::{Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use anyhow::{Context, Result, anyhow};
 
 mod thread_pool;
-use thread_pool::ThreadPool;
+use thread_pool::{ThreadPool, Builder as ThreadPoolBuilder};
 mod combiner;
+use combiner::{KeyGrouping, GroupKeyMode, KeyCheck, InvalidKeyPolicy};
 mod js;
 mod map_container;
 mod json_line;
 mod index;
+use index::{Index, IndexOptions};
 mod cli;
 use cli::CLIOptions;
 mod indexer;
-use indexer::spawn_indexer;
+use indexer::{spawn_indexer, spawn_grouper, Indexer, IndexerOptions, Grouped, IndexCycle};
 mod mapper;
-use mapper::map;
+use mapper::{map, filter, dry_parse, ChunkSize, MapOptions, FilterOptions, InputFormat, OffsetMode, EmptyInput};
 mod reducer;
-use reducer::spawn_reducer;
+use reducer::{spawn_reducer, ReduceOptions, ReduceErrorPolicy, ReducePasses, SingleValueReduce, TopK, is_rereduce_safe};
 mod consumer;
-use consumer::spawn_consumer;
+use consumer::{spawn_consumer, spawn_memory_consumer, spawn_reduced_consumer, ConsumerOptions, MemoryConsumerOptions, PartReadOrder, MissingPartPolicy};
 mod printer;
-use printer::print;
+use printer::{ResultFormat, OutputTerminator, print, print_with_values, print_entries, print_buckets, Buckets, print_final_result, print_keys, print_memory_keys, entry_result};
 mod result_table;
+use result_table::{ResultTable, ResultsOrdering, ResultEncoding, ResultColumnFamily, EmptyOutput};
+mod semaphore;
+use semaphore::Semaphore;
+mod retry;
+mod metrics;
+use metrics::Metrics;
+mod schema;
+use schema::infer_schema;
+mod empty_trace;
+use empty_trace::EmptyTrace;
+mod line_transform;
+use line_transform::LineTransform;
+mod hll;
+mod sorted_merge;
+mod malloc_stats;
+use malloc_stats::MallocStats;
+mod error_output;
+mod progress;
+use progress::Progress;
+mod provenance;
+use provenance::Provenance;
+mod sorted_stream;
+use sorted_stream::SortedStream;
+mod generator;
+use generator::GeneratedInput;
+mod reduce_memory;
+mod streaming_reduce;
+mod input_files;
+use input_files::InputFiles;
+mod block_reader;
+use block_reader::BlockReader;
+mod parquet_output;
+use parquet_output::write_parquet;
+use streaming_reduce::spawn_streaming_reducer;
+mod run_outputs;
+use run_outputs::RunOutputs;
 
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
+///Exit code used when --abort-on-empty-input finds no input
+const EMPTY_INPUT_EXIT_CODE: i32 = 3;
+///Exit code used when --require-output finds no results
+const EMPTY_OUTPUT_EXIT_CODE: i32 = 4;
+
 fn main() -> Result<()> {
     //get CLI options
     let options = CLIOptions::new()?;
 
-    let context_builder = js::ContextBuilder::new(&options.user_code);
+    if options.malloc_background_threads {
+        malloc_stats::enable_background_threads()?;
+    }
+    let malloc_stats = options.malloc_stats.map(|seconds| MallocStats::spawn(Duration::from_secs(seconds)));
+
+    //the input is read in blocks of --stdin-block-size and the lines are split from them instead of the default buffered reader
+    let mut reader: Box<dyn BufRead> = match (&options.generate_input, options.stdin_block_size) {
+        (Some(spec), _) => Box::new(GeneratedInput::new(spec.clone())),
+        //the input files are read as a single stream so the line numbers continue across them
+        (None, Some(block_size)) if !options.input_files.is_empty() => Box::new(BlockReader::new(InputFiles::new(options.input_files.clone()), block_size)),
+        (None, None) if !options.input_files.is_empty() => Box::new(InputFiles::new(options.input_files.clone())),
+        (None, Some(block_size)) => Box::new(BlockReader::new(stdin(), block_size)),
+        (None, None) => Box::new(BufReader::new(stdin()))
+    };
+    let mut writer = open_output(options.output.as_ref())?;
+    let result = if options.output_gzip {
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        run(options, &mut reader, &mut encoder).and_then(|_| {
+            //finish writes the gzip trailer, without it the stream is truncated
+            encoder.finish().context("Could not finish gzip output")?.flush()?;
+            Ok(())
+        })
+    } else {
+        //a buffered file only reports its write errors on flush
+        run(options, &mut reader, &mut writer).and_then(|_| writer.flush().context("Could not write output"))
+    };
+    if let Some(malloc_stats) = malloc_stats {
+        malloc_stats.stop();
+    }
+    if let Err(err) = &result {
+        if err.downcast_ref::<EmptyInput>().is_some() {
+            eprintln!("Error: {:?}", err);
+            process::exit(EMPTY_INPUT_EXIT_CODE);
+        }
+        if err.downcast_ref::<EmptyOutput>().is_some() {
+            eprintln!("Error: {:?}", err);
+            process::exit(EMPTY_OUTPUT_EXIT_CODE);
+        }
+    }
+    result
+}
+
+///Opens where the results are written, stdout unless --output is set, an existing file is truncated
+fn open_output(path: Option<&PathBuf>) -> Result<Box<dyn Write>> {
+    match path {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .with_context(|| format!("Could not open output file: {}", path.display()))?;
+            Ok(Box::new(BufWriter::new(file)))
+        },
+        None => Ok(Box::new(stdout()))
+    }
+}
+
+///Runs the whole map/reduce pipeline reading the input from reader and writing the results to writer
+fn run<R: BufRead, W: Write>(options: CLIOptions, reader: &mut R, writer: &mut W) -> Result<()> {
+    let outputs = RunOutputs::open(&options)?;
+    if options.chain.is_empty() {
+        run_stage(options, &outputs, reader, writer)?;
+    } else {
+        run_chain(options, &outputs, reader, writer)?;
+    }
+    outputs.finish()
+}
+
+///Runs FILE and then every --chain stage, the output of a stage is kept in a file and read back as the input lines of the next.  
+///All the stages keep their temp data under a single temp dir that is removed at the end
+fn run_chain<R: BufRead, W: Write>(mut options: CLIOptions, outputs: &RunOutputs, reader: &mut R, writer: &mut W) -> Result<()> {
+    let mut stages = vec![mem::take(&mut options.user_code)];
+    stages.append(&mut options.chain);
+    let dir = create_temp_dir(options.temp_dir.clone(), &options.temp_prefix)?;
+    options.temp_dir = dir.clone();
+    let result = run_stages(options, stages, &dir, outputs, reader, writer);
+    remove_temp_dir(dir)?;
+    result
+}
+
+fn run_stages<R: BufRead, W: Write>(options: CLIOptions, stages: Vec<String>, dir: &Path, outputs: &RunOutputs, reader: &mut R, writer: &mut W) -> Result<()> {
+    let total_stages = stages.len();
+    let mut previous_output: Option<PathBuf> = None;
+    for (position, user_code) in stages.into_iter().enumerate() {
+        let stage = position + 1;
+        let mut stage_options = options.clone();
+        stage_options.user_code = user_code;
+        let mut input: Box<dyn BufRead> = match &previous_output {
+            Some(path) => {
+                //the input format and transforms only apply to the input of the first stage
+                stage_options.input_format = String::from("lines");
+                stage_options.pre_map_transforms = vec![];
+                stage_options.lib_files = vec![];
+                Box::new(BufReader::new(File::open(path).context("Could not open chain stage output")?))
+            },
+            None => Box::new(&mut *reader)
+        };
+        if stage == total_stages {
+            return run_stage(stage_options, outputs, &mut input, writer).with_context(|| format!("Chain stage {} failed", stage));
+        }
+        //the next stage reads the output back as lines
+        stage_options.output_terminator = String::from("newline");
+        stage_options.output_format = String::from("lines");
+        let path = dir.join(format!("stage-{}", stage));
+        let mut output = BufWriter::new(File::create(&path).context("Could not create chain stage output")?);
+        run_stage(stage_options, outputs, &mut input, &mut output).with_context(|| format!("Chain stage {} failed", stage))?;
+        output.flush().context("Could not write chain stage output")?;
+        drop(input);
+        //only the output of the previous stage is kept on disk
+        if let Some(previous_path) = previous_output.replace(path) {
+            remove_file(previous_path).context("Could not remove chain stage output")?;
+        }
+    }
+    Ok(())
+}
+
+///Runs a single map/reduce pass
+fn run_stage<R: BufRead, W: Write>(options: CLIOptions, outputs: &RunOutputs, reader: &mut R, writer: &mut W) -> Result<()> {
+    for lib_file in &options.lib_files {
+        eprintln!("Loaded library file: {}", lib_file.display());
+    }
+
+    let context_builder = js::ContextBuilder::new(&options.user_code, &options.env, outputs.error_output.clone());
     let context_builder = Arc::new(context_builder);
 
     //create a js context for testing
     //so we can fail quickly if the js file has any errors before doing any more work
     {
         let context = context_builder.build()?;
-        context.validate()?;
+        context.validate(!options.passthrough)?;
+        if options.order == "custom" && !context.has_compare()? {
+            return Err(anyhow!("No compare() function defined in the js file for --order custom"));
+        }
+        if options.stream_sorted && context.has_bucket()? {
+            return Err(anyhow!("--stream-sorted can not be used with a bucket() function"));
+        }
     }
 
-    let dir = create_temp_dir(options.temp_dir)?;
-    let pool = ThreadPool::new(options.workers);
+    let line_transforms = options.pre_map_transforms.iter()
+        .map(|spec| LineTransform::new(spec))
+        .collect::<Result<Vec<LineTransform>>>()?;
 
-    //spawn the indexer
-    //get back a channel sender for mapper->indexer and the index
-    let (indexer, sender, index, index_guard) = spawn_indexer(
-        &dir,
-        pool.clone(), 
-        options.workers, 
-        options.key_flush_size,
-        options.max_file_part_size,
-        options.index_every
-    )?;
+    let input_format = InputFormat::new(&options.input_format);
+    let offset_mode = OffsetMode::new(&options.offset_mode);
+
+    //only check that map() does not throw on the first lines, nothing is indexed or reduced
+    if let Some(max_lines) = options.dry_parse {
+        let context = context_builder.build()?;
+        let (lines, failed) = dry_parse(reader, &context, max_lines, options.keep_empty_lines, &line_transforms, input_format, offset_mode)?;
+        if failed > 0 {
+            return Err(anyhow!("map() failed on {} of {} lines", failed, lines));
+        }
+        eprintln!("map() ran on {} lines without errors", lines);
+        return Ok(());
+    }
+
+    //a streamed line is dispatched on its own instead of waiting for the chunk to fill
+    let chunk_size = match options.lines_per_task {
+        _ if options.line_streaming => ChunkSize::Lines(1),
+        Some(lines_per_task) => ChunkSize::Lines(lines_per_task),
+        None => ChunkSize::Bytes(options.read_buffer_size)
+    };
+
+    //write the lines that map() keeps as they are, nothing is indexed or reduced
+    if options.passthrough {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(options.workers)
+            .thread_name(String::from("omnimap-worker"))
+            .number_thread_names()
+            .build();
+        let lines_read = filter(
+            reader,
+            writer,
+            pool.clone(),
+            context_builder,
+            FilterOptions {
+                chunk_size,
+                keep_empty_lines: options.keep_empty_lines,
+                max_inflight: options.workers * 2,
+                terminator: OutputTerminator::new(&options.output_terminator)
+            }
+        )?;
+        pool.join();
+        if lines_read == 0 && options.abort_on_empty_input {
+            return Err(EmptyInput.into());
+        }
+        return Ok(());
+    }
+
+    let progress = match options.progress_format {
+        Some(_) => Some(Progress::new(options.progress_fd)?),
+        None => None
+    };
+    //keys are assigned to the indexer partitions by partition() if it is defined
+    let partition_fn = if context_builder.build()?.has_partition()? {
+        Some(context_builder.clone())
+    } else {
+        None
+    };
+    let grouping = key_grouping(&options, partition_fn.clone());
+    let dir = create_temp_dir(options.temp_dir, &options.temp_prefix)?;
+    //the index can live on a different disk than the file parts
+    let index_dir = match options.index_dir {
+        Some(index_root) => create_temp_dir(index_root, &options.temp_prefix)?,
+        None => dir.clone()
+    };
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(options.workers)
+        .thread_name(String::from("omnimap-worker"))
+        .number_thread_names()
+        .build();
+    if options.reduce_warmup {
+        warmup_contexts(&pool, &context_builder, options.workers);
+    }
+
+    //the indexer and the consumer share the open file parts limit
+    let part_slots = options.max_open_parts.map(Semaphore::new);
+
+    //spawn the indexer, the grouper that keeps everything in memory with --no-index, or the streaming reducer with --streaming-reduce
+    //get back a channel sender for mapper->indexer
+    let (indexer, sender, index_guard) = if options.streaming_reduce {
+        let (streaming_reducer, sender, index_guard) = spawn_streaming_reducer(context_builder.clone(), options.ordered, grouping)?;
+        (Indexer::Streaming(streaming_reducer), sender, index_guard)
+    } else if options.no_index {
+        let (grouper, sender, index_guard) = spawn_grouper(options.ordered, grouping, options.expect_unique_keys)?;
+        (Indexer::Memory(grouper), sender, index_guard)
+    } else {
+        let index = Arc::new(Index::new(
+            &index_dir,
+            &dir,
+            IndexOptions {
+                flush_size: options.key_flush_size,
+                max_part_size: options.max_file_part_size,
+                max_parts: options.max_parts_per_key,
+                io_retries: options.io_retries,
+                part_slots: part_slots.clone(),
+                single_file_parts: options.single_file_parts,
+                expect_unique_keys: options.expect_unique_keys,
+                sort_values: options.sort_values,
+                max_temp_bytes: options.max_temp_bytes,
+                ordered: options.ordered
+            }
+        )?);
+        let (indexer, sender, index_guard) = spawn_indexer(
+            index.clone(),
+            pool.clone(), 
+            options.workers, 
+            IndexerOptions {
+                index_cycle: match options.index_every_bytes {
+                    Some(index_bytes) => IndexCycle::Bytes(index_bytes),
+                    None => IndexCycle::Every(options.index_every)
+                },
+                ordered: options.ordered,
+                grouping,
+                dump_partitions: options.dump_partitions,
+                progress: progress.clone()
+            }
+        )?;
+        (Indexer::Index(indexer, index), sender, index_guard)
+    };
 
     //read and map
-    map(
-        &mut stdin().lock(),
+    let map_start = Instant::now();
+    report_stage(&progress, "map");
+    let map_empty_trace = if options.trace_empties { Some(EmptyTrace::new()) } else { None };
+    let mapped = map(
+        reader,
         pool.clone(),
         sender,
         index_guard,
         context_builder.clone(),
-        options.read_buffer_size
-    )?;
+        MapOptions {
+            chunk_size,
+            keep_empty_lines: options.keep_empty_lines,
+            empty_trace: map_empty_trace.clone(),
+            line_transforms: Arc::new(line_transforms),
+            input_format,
+            progress: progress.clone(),
+            offset_mode
+        }
+    );
 
     //wait for indexing to finish
-    indexer.join().unwrap();
+    //also on a map error, the sender is dropped by then so the indexer stops after the already scheduled map tasks
+    let grouped = indexer.join();
     pool.join();
+    let lines_read = match mapped {
+        Ok(lines_read) => lines_read,
+        Err(err) => {
+            remove_temp_dir(index_dir)?;
+            remove_temp_dir(dir)?;
+            return Err(err);
+        }
+    };
+    let grouped = match grouped {
+        Ok(grouped) => grouped,
+        Err(err) => {
+            remove_temp_dir(index_dir)?;
+            remove_temp_dir(dir)?;
+            return Err(err);
+        }
+    };
+    let map_duration = map_start.elapsed();
+    if let Some(map_empty_trace) = map_empty_trace {
+        map_empty_trace.report("map tasks");
+    }
+
+    if lines_read == 0 && options.abort_on_empty_input {
+        remove_temp_dir(index_dir)?;
+        remove_temp_dir(dir)?;
+        return Err(EmptyInput.into());
+    }
+
+    let terminator = OutputTerminator::new(&options.output_terminator);
+
+    //the distinct keys are already known after indexing, no need to reduce
+    if options.keys_only {
+        let output_start = Instant::now();
+        report_stage(&progress, "output");
+        match &grouped {
+            Grouped::Index(index) => print_keys(writer, index, &options.order, &terminator)?,
+            Grouped::Memory(groups) => print_memory_keys(writer, groups, &options.order, &terminator)?,
+            Grouped::Reduced(results) => print_memory_keys(writer, results, &options.order, &terminator)?
+        }
+        outputs.record_metrics(Metrics {
+            lines_read,
+            keys: grouped.total_keys(),
+            file_parts: grouped.total_parts(),
+            spilled_bytes: grouped.spilled_bytes(),
+            phases: vec![("map", map_duration), ("output", output_start.elapsed())]
+        });
+        report_stage(&progress, "done");
+        remove_temp_dir(index_dir)?;
+        remove_temp_dir(dir)?;
+        return Ok(());
+    }
+
+    if let (true, Grouped::Index(index)) = (options.compact_index, &grouped) {
+        index.compact();
+    }
+
+    //sorting needs all the values of a key at once
+    let reduce_passes = if options.collect_sorted.is_some() {
+        ReducePasses::Single
+    } else if options.streaming_reduce {
+        //already checked while reducing
+        ReducePasses::Multi
+    } else if options.reduce_passes == "auto" {
+        detect_reduce_passes(&grouped, &context_builder)?
+    } else {
+        ReducePasses::new(&options.reduce_passes)
+    };
 
     //spawn the reducer
+    let reduce_start = Instant::now();
+    report_stage(&progress, "reduce");
+    let top_k = options.top_k.map(TopK::new);
+    //the keys are combined in a single partition without the index
+    let provenance = if options.with_provenance {
+        Some(Provenance::new(if options.no_index { 1 } else { options.workers }, partition_fn))
+    } else {
+        None
+    };
+    //the results are saved as json objects with their values or provenance
+    let structured = options.with_values || options.with_provenance;
+    let (sorted_stream, sorted_receiver) = if options.stream_sorted {
+        let (sorted_stream, receiver) = SortedStream::new();
+        (Some(sorted_stream), Some(receiver))
+    } else {
+        (None, None)
+    };
     //get back a channel sender for consumer->reducer and the result_table
     let (reducer, sender, result_table) = spawn_reducer(
         pool.clone(),
         context_builder.clone(),
-        options.workers,
-        &dir
+        options.reduce_queue_size,
+        &dir,
+        ReduceOptions {
+            on_error: ReduceErrorPolicy::new(&options.on_reduce_error),
+            max_inflight: options.reduce_max_inflight,
+            passes: reduce_passes,
+            max_values_retained: if options.with_values { Some(options.max_values_retained) } else { None },
+            chunk_values: options.reduce_chunk_values,
+            partial_writer: outputs.partial_writer.clone(),
+            max_value_bytes: options.max_value_bytes,
+            result_encoding: ResultEncoding::new(&options.result_encoding),
+            top_k: top_k.clone(),
+            error_writer: outputs.error_writer.clone(),
+            collect_sorted: options.collect_sorted,
+            dedup_values: options.reduce_value_dedup,
+            single_value_reduce: SingleValueReduce::new(&options.single_value_reduce),
+            progress: progress.clone(),
+            provenance: provenance.clone(),
+            result_column_family: match (&options.result_db, &options.result_cf) {
+                (Some(db_path), Some(name)) => Some(ResultColumnFamily { db_path: db_path.clone(), name: name.clone(), replace: options.replace_result_cf }),
+                _ => None
+            },
+            sorted_stream: sorted_stream.clone(),
+            memory_report: options.reduce_memory_report.map(Duration::from_secs),
+            ordered: options.ordered
+        }
     )?;
 
+    //the counters are needed for the metrics after the groups are consumed
+    let (total_keys, total_parts, spilled_bytes) = (grouped.total_keys(), grouped.total_parts(), grouped.spilled_bytes());
+
+    //the consumer only needs a js context if filter() is defined
+    let key_filter = if context_builder.build()?.has_key_filter()? {
+        Some(context_builder.clone())
+    } else {
+        None
+    };
+
+    let key_empty_trace = if options.trace_empties { Some(EmptyTrace::new()) } else { None };
+
     //spawn the consumer of the index
-    let consumer = spawn_consumer(index, sender, options.key_flush_size);
+    let consumer = match grouped {
+        Grouped::Index(index) => spawn_consumer(
+            index,
+            sender,
+            ConsumerOptions {
+                flush_size: options.key_flush_size,
+                buffer_size: options.consumer_buffer_size,
+                debug_key: options.debug_key,
+                verify_parts: options.verify_parts,
+                coalesce_size: options.coalesce_keys,
+                part_read_order: PartReadOrder::new(&options.part_read_order),
+                part_slots,
+                key_filter,
+                empty_trace: key_empty_trace.clone(),
+                missing_parts: MissingPartPolicy::new(&options.on_missing_part),
+                sort_values: options.sort_values,
+                provenance: provenance.clone(),
+                sorted_stream
+            }
+        )?,
+        Grouped::Memory(groups) => spawn_memory_consumer(
+            groups,
+            sender,
+            MemoryConsumerOptions {
+                debug_key: options.debug_key,
+                coalesce_size: options.coalesce_keys,
+                key_filter,
+                sort_values: options.sort_values,
+                provenance: provenance.clone(),
+                sorted_stream
+            }
+        )?,
+        Grouped::Reduced(results) => spawn_reduced_consumer(results, sender, key_filter, sorted_stream)?
+    };
+
+    //the receiver ends once the consumer and the reducer are done with the stream
+    let streamed = match sorted_receiver {
+        Some(receiver) => print_sorted_stream(writer, receiver, &context_builder, structured, &options.null_value, options.float_precision, &terminator),
+        None => Ok(())
+    };
 
     //wait for everything to finish
-    consumer.join().unwrap()?;
-    reducer.join().unwrap();
+    //a reduce error stops the reducer early so it takes precedence over the consumer's closed channel error
+    let consumed = consumer.join().unwrap();
+    reducer.join().unwrap()?;
+    consumed?;
+    streamed?;
+    if let Some(key_empty_trace) = key_empty_trace {
+        key_empty_trace.report("keys");
+    }
     pool.join();
+    let final_result = final_reduce(&context_builder, &result_table, structured)?;
+    let reduce_duration = reduce_start.elapsed();
+
+    if options.require_output && result_table.is_empty() {
+        remove_temp_dir(index_dir)?;
+        remove_temp_dir(dir)?;
+        return Err(EmptyOutput.into());
+    }
 
     //write the reducer results
-    print(&mut stdout().lock(), &result_table, &options.order)?;
+    let output_start = Instant::now();
+    report_stage(&progress, "output");
+    if options.print_schema {
+        let schema = infer_schema(&result_table, structured)?;
+        eprintln!("{}", serde_json::to_string_pretty(&schema)?);
+    }
+    if !options.schema_only {
+        //transform() only changes the printed results
+        let print_context = context_builder.build()?;
+        let format = ResultFormat {
+            transform: if print_context.has_transform()? { Some(&print_context) } else { None },
+            float_precision: options.float_precision,
+            terminator: &terminator
+        };
+        let has_bucket = print_context.has_bucket()?;
+        //the results are buffered when they are reordered or grouped into buckets
+        let entries = if let Some(top_k) = top_k {
+            Some(top_k.into_sorted())
+        } else if options.order == "custom" {
+            Some(custom_order(&context_builder, &result_table, structured)?)
+        } else if has_bucket {
+            Some(result_table.iter(ResultsOrdering::new(&options.order)).collect::<Result<Vec<_>>>()?)
+        } else {
+            None
+        };
+        if options.output_format == "parquet" {
+            if has_bucket {
+                return Err(anyhow!("--output-format parquet can not be used with a bucket() function"));
+            }
+            let rows: Box<dyn Iterator<Item = Result<(String, String)>>> = match entries {
+                Some(entries) => Box::new(entries.into_iter().map(Ok)),
+                None => Box::new(result_table.iter(ResultsOrdering::new(&options.order)))
+            };
+            let final_row = final_result.map(|result| Ok((options.final_reduce_key.clone(), result)));
+            let rows = rows.chain(final_row).map(|row| {
+                let (key, result) = row?;
+                match format.transform {
+                    Some(context) => Ok((key, context.run_transform(&result)?)),
+                    None => Ok((key, result))
+                }
+            });
+            write_parquet(options.output.as_ref().unwrap(), &options.parquet_columns, rows)?;
+        } else {
+            match entries {
+                Some(entries) if has_bucket => {
+                    let buckets = group_buckets(&print_context, entries, structured)?;
+                    print_buckets(writer, buckets, structured, &options.null_value, &format)?;
+                },
+                Some(entries) => print_entries(writer, entries, structured, &options.null_value, &format)?,
+                //already printed while the keys were reduced
+                None if options.stream_sorted => {},
+                None if structured => print_with_values(writer, &result_table, &options.order, &format)?,
+                None => print(writer, &result_table, &options.order, &options.null_value, &format)?
+            }
+            if let Some(final_result) = &final_result {
+                print_final_result(writer, &options.final_reduce_key, final_result, structured, &options.null_value, &format)?;
+            }
+        }
+    }
+
+    outputs.record_metrics(Metrics {
+        lines_read,
+        keys: total_keys,
+        file_parts: total_parts,
+        spilled_bytes,
+        phases: vec![("map", map_duration), ("reduce", reduce_duration), ("output", output_start.elapsed())]
+    });
+
+    report_stage(&progress, "done");
 
     //clean up
+    remove_temp_dir(index_dir)?;
     remove_temp_dir(dir)?;
 
     Ok(())
 }
 
-fn create_temp_dir(root: PathBuf) -> Result<PathBuf> {
+fn report_stage(progress: &Option<Progress>, stage: &str) {
+    if let Some(progress) = progress {
+        progress.stage(stage);
+    }
+}
+
+fn key_grouping(options: &CLIOptions, partition_fn: Option<Arc<js::ContextBuilder>>) -> KeyGrouping {
+    KeyGrouping {
+        rollup_separator: options.rollup_separator.clone(),
+        mode: GroupKeyMode::new(&options.group_key_mode),
+        key_check: KeyCheck {
+            max_key_bytes: options.max_key_bytes,
+            on_invalid: InvalidKeyPolicy::new(&options.on_invalid_key)
+        },
+        partition_fn
+    }
+}
+
+///Creates a uniquely named temp directory as `<prefix>-<pid>-<uuid>` under root
+fn create_temp_dir(root: PathBuf, prefix: &str) -> Result<PathBuf> {
     let mut dir = root.clone();
     let uuid = Uuid::new_v4();
-    dir.push(format!("omnimap-{}", uuid));
+    dir.push(format!("{}-{}-{}", prefix, process::id(), uuid));
     create_dir_all(&dir).with_context(|| format!("Could not create temp directory: {}", dir.display()))?;
 
     Ok(dir)
@@ -111,4 +669,1389 @@ fn remove_temp_dir(dir: PathBuf) -> Result<()> {
         remove_dir_all(&dir).with_context(|| format!("Could not remove temp directory: {}", dir.display()))?;
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+///Builds the js context of every worker up front instead of on their first map or reduce task.  
+///Each task waits for all the others so every worker gets exactly one
+fn warmup_contexts(pool: &ThreadPool, context_builder: &Arc<js::ContextBuilder>, workers: usize) {
+    let barrier = Arc::new(Barrier::new(workers));
+    for _ in 0..workers {
+        let context_builder = context_builder.clone();
+        let barrier = barrier.clone();
+        pool.execute(move|| {
+            context_builder.reuse(|_| {});
+            barrier.wait();
+        });
+    }
+    pool.join();
+}
+
+///Prints the --stream-sorted results in key order as they are reduced, in the same format as the buffered output
+fn print_sorted_stream<W: Write>(
+    writer: &mut W,
+    receiver: Receiver<(String, String)>,
+    context_builder: &js::ContextBuilder,
+    structured: bool,
+    null_value: &str,
+    float_precision: Option<usize>,
+    terminator: &OutputTerminator
+) -> Result<()> {
+    let print_context = context_builder.build()?;
+    let format = ResultFormat {
+        transform: if print_context.has_transform()? { Some(&print_context) } else { None },
+        float_precision,
+        terminator
+    };
+    for entry in receiver.iter() {
+        print_entries(writer, vec![entry], structured, null_value, &format)?;
+    }
+    Ok(())
+}
+
+///Runs the optional finalReduce() over the results of all the keys
+fn final_reduce(context_builder: &js::ContextBuilder, result_table: &ResultTable, with_values: bool) -> Result<Option<String>> {
+    let context = context_builder.build()?;
+    if !context.has_final_reduce()? {
+        return Ok(None);
+    }
+    let mut results = vec![];
+    for pair in result_table.iter(ResultsOrdering::Asc) {
+        let (_, entry) = pair?;
+        results.push(entry_result(entry, with_values)?);
+    }
+    Ok(Some(context.run_final_reduce(&results)?))
+}
+
+///Sorts all the results with the js compare() of --order custom, the entries are buffered in memory
+fn custom_order(context_builder: &js::ContextBuilder, result_table: &ResultTable, with_values: bool) -> Result<Vec<(String, String)>> {
+    let context = context_builder.build()?;
+    let mut entries = vec![];
+    for pair in result_table.iter(ResultsOrdering::Asc) {
+        let (key, entry) = pair?;
+        let result = entry_result(entry.clone(), with_values)?;
+        entries.push((key, result, entry));
+    }
+    //compare() is user code so it may not be a total order, sort_by needs one
+    let entries = try_merge_sort(entries, &mut |a, b| context.run_compare((&a.0, &a.1), (&b.0, &b.1)))?;
+    Ok(entries.into_iter().map(|(key, _, entry)| (key, entry)).collect())
+}
+
+///A stable merge sort that stops at the first error of compare.  
+///Any compare is fine, an inconsistent one only gives an arbitrary order
+fn try_merge_sort<T, F: FnMut(&T, &T) -> Result<cmp::Ordering>>(mut items: Vec<T>, compare: &mut F) -> Result<Vec<T>> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let left = try_merge_sort(items, compare)?;
+    let right = try_merge_sort(right, compare)?;
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    loop {
+        let take_right = match (left.peek(), right.peek()) {
+            (Some(a), Some(b)) => compare(a, b)? == cmp::Ordering::Greater,
+            _ => break
+        };
+        merged.push(if take_right { right.next() } else { left.next() }.unwrap());
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+///Groups the entries by the bucket the js bucket() assigns to their result, the entries keep their order within a bucket
+fn group_buckets(context: &js::Context, entries: Vec<(String, String)>, with_values: bool) -> Result<Buckets> {
+    let mut buckets = Buckets::new();
+    for (key, entry) in entries {
+        let bucket = context.run_bucket(&key, &entry_result(entry.clone(), with_values)?)?;
+        buckets.entry(bucket).or_default().push((key, entry));
+    }
+    Ok(buckets)
+}
+
+///Picks the multi pass reduce if rereduce gives the same result as a single pass over a sample
+fn detect_reduce_passes(grouped: &Grouped, context_builder: &js::ContextBuilder) -> Result<ReducePasses> {
+    let passes = match grouped.sample(1000)? {
+        Some((key, values)) => {
+            let context = context_builder.build()?;
+            if is_rereduce_safe(&context, &key, &values)? {
+                ReducePasses::Multi
+            } else {
+                ReducePasses::Single
+            }
+        },
+        None => ReducePasses::Multi
+    };
+    match passes {
+        ReducePasses::Multi => eprintln!("reduce() is rereduce safe, using multiple passes"),
+        ReducePasses::Single => eprintln!("reduce() is not rereduce safe, using a single pass (all values of a key are kept in memory)")
+    }
+
+    Ok(passes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::env::temp_dir;
+    use std::io::Cursor;
+    use std::collections::BTreeMap;
+    use std::net::TcpListener;
+    use std::thread;
+    use super::cli::allowed_env;
+
+    ///Counts how many times each input line appears
+    const WORD_COUNT: &str = "
+        function map(key, value) {
+            emit(value, 1);
+        }
+        function reduce(key, values, rereduce) {
+            return sum(values);
+        }
+    ";
+
+    ///Runs the pipeline with the js code and input, returns the output
+    fn run_with(code: &str, input: &str, args: &[&str]) -> String {
+        try_run_with(code, input, args).unwrap()
+    }
+
+    fn try_run_with(code: &str, input: &str, args: &[&str]) -> Result<String> {
+        let code_file = temp_path("js");
+        fs::write(&code_file, code).unwrap();
+        let mut all_args = vec!["omnimap", code_file.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        let options = CLIOptions::from_args(all_args).unwrap();
+        let mut output = vec![];
+        let result = run(options, &mut Cursor::new(input), &mut output);
+        fs::remove_file(&code_file).unwrap();
+        result?;
+        Ok(String::from_utf8(output)?)
+    }
+
+    ///A new path in the temp dir with the ext extension, without one if ext is empty
+    fn temp_path(ext: &str) -> PathBuf {
+        if ext.is_empty() {
+            temp_dir().join(format!("omnimap-test-{}", Uuid::new_v4()))
+        } else {
+            temp_dir().join(format!("omnimap-test-{}.{}", Uuid::new_v4(), ext))
+        }
+    }
+
+    #[test]
+    fn test_ordered_values() {
+        let code = "
+            function map(key, value) {
+                emit('k', value);
+            }
+            function reduce(key, values, rereduce) {
+                return values.join(',');
+            }
+        ";
+        let values: Vec<String> = (1..=5000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        //tiny buffers so values are spread across many map tasks, index cycles and file parts
+        let output = run_with(code, &input, &[
+            "--ordered",
+            "--workers", "8",
+            "--read-buffer-size", "1",
+            "--key-flush-size", "1",
+            "--max-file-part-size", "4",
+            "--index-every", "3"
+        ]);
+        assert_eq!(output, format!("k\t{}\n", values.join(",")));
+    }
+
+    #[test]
+    fn test_with_values() {
+        let code = "
+            function map(key, value) {
+                emit('k', value);
+            }
+            function reduce(key, values, rereduce) {
+                return values.join(',');
+            }
+        ";
+        let output = run_with(code, "1\n2\n3", &["--with-values", "--max-values-retained", "2"]);
+        assert_eq!(output, "{\"key\":\"k\",\"result\":\"1,2,3\",\"values\":[\"1\",\"2\"]}\n");
+    }
+
+    #[test]
+    fn test_reduce_chunk_values() {
+        let code = "
+            function map(key, value) {
+                emit('k', value);
+            }
+            function reduce(key, values, rereduce) {
+                return values.join(',');
+            }
+        ";
+        let values: Vec<String> = (1..=20000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        //a single huge file part with many lines that are rereduced in chunks of 3
+        let output = run_with(code, &input, &[
+            "--ordered",
+            "--workers", "4",
+            "--read-buffer-size", "1",
+            "--key-flush-size", "1",
+            "--index-every", "1",
+            "--reduce-chunk-values", "3"
+        ]);
+        assert_eq!(output, format!("k\t{}\n", values.join(",")));
+    }
+
+    #[test]
+    fn test_tuple_stats() {
+        let code = "
+            function map(key, value) {
+                const n = Number(value);
+                emit('k', [1, n, n * n]);
+            }
+            function reduce(key, values, rereduce) {
+                return reduceTuples(values);
+            }
+            function finalize(key, value) {
+                const s = stats(value);
+                return [s.count, s.mean, s.variance];
+            }
+        ";
+        let values: Vec<String> = (1..=1000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        //tiny buffers so the tuples go through many rereduce calls
+        let args = [
+            "--workers", "4",
+            "--read-buffer-size", "1",
+            "--key-flush-size", "1",
+            "--max-file-part-size", "4",
+            "--index-every", "3"
+        ];
+        assert_eq!(run_with(code, &input, &args), "k\t[1000,500.5,83333.25]\n");
+        assert_eq!(run_with(code, &input, &["--reduce-passes", "single"]), "k\t[1000,500.5,83333.25]\n");
+    }
+
+    #[test]
+    fn test_sum_strict() {
+        let code = "
+            function map(key, value) {
+                emit('k', value);
+            }
+            function reduce(key, values, rereduce) {
+                return sumStrict(values);
+            }
+        ";
+        assert_eq!(run_with(code, "1\n2\n3", &[]), "k\t6\n");
+        let err = try_run_with(code, "1\nN/A\n3", &[]).unwrap_err();
+        assert!(format!("{:#}", err).contains("non-numeric value: N/A"));
+        //sum() keeps counting bad values as 0
+        assert_eq!(run_with(&code.replace("sumStrict", "sum"), "1\nN/A\n3", &[]), "k\t4\n");
+    }
+
+    #[test]
+    fn test_offset_mode_bytes() {
+        let code = "
+            function map(key, value) {
+                emit('k', key);
+            }
+            function reduce(key, values, rereduce) {
+                return values.join(',');
+            }
+        ";
+        //multi-byte characters, skipped empty lines and trimmed spaces still count
+        let input = "a\n bb\n\nΩc\na\n";
+        let args = ["--ordered", "--offset-mode", "bytes", "--lines-per-task", "2", "--pre-map-transform", "trim"];
+        assert_eq!(run_with(code, input, &args), "k\t0,2,7,11\n");
+        assert_eq!(run_with(code, input, &["--ordered", "--offset-mode", "bytes", "--keep-empty-lines"]), "k\t0,2,6,7,11\n");
+        assert_eq!(run_with(code, "a\nb\nc", &["--ordered"]), "k\t1,2,3\n");
+    }
+
+    #[test]
+    fn test_stream_sorted() {
+        let code = "
+            function map(key, value) {
+                emit(value.split(',')[0], value.split(',')[1]);
+            }
+            function reduce(key, values, rereduce) {
+                if (key === 'bad') {
+                    throw new Error('bad key');
+                }
+                return sum(values);
+            }
+        ";
+        let lines: Vec<String> = (1..=3000).map(|n| format!("k{},{}", n % 37, n)).chain(vec![String::from("bad,1")]).collect();
+        let input = lines.join("\n");
+        //keys of file parts and of the index are reduced out of order by many workers
+        let args = ["--workers", "8", "--read-buffer-size", "1", "--key-flush-size", "1", "--max-file-part-size", "4", "--index-every", "3", "--on-reduce-error", "skip"];
+        let buffered = run_with(code, &input, &args);
+        let mut streamed_args = args.to_vec();
+        streamed_args.push("--stream-sorted");
+        assert_eq!(run_with(code, &input, &streamed_args), buffered);
+        assert!(!buffered.contains("bad"));
+        let mut memory_args = streamed_args.clone();
+        memory_args.push("--no-index");
+        assert_eq!(run_with(code, &input, &memory_args), buffered);
+    }
+
+    #[test]
+    fn test_output_terminator() {
+        let code = WORD_COUNT;
+        let input = "b\na\nb";
+        assert_eq!(run_with(code, input, &["--output-terminator", "newline"]), "a\t1\nb\t2\n");
+        assert_eq!(run_with(code, input, &["--output-terminator", "nul"]), "a\t1\0b\t2\0");
+        assert_eq!(run_with(code, input, &["--output-terminator", "none"]), "a\t1\nb\t2");
+        assert_eq!(run_with(code, input, &["--output-terminator", "nul", "--keys-only"]), "a\0b\0");
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let code = "
+            function map(key, value) {
+                emit(value === 'broken' ? 'k\\uD800' : value, 1);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let input = format!("a\n{}\nbroken\na", "x".repeat(300));
+        let err = try_run_with(code, &input, &["--max-key-bytes", "10"]).unwrap_err();
+        assert!(format!("{:#}", err).contains("over the limit of 10 bytes"));
+        let err = try_run_with(code, "a\nbroken", &["--no-index"]).unwrap_err();
+        assert!(format!("{:#}", err).contains("Invalid key k\u{FFFD}: it is not valid UTF-8"));
+        assert_eq!(run_with(code, &input, &["--max-key-bytes", "10", "--on-invalid-key", "skip"]), "a\t2\n");
+    }
+
+    #[test]
+    fn test_hll() {
+        let code = "
+            function map(key, value) {
+                emit('users', String(Number(value) % 300));
+            }
+            function reduce(key, values, rereduce) {
+                return rereduce ? hllMerge(values) : hll(values);
+            }
+            function finalize(key, value) {
+                return hllCount(value);
+            }
+        ";
+        let values: Vec<String> = (1..=1000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        //the sketches are merged through many rereduce calls
+        let args = ["--workers", "4", "--read-buffer-size", "1", "--key-flush-size", "1", "--max-file-part-size", "4", "--index-every", "3"];
+        let merged = run_with(code, &input, &args);
+        //merging sketches is exact so both give the same estimate
+        assert_eq!(merged, run_with(code, &input, &["--reduce-passes", "single"]));
+        let estimate: f64 = merged.trim_start_matches("users\t").trim_end().parse().unwrap();
+        assert!((estimate - 300.0).abs() <= 9.0);
+    }
+
+    #[test]
+    fn test_part_read_order() {
+        let code = "
+            function map(key, value) {
+                emit('k', value);
+            }
+            function reduce(key, values, rereduce) {
+                return values.join(',');
+            }
+        ";
+        let values: Vec<String> = (1..=5000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        //splits the output into runs of consecutive values, each part is a single run
+        let runs = |order: &str| -> Vec<(usize, usize)> {
+            let output = run_with(code, &input, &[
+                "--ordered",
+                "--part-read-order", order,
+                "--read-buffer-size", "1",
+                "--key-flush-size", "3",
+                "--max-file-part-size", "4",
+                "--index-every", "1"
+            ]);
+            let values: Vec<usize> = output.trim_end().split('\t').nth(1).unwrap().split(',').map(|v| v.parse().unwrap()).collect();
+            let mut runs = vec![(values[0], values[0])];
+            for value in values.into_iter().skip(1) {
+                let last = runs.last_mut().unwrap();
+                if value == last.1 + 1 {
+                    last.1 = value;
+                } else {
+                    runs.push((value, value));
+                }
+            }
+            runs
+        };
+        assert_eq!(runs("asc"), vec![(1, 5000)]);
+        //the index values (the newest part) first and then each file part from the newest to the oldest
+        let desc = runs("desc");
+        assert!(desc.len() > 2);
+        assert_eq!(desc.first().unwrap().1, 5000);
+        assert_eq!(desc.last().unwrap().0, 1);
+        assert!(desc.windows(2).all(|runs| runs[1].1 + 1 == runs[0].0));
+        //the index values first and then the file parts in order
+        let index_first = runs("index-first");
+        assert_eq!(index_first, vec![(desc[0].0, 5000), (1, desc[0].0 - 1)]);
+    }
+
+    #[test]
+    fn test_max_value_bytes() {
+        //a reduce that never shrinks its values
+        let code = "
+            function map(key, value) {
+                emit(value.length > 2 ? 'big' : 'small', value);
+            }
+            function reduce(key, values, rereduce) {
+                return values.join(',');
+            }
+        ";
+        let output = run_with(code, "1\n2\n333\n444\n555", &["--max-value-bytes", "8", "--on-reduce-error", "skip"]);
+        assert_eq!(output, "small\t1,2\n");
+    }
+
+    #[test]
+    fn test_reduce_error_output() {
+        let code = "
+            function map(key, value) {
+                emit(value, key);
+            }
+            function reduce(key, values, rereduce) {
+                if (key === 'b') {
+                    throw new Error('bad key');
+                }
+                return values.length;
+            }
+        ";
+        let error_file = temp_path("jsonl");
+        let output = run_with(code, "a\nb\na\nb", &[
+            "--on-reduce-error", "skip",
+            "--reduce-passes", "single",
+            "--with-values",
+            "--reduce-error-output", error_file.to_str().unwrap()
+        ]);
+        assert!(output.contains(r#""key":"a""#));
+        assert!(!output.contains(r#""key":"b""#));
+        let errors = fs::read_to_string(&error_file).unwrap();
+        fs::remove_file(&error_file).unwrap();
+        let errors: Vec<serde_json::Value> = errors.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["key"], "b");
+        assert!(errors[0]["error"].as_str().unwrap().contains("bad key"));
+        assert_eq!(errors[0]["values"], serde_json::json!(["2", "4"]));
+    }
+
+    #[test]
+    fn test_single_value_reduce() {
+        let code = "
+            function map(key, value) {
+                emit(value, 'x'.repeat(1100));
+            }
+            function reduce(key, values, rereduce) {
+                return rereduce ? 'r(' + values.join(',') + ')' : 'i(' + values.length + ')';
+            }
+        ";
+        let input = "a\nb\nb";
+        assert_eq!(run_with(code, input, &[]), "a\tr(r(i(1)))\nb\tr(r(i(2)))\n");
+        assert_eq!(run_with(code, input, &["--single-value-reduce", "initial"]), "a\ti(1)\nb\tr(r(i(2)))\n");
+        assert_eq!(run_with(code, input, &["--single-value-reduce", "initial", "--coalesce-keys", "1000"]), "a\ti(1)\nb\tr(r(i(2)))\n");
+        //every value is flushed to a file part
+        let output = run_with(code, input, &["--single-value-reduce", "initial", "--key-flush-size", "1"]);
+        assert!(output.starts_with("a\ti(1)\nb\tr(r("));
+    }
+
+    #[test]
+    fn test_remote_code() {
+        let code = "function map(key, value) { emit('k', 1); } function reduce(key, values, rereduce) { return sum(values); }";
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/job.js", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            //the request headers end with an empty line
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut header = String::new();
+            while reader.read_line(&mut header).unwrap() > 2 {
+                header.clear();
+            }
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", code.len(), code);
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        //nothing is fetched without the flag
+        assert!(CLIOptions::from_args(vec!["omnimap", &url]).is_err());
+        let options = CLIOptions::from_args(vec!["omnimap", &url, "--allow-remote-code"]).unwrap();
+        server.join().unwrap();
+        let mut output = vec![];
+        run(options, &mut Cursor::new("a\nb"), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "k\t2\n");
+    }
+
+    #[test]
+    fn test_emit_error() {
+        let code = "
+            function map(key, value) {
+                if (isNaN(Number(value))) {
+                    emitError('not a number', value);
+                } else if (Number(value) < 0) {
+                    emitError('negative', {line: Number(key), value: Number(value)});
+                } else {
+                    emit('sum', value);
+                }
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let error_file = temp_path("jsonl");
+        let output = run_with(code, "1\nx\n2\n-3\n4", &["--error-output", error_file.to_str().unwrap()]);
+        assert_eq!(output, "sum\t7\n");
+        let errors = fs::read_to_string(&error_file).unwrap();
+        fs::remove_file(&error_file).unwrap();
+        let mut errors: Vec<serde_json::Value> = errors.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        errors.sort_by_key(|error| error["reason"].as_str().unwrap().to_owned());
+        assert_eq!(errors, vec![
+            serde_json::json!({"reason": "negative", "record": "{\"line\":4,\"value\":-3}"}),
+            serde_json::json!({"reason": "not a number", "record": "x"})
+        ]);
+    }
+
+    #[test]
+    fn test_lines_per_task() {
+        let code = "
+            function map(key, value) {
+                emit('k', key + ':' + value.length);
+            }
+            function reduce(key, values, rereduce) {
+                return values.join(',');
+            }
+        ";
+        //lines of very different lengths, each map task gets 7 of them
+        let lines: Vec<String> = (1..=1000).map(|n| "x".repeat(n % 50 * 100 + 1)).collect();
+        let expected: Vec<String> = lines.iter().enumerate().map(|(i, line)| format!("{}:{}", i + 1, line.len())).collect();
+        let output = run_with(code, &lines.join("\n"), &["--ordered", "--workers", "4", "--lines-per-task", "7"]);
+        assert_eq!(output, format!("k\t{}\n", expected.join(",")));
+        //every line is its own map task
+        let output = run_with(code, &lines.join("\n"), &["--ordered", "--workers", "4", "--line-streaming"]);
+        assert_eq!(output, format!("k\t{}\n", expected.join(",")));
+    }
+
+    #[test]
+    fn test_final_reduce() {
+        let code = &[WORD_COUNT, "
+            function finalReduce(results) {
+                return sum(results);
+            }
+        "].concat();
+        assert_eq!(run_with(code, "a\nb\na", &[]), "a\t2\nb\t1\n_total\t3\n");
+        assert_eq!(run_with(code, "a\nb\na", &["--order", "desc", "--final-reduce-key", "all"]), "b\t1\na\t2\nall\t3\n");
+    }
+
+    #[test]
+    fn test_single_file_parts() {
+        let code = "
+            function map(key, value) {
+                emit(String(value % 3), value);
+            }
+            function reduce(key, values, rereduce) {
+                return values.join(',');
+            }
+        ";
+        let values: Vec<String> = (1..=5000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        let args = ["--ordered", "--reduce-passes", "single", "--key-flush-size", "3", "--max-file-part-size", "4", "--index-every", "1", "--verify-parts"];
+        let expected = run_with(code, &input, &args);
+        let mut single_file_args = args.to_vec();
+        single_file_args.push("--single-file-parts");
+        assert_eq!(run_with(code, &input, &single_file_args), expected);
+    }
+
+    #[test]
+    fn test_index_every_bytes() {
+        let code = "
+            function map(key, value) {
+                emit(String(value % 7), value);
+            }
+            function reduce(key, values, rereduce) {
+                return values.join(',');
+            }
+        ";
+        let values: Vec<String> = (1..=5000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        let args = ["--ordered", "--reduce-passes", "single", "--key-flush-size", "1", "--read-buffer-size", "1"];
+        let expected = run_with(code, &input, &args);
+        let mut index_bytes_args = args.to_vec();
+        index_bytes_args.extend_from_slice(&["--index-every-bytes", "2"]);
+        assert_eq!(run_with(code, &input, &index_bytes_args), expected);
+    }
+
+    #[test]
+    fn test_custom_order() {
+        let code = &[WORD_COUNT, "
+            function compare(aKey, aValue, bKey, bValue) {
+                return Number(bValue) - Number(aValue) || aKey.localeCompare(bKey);
+            }
+        "].concat();
+        assert_eq!(run_with(code, "a\nb\nc\nb\nc\nc\nd", &["--order", "custom"]), "c\t3\nb\t2\na\t1\nd\t1\n");
+        //not a total order, it only has to finish
+        let random_code = code.replace("Number(bValue) - Number(aValue) || aKey.localeCompare(bKey)", "Math.random() < 0.5 ? NaN : Math.random() - 0.5");
+        let values: Vec<String> = (1..=500).map(|n| n.to_string()).collect();
+        assert_eq!(run_with(&random_code, &values.join("\n"), &["--order", "custom"]).lines().count(), 500);
+        let failing_code = code.replace("return Number(bValue)", "if (aKey === '3' || bKey === '3') { throw new Error('bad key'); } return Number(bValue)");
+        let err = try_run_with(&failing_code, &values.join("\n"), &["--order", "custom"]).unwrap_err();
+        assert!(format!("{:?}", err).contains("bad key"));
+    }
+
+    #[test]
+    fn test_transform() {
+        let code = "
+            function map(key, value) {
+                emit(value, 1);
+            }
+            function reduce(key, values, rereduce) {
+                const total = values.reduce((acc, value) => acc + Number(value), 0);
+                return rereduce ? total : total / 3;
+            }
+            function finalReduce(results) {
+                return results.length;
+            }
+            function transform(value) {
+                return Number(value).toFixed(2);
+            }
+        ";
+        assert_eq!(run_with(code, "a\nb\na", &[]), "a\t0.67\nb\t0.33\n_total\t2.00\n");
+    }
+
+    #[test]
+    fn test_expect_unique_keys() {
+        let code = "
+            function map(key, value) {
+                emit(value, key);
+            }
+            function reduce(key, values, rereduce) {
+                return values[0];
+            }
+        ";
+        for args in &[["--expect-unique-keys", "--no-index"], ["--expect-unique-keys", "--ordered"]] {
+            assert_eq!(run_with(code, "a\nb\nc", args), "a\t1\nb\t2\nc\t3\n");
+            let err = try_run_with(code, "a\nb\na\nc", args).unwrap_err();
+            assert!(format!("{:#}", err).contains("Key a received 2 values but unique keys are expected"));
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        //per user totals and then the number of users in each bucket of 10
+        let totals = "
+            function map(key, value) {
+                const [user, amount] = value.split(',');
+                emit(user, Number(amount));
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let buckets = "
+            function map(key, value) {
+                const [user, total] = value.split('\\t');
+                emit(String(Math.floor(Number(total) / 10) * 10), 1);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let stage_file = temp_path("js");
+        fs::write(&stage_file, buckets).unwrap();
+        let temp_root = temp_path("");
+        fs::create_dir(&temp_root).unwrap();
+        let input = "a,5\nb,12\na,3\nc,15\nd,40\nb,1";
+        let output = run_with(totals, input, &["--chain", stage_file.to_str().unwrap(), "--temp-dir", temp_root.to_str().unwrap()]);
+        assert_eq!(output, "0\t1\n10\t2\n40\t1\n");
+        //the stages share a single temp dir that is removed at the end
+        assert_eq!(fs::read_dir(&temp_root).unwrap().count(), 0);
+        fs::remove_dir(&temp_root).unwrap();
+        fs::remove_file(&stage_file).unwrap();
+    }
+
+    #[test]
+    fn test_chain_outputs() {
+        let first = "
+            function map(key, value) {
+                if (value === 'bad') {
+                    emitError('first stage', value);
+                    return;
+                }
+                emit(value, 1);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let second = "
+            function map(key, value) {
+                const [word, count] = value.split('\\t');
+                if (word === 'b') {
+                    emitError('second stage', value);
+                }
+                emit(count, 1);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let stage_file = temp_path("js");
+        fs::write(&stage_file, second).unwrap();
+        let error_file = temp_path("jsonl");
+        let metrics_file = temp_path("prom");
+        let args = [
+            "--chain", stage_file.to_str().unwrap(),
+            "--error-output", error_file.to_str().unwrap(),
+            "--metrics-file", metrics_file.to_str().unwrap()
+        ];
+        let output = run_with(first, "a\nbad\nb\na", &args);
+        assert_eq!(output, "1\t1\n2\t1\n");
+        //the records of every stage end up in the same file
+        let errors = fs::read_to_string(&error_file).unwrap();
+        let reasons: Vec<String> = errors.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["reason"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(reasons, vec!["first stage", "second stage"]);
+        let metrics = fs::read_to_string(&metrics_file).unwrap();
+        assert!(metrics.contains("omnimap_lines_read_total{stage=\"1\"} 4\n"));
+        assert!(metrics.contains("omnimap_lines_read_total{stage=\"2\"} 2\n"));
+        assert_eq!(metrics.matches("# TYPE omnimap_lines_read_total").count(), 1);
+        fs::remove_file(&stage_file).unwrap();
+        fs::remove_file(&error_file).unwrap();
+        fs::remove_file(&metrics_file).unwrap();
+    }
+
+    #[test]
+    fn test_progress_json() {
+        use std::os::unix::io::AsRawFd;
+        let code = "
+            function map(key, value) {
+                emit(value % 7, 1);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let values: Vec<String> = (1..=3000).map(|n| n.to_string()).collect();
+        let progress_path = temp_path("jsonl");
+        let progress_file = File::create(&progress_path).unwrap();
+        let fd = progress_file.as_raw_fd().to_string();
+        let started = Instant::now();
+        run_with(code, &values.join("\n"), &["--progress-format", "json", "--progress-fd", &fd, "--read-buffer-size", "1", "--index-every", "1"]);
+        let elapsed_secs = started.elapsed().as_secs() as usize;
+        drop(progress_file);
+        let events = fs::read_to_string(&progress_path).unwrap();
+        fs::remove_file(&progress_path).unwrap();
+        let events: Vec<serde_json::Value> = events.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let stages: Vec<&serde_json::Value> = events.iter().filter(|event| event["event"] == "stage").collect();
+        let names: Vec<&str> = stages.iter().map(|event| event["stage"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["map", "reduce", "output", "done"]);
+        let done = stages[3];
+        assert_eq!(done["lines_read"], 3000);
+        assert_eq!(done["keys_reduced"], 7);
+        assert!(done["index_cycles"].as_u64().unwrap() > 1);
+        assert!(events.iter().all(|event| event["time_ms"].as_u64().unwrap() > 0));
+        //every line is its own chunk but the counter events are throttled to one per second
+        for event in &["map", "index", "reduce"] {
+            assert!(events.iter().filter(|e| e["event"] == *event).count() <= elapsed_secs + 1);
+        }
+    }
+
+    #[test]
+    fn test_with_provenance() {
+        let code = "
+            function map(key, value) {
+                emit(value, 'x'.repeat(300));
+            }
+            function reduce(key, values, rereduce) {
+                return values.length;
+            }
+        ";
+        let input = "a\nb\na\na\na\na";
+        let args = ["--with-provenance", "--workers", "3", "--key-flush-size", "1", "--index-every", "1", "--read-buffer-size", "1"];
+        let lines: Vec<serde_json::Value> = run_with(code, input, &args).lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        for line in &lines {
+            let key = line["key"].as_str().unwrap();
+            assert_eq!(line["partition"], combiner::partition_of(key, 3));
+            assert!(line.get("values").is_none());
+        }
+        //a was spilled to file parts, b stayed in the index
+        assert!(lines[0]["parts"].as_u64().unwrap() > 0);
+        assert_eq!(lines[1]["parts"], 0);
+        let mut with_values_args = args.to_vec();
+        with_values_args.push("--with-values");
+        let output = run_with(code, input, &with_values_args);
+        let line: serde_json::Value = serde_json::from_str(output.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(line["values"].as_array().unwrap().len(), 1);
+        assert_eq!(line["parts"], 0);
+        let output = run_with(code, input, &["--with-provenance", "--no-index"]);
+        assert!(output.contains(r#""partition":0"#));
+    }
+
+    #[test]
+    fn test_partition_function() {
+        let code = &[WORD_COUNT, "
+            function partition(key) {
+                if (key.startsWith('x')) {
+                    return 2;
+                }
+            }
+        "].concat();
+        let input = "xa\nxb\nc\nxa";
+        let output = run_with(code, input, &["--with-provenance", "--workers", "3"]);
+        let lines: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["partition"], combiner::partition_of("c", 3));
+        assert_eq!(lines[1]["key"], "xa");
+        assert_eq!(lines[1]["result"], "2");
+        assert_eq!(lines[1]["partition"], 2);
+        assert_eq!(lines[2]["partition"], 2);
+        //only partitions 0 and 1 exist with 2 workers
+        let err = try_run_with(code, input, &["--workers", "2"]).unwrap_err();
+        assert!(format!("{:?}", err).contains("partition() returned 2 for key x"));
+        assert_eq!(run_with(code, input, &["--workers", "2", "--no-index"]), "c\t1\nxa\t2\nxb\t1\n");
+    }
+
+    #[test]
+    fn test_max_temp_bytes() {
+        let code = "
+            function map(key, value) {
+                emit(value % 10, value);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let values: Vec<String> = (1..=20000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        let temp_root = temp_path("");
+        fs::create_dir(&temp_root).unwrap();
+        let args = ["--temp-dir", temp_root.to_str().unwrap(), "--key-flush-size", "1", "--index-every", "1"];
+        let mut limited_args = args.to_vec();
+        limited_args.extend_from_slice(&["--max-temp-bytes", "10000000"]);
+        assert_eq!(run_with(code, &input, &limited_args), run_with(code, &input, &args));
+        let mut small_args = args.to_vec();
+        small_args.extend_from_slice(&["--max-temp-bytes", "1000"]);
+        let err = try_run_with(code, &input, &small_args).unwrap_err();
+        assert!(format!("{:#}", err).contains("over the --max-temp-bytes limit of 1000 bytes"));
+        //the temp dir of the failed run is removed
+        assert_eq!(fs::read_dir(&temp_root).unwrap().count(), 0);
+        fs::remove_dir(&temp_root).unwrap();
+    }
+
+    #[test]
+    fn test_pre_map_transform() {
+        let code = WORD_COUNT;
+        let input = "1, Apple\n2,apple \n3, BANANA";
+        let output = run_with(code, input, &["--pre-map-transform", "field:2:,", "--pre-map-transform", "trim", "--pre-map-transform", "lowercase"]);
+        assert_eq!(output, "apple\t2\nbanana\t1\n");
+    }
+
+    #[test]
+    fn test_collect_sorted() {
+        let code = "
+            function map(key, value) {
+                emit(value.length > 3 ? 'big' : 'small', value);
+            }
+            function reduce(key, values, rereduce) {
+                return values[Math.floor(values.length / 2)];
+            }
+        ";
+        //big has its values spread over file parts, small only has a few values in the index
+        let big: Vec<String> = (1..=2001).rev().map(|n| (n * 1000).to_string()).collect();
+        let input = format!("{}\n30\n-5\n7.5", big.join("\n"));
+        let args = ["--collect-sorted", "--key-flush-size", "1", "--max-file-part-size", "4", "--index-every", "1"];
+        assert_eq!(run_with(code, &input, &args), "big\t1001000\nsmall\t7.5\n");
+        //the values of big are over 1kb
+        let capped = run_with(code, &input, &["--collect-sorted", "--max-collected-size", "1", "--on-reduce-error", "skip"]);
+        assert_eq!(capped, "small\t7.5\n");
+        assert!(try_run_with(code, "1\nnot a number", &["--collect-sorted"]).is_err());
+    }
+
+    #[test]
+    fn test_reduce_value_dedup() {
+        let code = "
+            function map(key, value) {
+                const [name, tag] = value.split(' ');
+                emit(name, tag);
+            }
+            function reduce(key, values, rereduce) {
+                return rereduce ? sum(values) : values.length;
+            }
+        ";
+        let input = "a x\na y\na x\nb z\nb z\nb z";
+        assert_eq!(run_with(code, input, &[]), "a\t3\nb\t3\n");
+        assert_eq!(run_with(code, input, &["--reduce-value-dedup"]), "a\t2\nb\t1\n");
+        let single = ["--reduce-value-dedup", "--key-flush-size", "1", "--index-every", "1", "--reduce-passes", "single"];
+        assert_eq!(run_with(code, input, &single), "a\t2\nb\t1\n");
+    }
+
+    #[test]
+    fn test_length_prefixed_input() {
+        let code = WORD_COUNT;
+        let record = |payload: &str| format!("{}{}", String::from_utf8(vec![0, 0, 0, payload.len() as u8]).unwrap(), payload);
+        let input = [record("hello"), record(""), record("a\nb"), record("hello")].concat();
+        let args = ["--input-format", "length-prefixed"];
+        assert_eq!(run_with(code, &input, &args), "YQpi\t1\naGVsbG8=\t2\n");
+        //the last record is shorter than its length
+        assert!(try_run_with(code, &input[..input.len() - 1], &args).is_err());
+    }
+
+    #[test]
+    fn test_require_output() {
+        let code = "
+            function map(key, value) {
+                if (value !== 'skip') {
+                    emit(value, 1);
+                }
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        assert_eq!(run_with(code, "a\nskip", &["--require-output"]), "a\t1\n");
+        let err = try_run_with(code, "skip\nskip", &["--require-output"]).unwrap_err();
+        assert!(err.downcast_ref::<EmptyOutput>().is_some());
+        assert_eq!(run_with(code, "skip\nskip", &[]), "");
+    }
+
+    #[test]
+    fn test_sort_values() {
+        let code = "
+            function map(key, value) {
+                emit('k', value);
+            }
+            function reduce(key, values, rereduce) {
+                return values.join(',');
+            }
+        ";
+        //a shuffled input, spilled to few parts so new values are merged into already sorted parts
+        let values: Vec<String> = (0..5000).map(|n| ((n * 7919) % 5000 - 2500).to_string()).collect();
+        let input = values.join("\n");
+        let mut sorted: Vec<i32> = values.iter().map(|value| value.parse().unwrap()).collect();
+        sorted.sort();
+        let sorted: Vec<String> = sorted.iter().map(|n| n.to_string()).collect();
+        let expected = format!("k\t{}\n", sorted.join(","));
+        let args = [
+            "--sort-values",
+            "--verify-parts",
+            "--read-buffer-size", "1",
+            "--key-flush-size", "1",
+            "--max-file-part-size", "4",
+            "--index-every", "7"
+        ];
+        assert_eq!(run_with(code, &input, &args), expected);
+        assert_eq!(run_with(code, &input, &["--sort-values"]), expected);
+        assert_eq!(run_with(code, &input, &["--sort-values", "--no-index"]), expected);
+        assert!(try_run_with(code, "1\nnot a number", &["--sort-values"]).is_err());
+    }
+
+    #[test]
+    fn test_float_precision() {
+        let code = "
+            function map(key, value) {
+                const [name, score] = value.split(' ');
+                emit(name, score);
+            }
+            function reduce(key, values, rereduce) {
+                return values.reduce((total, value) => total + Number(value), 0);
+            }
+            function finalize(key, value) {
+                if (key === 'json') {
+                    return {avg: value / 3, count: 3, name: key};
+                }
+                return key === 'int' ? value : value / 3;
+            }
+        ";
+        let input = "avg 1\navg 1\nint 7\njson 2";
+        let args = ["--float-precision", "2"];
+        assert_eq!(run_with(code, input, &args), "avg\t0.67\nint\t7\njson\t{\"avg\":0.67,\"count\":3,\"name\":\"json\"}\n");
+        assert_eq!(run_with(code, "avg 1\navg 1", &[]), "avg\t0.6666666666666666\n");
+    }
+
+    #[test]
+    fn test_bucket() {
+        let code = "
+            function map(key, value) {
+                const [name, score] = value.split(' ');
+                emit(name, score);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+            function bucket(key, value) {
+                return Number(value) >= 100 ? '100+' : '0-99';
+            }
+        ";
+        let input = "a 50\nb 120\nc 20\nd 300\na 10";
+        assert_eq!(run_with(code, input, &[]), "# 0-99\na\t60\nc\t20\n# 100+\nb\t120\nd\t300\n");
+        assert_eq!(run_with(code, input, &["--order", "desc"]), "# 0-99\nc\t20\na\t60\n# 100+\nd\t300\nb\t120\n");
+        assert_eq!(run_with(code, input, &["--top-k", "3"]), "# 0-99\na\t60\n# 100+\nd\t300\nb\t120\n");
+    }
+
+    #[test]
+    fn test_key_filter() {
+        let code = &[WORD_COUNT, "
+            function filter(key) {
+                return key !== 'b';
+            }
+        "].concat();
+        assert_eq!(run_with(code, "a\nb\na\nc", &[]), "a\t2\nc\t1\n");
+        assert_eq!(run_with(code, "a\nb\na\nc", &["--no-index"]), "a\t2\nc\t1\n");
+    }
+
+    #[test]
+    fn test_open_output() {
+        let path = temp_path("txt");
+        fs::write(&path, "an earlier and longer output\n").unwrap();
+        let mut writer = open_output(Some(&path)).unwrap();
+        writer.write_all(b"a\t1\n").unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a\t1\n");
+        fs::remove_file(&path).unwrap();
+        let unwritable = temp_path("").join("output.txt");
+        let err = open_output(Some(&unwritable)).err().unwrap();
+        assert!(err.to_string().starts_with("Could not open output file"));
+    }
+
+    #[test]
+    fn test_passthrough() {
+        //no reduce() is needed
+        let code = "
+            function map(key, value) {
+                if (Number(value.split(' ')[0]) % 2 === 0) {
+                    keep();
+                }
+            }
+        ";
+        let lines: Vec<String> = (1..=2000).map(|n| format!("{} line", n)).collect();
+        let kept: Vec<String> = lines.iter().filter(|line| line.split(' ').next().unwrap().parse::<usize>().unwrap() % 2 == 0).cloned().collect();
+        let output = run_with(code, &lines.join("\n"), &["--passthrough", "--workers", "4", "--lines-per-task", "3"]);
+        assert_eq!(output, format!("{}\n", kept.join("\n")));
+        let output = run_with(code, &lines.join("\n"), &["--passthrough", "--output-terminator", "nul"]);
+        assert_eq!(output, format!("{}\0", kept.join("\0")));
+        let output = run_with(code, &lines.join("\n"), &["--passthrough", "--output-terminator", "none"]);
+        assert_eq!(output, kept.join("\n"));
+    }
+
+    #[test]
+    fn test_result_encoding() {
+        let code = "
+            function map(key, value) {
+                emit(value, 1);
+            }
+            function reduce(key, values, rereduce) {
+                const total = sum(values);
+                if (key === 'text') {
+                    return 'total ' + total;
+                } else if (key === 'unordered') {
+                    return {z: total, a: [total / 3, null, true]};
+                }
+                return {a: [total / 3, null, true], z: {count: total}};
+            }
+        ";
+        let input = "sorted\nsorted\ntext\nunordered";
+        let json = run_with(code, input, &["--reduce-passes", "single"]);
+        assert_eq!(run_with(code, input, &["--reduce-passes", "single", "--result-encoding", "bincode"]), json);
+        assert_eq!(json, "sorted\t{\"a\":[0.6666666666666666,null,true],\"z\":{\"count\":2}}\ntext\ttotal 1\nunordered\t{\"z\":1,\"a\":[0.3333333333333333,null,true]}\n");
+    }
+
+    #[test]
+    fn test_result_column_family() {
+        let code = WORD_COUNT;
+        let db_path = temp_path("");
+        //the user's own data lives in another column family
+        {
+            let mut opts = rocksdb::Options::default();
+            opts.create_if_missing(true);
+            let mut db = rocksdb::DB::open_default(&db_path).unwrap();
+            db.create_cf("users", &opts).unwrap();
+            db.put_cf(db.cf_handle("users").unwrap(), "u1", "kept").unwrap();
+        }
+        let args = ["--result-db", db_path.to_str().unwrap(), "--result-cf", "results"];
+        assert_eq!(run_with(code, "a\nb\na", &args), "a\t2\nb\t1\n");
+        //a second run adds its results to the existing ones
+        assert_eq!(run_with(code, "c\na", &args), "a\t1\nb\t1\nc\t1\n");
+        //unless it replaces the column family
+        let mut replace_args = args.to_vec();
+        replace_args.push("--replace-result-cf");
+        assert_eq!(run_with(code, "c", &replace_args), "c\t1\n");
+        let column_families = rocksdb::DB::list_cf(&rocksdb::Options::default(), &db_path).unwrap();
+        let db = rocksdb::DB::open_cf(&rocksdb::Options::default(), &db_path, &column_families).unwrap();
+        let results: Vec<_> = db.iterator_cf(db.cf_handle("results").unwrap(), rocksdb::IteratorMode::Start).map(|(key, value)| (key.to_vec(), value.to_vec())).collect();
+        assert_eq!(results, vec![(b"c".to_vec(), b"1".to_vec())]);
+        assert_eq!(&db.get_cf(db.cf_handle("users").unwrap(), "u1").unwrap().unwrap()[..], b"kept");
+        drop(db);
+        fs::remove_dir_all(&db_path).unwrap();
+    }
+
+    #[test]
+    fn test_max_open_parts() {
+        let code = "
+            function map(key, value) {
+                emit(String(Number(value) % 20), value);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let values: Vec<String> = (1..=5000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        //the file parts of the run are the only ones under its own temp dir
+        let dir = temp_path("");
+        create_dir_all(&dir).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+        //many keys spilled to file parts by 8 workers at once
+        let args = ["--workers", "8", "--read-buffer-size", "1", "--key-flush-size", "1", "--max-file-part-size", "1", "--index-every", "2", "--temp-dir", dir.to_str().unwrap()];
+        let (unlimited, unlimited_peak) = with_open_parts_peak(&dir, || run_with(code, &input, &args));
+        let mut limited_args = args.to_vec();
+        limited_args.extend_from_slice(&["--max-open-parts", "1"]);
+        let (limited, limited_peak) = with_open_parts_peak(&dir, || run_with(code, &input, &limited_args));
+        assert_eq!(limited, unlimited);
+        //the open parts are seen at all, but never more than one at a time with the limit
+        assert!(unlimited_peak >= 1);
+        assert!(limited_peak <= 1);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    ///Runs f while counting the file parts open under dir, returns its output and the most parts seen open at once
+    fn with_open_parts_peak<F: FnOnce() -> String>(dir: &Path, f: F) -> (String, usize) {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let (dir, done) = (dir.to_path_buf(), done.clone());
+            thread::spawn(move || {
+                let mut peak = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let open = fs::read_dir("/proc/self/fd").unwrap()
+                        .filter_map(|fd| fs::read_link(fd.ok()?.path()).ok())
+                        .filter(|target| target.starts_with(&dir) && target.to_string_lossy().ends_with(".jsonl"))
+                        .count();
+                    peak = cmp::max(peak, open);
+                }
+                peak
+            })
+        };
+        let output = f();
+        done.store(true, Ordering::SeqCst);
+        (output, watcher.join().unwrap())
+    }
+
+    #[test]
+    fn test_reduce_queue_size() {
+        let code = "
+            function map(key, value) {
+                emit(String(Number(value) % 10), value);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let values: Vec<String> = (1..=1000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        let args = ["--workers", "4", "--key-flush-size", "1", "--max-file-part-size", "1", "--index-every", "2"];
+        let mut small_args = args.to_vec();
+        small_args.extend_from_slice(&["--reduce-queue-size", "1"]);
+        let mut large_args = args.to_vec();
+        large_args.extend_from_slice(&["--reduce-queue-size", "256"]);
+        assert_eq!(run_with(code, &input, &small_args), run_with(code, &input, &large_args));
+    }
+
+    #[test]
+    fn test_group_key_mode() {
+        let code = "
+            function map(key, value) {
+                emit(value.replace('_', ' '), 1);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let input = "A\na\nA_\nb";
+        assert_eq!(run_with(code, input, &[]), "A\t1\nA \t1\na\t1\nb\t1\n");
+        assert_eq!(run_with(code, input, &["--group-key-mode", "trimmed"]), "A\t2\na\t1\nb\t1\n");
+        assert_eq!(run_with(code, input, &["--group-key-mode", "lowercase"]), "A\t2\nA \t1\nb\t1\n");
+        assert_eq!(run_with(code, input, &["--group-key-mode", "lowercase", "--no-index"]), "A\t2\nA \t1\nb\t1\n");
+        assert_eq!(run_with(code, input, &["--group-key-mode", "trimmed", "--keys-only"]), "A\na\nb\n");
+    }
+
+    #[test]
+    fn test_top_k() {
+        let code = "
+            function map(key, value) {
+                emit(value, 1);
+            }
+            function reduce(key, values, rereduce) {
+                return key === 'text' ? 'many' : sum(values);
+            }
+        ";
+        let input = "a\nb\nb\nc\nc\nc\nd\nd\ne\ntext\ntext\ntext\ntext";
+        //b and d are tied, the smaller key ranks higher
+        assert_eq!(run_with(code, input, &["--top-k", "3"]), "c\t3\nb\t2\nd\t2\n");
+        assert_eq!(run_with(code, input, &["--top-k", "10"]), "c\t3\nb\t2\nd\t2\na\t1\ne\t1\n");
+    }
+
+    #[test]
+    fn test_env_allow() {
+        let code = "
+            function map(key, value) {
+                env.OMNIMAP_TEST_ALLOWED = 'changed';
+                emit('env', JSON.stringify(env));
+            }
+            function reduce(key, values, rereduce) {
+                return values[0];
+            }
+        ";
+        //a fake environment instead of the process one which is shared by the tests
+        let vars: BTreeMap<&str, &str> = vec![("OMNIMAP_TEST_ALLOWED", "1"), ("OMNIMAP_TEST_SECRET", "2")].into_iter().collect();
+        let allowed = allowed_env(" OMNIMAP_TEST_ALLOWED,OMNIMAP_TEST_MISSING,", |name| vars.get(name).map(|value| value.to_string()));
+        assert_eq!(allowed.keys().collect::<Vec<_>>(), vec!["OMNIMAP_TEST_ALLOWED"]);
+        let code_file = temp_path("js");
+        fs::write(&code_file, code).unwrap();
+        let mut options = CLIOptions::from_args(vec!["omnimap", code_file.to_str().unwrap()]).unwrap();
+        options.env = allowed;
+        let mut output = vec![];
+        run(options, &mut Cursor::new("x"), &mut output).unwrap();
+        fs::remove_file(&code_file).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "env\t{\"OMNIMAP_TEST_ALLOWED\":\"1\"}\n");
+        assert_eq!(run_with(code, "x", &[]), "env\t{}\n");
+        //a script that declares its own env keeps it
+        for own_env in &["const env = {own: 1};", "let env = {own: 1};", "var env = {own: 1};", "function env() {} env.own = 1;"] {
+            let own_code = format!("
+                {}
+                function map(key, value) {{
+                    emit('own', String(env.own));
+                }}
+                function reduce(key, values, rereduce) {{
+                    return values[0];
+                }}
+            ", own_env);
+            assert_eq!(run_with(&own_code, "x", &[]), "own\t1\n");
+            assert_eq!(run_with(&own_code, "x", &["--env-allow", "OMNIMAP_TEST_MISSING"]), "own\t1\n");
+        }
+    }
+
+    #[test]
+    fn test_no_index() {
+        let code = "
+            function map(key, value) {
+                emit(value % 97, value);
+                emit('all', 1);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let values: Vec<String> = (1..=5000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        let args = ["--read-buffer-size", "1", "--key-flush-size", "1", "--index-every", "2", "--max-file-part-size", "4"];
+        let indexed = run_with(code, &input, &args);
+        let mut no_index_args = args.to_vec();
+        no_index_args.push("--no-index");
+        assert_eq!(run_with(code, &input, &no_index_args), indexed);
+        let mut coalesced_args = no_index_args.clone();
+        coalesced_args.extend_from_slice(&["--coalesce-keys", "1000"]);
+        assert_eq!(run_with(code, &input, &coalesced_args), indexed);
+    }
+
+    #[test]
+    fn test_streaming_reduce() {
+        let code = "
+            function map(key, value) {
+                emit(value % 97, value);
+                emit('all', 1);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let values: Vec<String> = (1..=5000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        let args = ["--read-buffer-size", "1", "--workers", "4"];
+        let indexed = run_with(code, &input, &args);
+        let mut streaming_args = args.to_vec();
+        streaming_args.push("--streaming-reduce");
+        assert_eq!(run_with(code, &input, &streaming_args), indexed);
+        assert_eq!(indexed.lines().count(), 98);
+        let mut top_args = args.to_vec();
+        top_args.extend_from_slice(&["--top-k", "3"]);
+        let top = run_with(code, &input, &top_args);
+        streaming_args.extend_from_slice(&["--top-k", "3"]);
+        assert_eq!(run_with(code, &input, &streaming_args), top);
+        //counting the values without rereduce gives the wrong result in steps
+        let unsafe_code = "
+            function map(key, value) {
+                emit('all', value);
+            }
+            function reduce(key, values, rereduce) {
+                return values.length;
+            }
+        ";
+        let err = try_run_with(unsafe_code, &input, &["--streaming-reduce"]).unwrap_err();
+        assert!(format!("{:?}", err).contains("not rereduce safe"));
+        //every map task has a single value so the check only happens when the key is merged
+        let err = try_run_with(unsafe_code, &input, &["--streaming-reduce", "--read-buffer-size", "1"]).unwrap_err();
+        assert!(format!("{:?}", err).contains("not rereduce safe"));
+        let code_file = temp_path("js");
+        fs::write(&code_file, code).unwrap();
+        let code_path = code_file.to_str().unwrap();
+        assert!(CLIOptions::from_args(vec!["omnimap", code_path, "--streaming-reduce"]).is_ok());
+        assert!(CLIOptions::from_args(vec!["omnimap", code_path, "--streaming-reduce", "--on-reduce-error", "skip"]).is_err());
+        fs::remove_file(&code_file).unwrap();
+    }
+
+    #[test]
+    fn test_input_file_options() {
+        let code_file = temp_path("js");
+        fs::write(&code_file, WORD_COUNT).unwrap();
+        let input_file = temp_path("txt");
+        fs::write(&input_file, "a").unwrap();
+        let missing_file = temp_path("txt");
+        let (code_path, input_path, missing_path) = (code_file.to_str().unwrap(), input_file.to_str().unwrap(), missing_file.to_str().unwrap());
+        assert!(CLIOptions::from_args(vec!["omnimap", code_path, input_path, input_path]).is_ok());
+        let err = CLIOptions::from_args(vec!["omnimap", code_path, input_path, missing_path]).err().unwrap();
+        assert!(err.to_string().starts_with("Could not open input file"));
+        assert!(CLIOptions::from_args(vec!["omnimap", code_path, input_path, "--offset-mode", "bytes"]).is_ok());
+        assert!(CLIOptions::from_args(vec!["omnimap", code_path, input_path, input_path, "--offset-mode", "bytes"]).is_err());
+        fs::remove_file(&code_file).unwrap();
+        fs::remove_file(&input_file).unwrap();
+    }
+
+    #[test]
+    fn test_async_functions() {
+        let code = "
+            function map(key, value) {
+                emit(value % 7, value);
+            }
+            function reduce(key, values, rereduce) {
+                return sum(values);
+            }
+        ";
+        let async_code = "
+            async function map(key, value) {
+                await Promise.resolve();
+                emit(value % 7, value);
+            }
+            async function reduce(key, values, rereduce) {
+                const total = await Promise.resolve(sum(values));
+                return total;
+            }
+        ";
+        let values: Vec<String> = (1..=1000).map(|n| n.to_string()).collect();
+        let input = values.join("\n");
+        let args = ["--read-buffer-size", "1"];
+        let expected = run_with(code, &input, &args);
+        assert_eq!(expected.lines().count(), 7);
+        assert_eq!(run_with(async_code, &input, &args), expected);
+        //nothing can resolve a promise that waits for something else
+        let pending_code = "
+            function map(key, value) {
+                emit('k', value);
+            }
+            function reduce(key, values, rereduce) {
+                return new Promise(() => {});
+            }
+        ";
+        let err = try_run_with(pending_code, &input, &[]).unwrap_err();
+        assert!(format!("{:?}", err).contains("Promise did not resolve"));
+    }
+}
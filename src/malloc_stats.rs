@@ -0,0 +1,77 @@
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use jemalloc_ctl::{background_thread, epoch, stats};
+use anyhow::{Result, anyhow};
+
+///Lets jemalloc purge unused memory from its own background threads instead of the allocating ones
+pub fn enable_background_threads() -> Result<()> {
+    background_thread::write(true).map_err(|err| anyhow!("Could not enable jemalloc background threads: {}", err))
+}
+
+///Periodically writes the allocated and resident bytes of jemalloc to stderr, used with --malloc-stats
+pub struct MallocStats {
+    stop: Sender<()>,
+    handle: JoinHandle<()>
+}
+
+impl MallocStats {
+    pub fn spawn(interval: Duration) -> MallocStats {
+        let (stop, receiver) = channel::<()>();
+        let handle = thread::spawn(move || {
+            loop {
+                match receiver.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => report(),
+                    //the stats at the end of the run are always written
+                    _ => {
+                        report();
+                        break;
+                    }
+                }
+            }
+        });
+
+        MallocStats { stop, handle }
+    }
+
+    ///Writes the last stats and waits for the reporting thread to exit
+    pub fn stop(self) {
+        drop(self.stop);
+        self.handle.join().unwrap();
+    }
+}
+
+fn report() {
+    match read() {
+        Ok((allocated, resident)) => eprintln!("{}", summary(allocated, resident)),
+        Err(err) => eprintln!("Could not read jemalloc stats: {:#}", err)
+    }
+}
+
+///Returns the allocated and resident bytes, the epoch is advanced first since jemalloc caches its stats
+fn read() -> Result<(usize, usize)> {
+    epoch::advance().map_err(|err| anyhow!("{}", err))?;
+    let allocated = stats::allocated::read().map_err(|err| anyhow!("{}", err))?;
+    let resident = stats::resident::read().map_err(|err| anyhow!("{}", err))?;
+    Ok((allocated, resident))
+}
+
+fn summary(allocated: usize, resident: usize) -> String {
+    format!("Allocator: {:.1}MB allocated, {:.1}MB resident", to_megabytes(allocated), to_megabytes(resident))
+}
+
+fn to_megabytes(bytes: usize) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(summary(3 * 1024 * 1024 / 2, 4 * 1024 * 1024), "Allocator: 1.5MB allocated, 4.0MB resident");
+        let (allocated, resident) = read().unwrap();
+        assert!(allocated > 0 && resident > 0);
+    }
+}
@@ -1,10 +1,15 @@
 use std::path::{PathBuf, Path};
-use std::fs::OpenOptions;
-use std::io::prelude::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, SeekFrom, Take, prelude::*};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::cmp;
 use serde::{Serialize, Deserialize};
 use bincode;
 use anyhow::{Context, Result, anyhow};
 use super::json_line::to_json_line;
+use super::retry::with_retries;
+use super::sorted_merge::{SortedMerge, Run};
 
 ///Contains values and metadata for a map key
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -12,26 +17,53 @@ pub struct MapContainer {
     pub values: Vec<String>,
     pub buffered_size: usize,
     encoded_key: String,
+    key_encoding: KeyEncoding,
     last_part_size: usize,
     last_part_sequence: usize,
     lines_per_part: Vec<usize>,
-    total_parts: usize
+    checksum_per_part: Vec<u64>,
+    values_per_part: Vec<usize>,
+    ///the start and end byte of each part in the single file of the key, empty when every part has its own file
+    part_ranges: Vec<(u64, u64)>,
+    total_parts: usize,
+    ///the first seen form of a key that is grouped by a normalized form, none if they are the same
+    display_key: Option<String>
 }
 
 impl MapContainer {
     ///Creates a new empty container for a key
     pub fn new(key: &str) -> MapContainer {
+        let (encoded_key, key_encoding) = encode_key(key);
         MapContainer {
-            encoded_key: base64::encode(key),
+            encoded_key,
+            key_encoding,
             values: vec![],
             buffered_size: 0,
             last_part_size: 0,
             last_part_sequence: 0,
             lines_per_part: vec![],
-            total_parts: 0
+            checksum_per_part: vec![],
+            values_per_part: vec![],
+            part_ranges: vec![],
+            total_parts: 0,
+            display_key: None
         }
     }
 
+    ///Creates a new empty container for a key that is grouped by the normalized form of display_key
+    pub fn with_display_key(key: &str, display_key: &str) -> MapContainer {
+        let mut container = MapContainer::new(key);
+        if key != display_key {
+            container.display_key = Some(display_key.to_owned());
+        }
+        container
+    }
+
+    ///Returns the key as it is printed, key is the normalized key of the container
+    pub fn display_key<'a>(&'a self, key: &'a str) -> &'a str {
+        self.display_key.as_deref().unwrap_or(key)
+    }
+
     ///Serializes a container to bytes
     pub fn serialize(container: &MapContainer) -> Result<Vec<u8>> {
         bincode::serialize(&container).context("Could not serialize container")
@@ -60,7 +92,11 @@ impl MapContainer {
         self.last_part_sequence = other.last_part_sequence;
         self.last_part_size = other.last_part_size;
         self.lines_per_part = other.lines_per_part;
+        self.checksum_per_part = other.checksum_per_part;
+        self.values_per_part = other.values_per_part;
+        self.part_ranges = other.part_ranges;
         self.total_parts = other.total_parts;
+        self.display_key = other.display_key;
         self.add_values(other.values);
     }
 
@@ -91,50 +127,257 @@ impl MapContainer {
         }
     }
 
+    ///Returns the value count for a part number, if the part does not exist an error will be returned
+    pub fn part_value_count(&self, part: usize) -> Result<usize> {
+        match self.values_per_part.get(part) {
+            Some(c) => Ok(*c),
+            None => Err(anyhow!("Part {} does not exist", part))
+        }
+    }
+
     ///Constructs a file path for a part number, if the part does not exist an error will be returned
     pub fn part_file_path(&self, dir: &PathBuf, part: usize) -> Result<String> {
         if part > self.last_part_sequence {
             Err(anyhow!("Part {} does not exist", part))
+        } else if !self.part_ranges.is_empty() {
+            Ok(self.single_file_path(dir))
         } else {
-            Ok(format!("{}/{}.map.{}.jsonl", dir.display(), &self.encoded_key, part))
+            match self.key_encoding {
+                KeyEncoding::Percent => Ok(format!("{}/{}.map.{}.jsonl", dir.display(), &self.encoded_key, part)),
+                KeyEncoding::Base64 => Ok(format!("{}/{}.b64.map.{}.jsonl", dir.display(), &self.encoded_key, part))
+            }
+        }
+    }
+
+    ///Constructs the path of the file that holds all the parts of the key with --single-file-parts
+    fn single_file_path(&self, dir: &PathBuf) -> String {
+        match self.key_encoding {
+            KeyEncoding::Percent => format!("{}/{}.map.jsonl", dir.display(), &self.encoded_key),
+            KeyEncoding::Base64 => format!("{}/{}.b64.map.jsonl", dir.display(), &self.encoded_key)
+        }
+    }
+
+    ///Opens a part for reading, a part of a single file is read only within its byte range.  
+    ///A part smaller than buffer_size gets a buffer of its own size so it is read whole by the first read
+    pub fn read_part(&self, dir: &PathBuf, part: usize, buffer_size: usize) -> Result<BufReader<Take<File>>> {
+        let file_path = self.part_file_path(dir, part)?;
+        let mut file = File::open(&file_path).with_context(|| format!("Could not open file part: {}", file_path))?;
+        let (start, end) = match self.part_ranges.get(part) {
+            Some(range) => *range,
+            None => (0, file.metadata().with_context(|| format!("Could not read file part: {}", file_path))?.len())
+        };
+        file.seek(SeekFrom::Start(start)).with_context(|| format!("Could not seek file part: {}", file_path))?;
+        let capacity = cmp::max(cmp::min(buffer_size as u64, end - start), 1) as usize;
+        Ok(BufReader::with_capacity(capacity, file.take(end - start)))
+    }
+
+    ///Re-reads a part file and checks it against the checksum recorded while writing it
+    pub fn verify_part(&self, dir: &PathBuf, part: usize) -> Result<()> {
+        let file_path = self.part_file_path(dir, part)?;
+        let expected = match self.checksum_per_part.get(part) {
+            Some(checksum) => *checksum,
+            None => return Err(anyhow!("Part {} does not exist", part))
+        };
+        let mut reader = self.read_part(dir, part, DEFAULT_BUFFER_SIZE)?;
+        let mut line = String::new();
+        let mut checksum = 0;
+        while reader.read_line(&mut line).with_context(|| format!("Could not read file part: {}", file_path))? > 0 {
+            checksum = chain_checksum(checksum, &line);
+            line.clear();
+        }
+        if checksum != expected {
+            return Err(anyhow!("File part is corrupted: {}", file_path));
         }
+
+        Ok(())
     }
 
-    ///Flushes the indexed values to their own file while creating new file parts as needed based on max_part_size.
-    pub fn flush_to_file_part(&mut self, directory: &PathBuf, max_part_size: usize) -> Result<()> {
+    ///Flushes the indexed values to their own file while creating new file parts as needed based on max_part_size.  
+    ///Once max_parts is reached the last part keeps growing instead, unless sorted is set.  
+    ///With single_file all the parts are appended to the same file and their byte ranges are kept instead.  
+    ///With sorted the values must already be sorted numerically and they are merged into the sorted last part instead of appended to it.  
+    ///Returns the number of bytes written.
+    pub fn flush_to_file_part(&mut self, directory: &PathBuf, max_part_size: usize, max_parts: usize, io_retries: usize, single_file: bool, sorted: bool) -> Result<usize> {
+        if single_file {
+            return self.flush_to_single_file(directory, max_part_size, max_parts, io_retries);
+        }
         //serialize
         let json_line = to_json_line(&self.values);
         //create the file if needed and open it
         let mut file_path = self.part_file_path(directory, self.last_part_sequence)?;
         let mut file = if !Path::new(&file_path).exists() {
             self.lines_per_part.push(1);
+            self.checksum_per_part.push(0);
+            self.values_per_part.push(self.values.len());
             self.total_parts += 1;
-            OpenOptions::new().create(true).append(true).open(&file_path).with_context(|| format!("Could not open file part: {}", file_path))?
+            open_part(&file_path, true, io_retries)?
         } else {
             //check size and use a new file part if needed
-            if json_line.len() + self.last_part_size >= max_part_size {
+            //sorted parts always roll over, merging into a last part that keeps growing would rewrite it on every flush
+            if json_line.len() + self.last_part_size >= max_part_size && (self.total_parts < max_parts || sorted) {
                 self.last_part_sequence += 1;
                 self.last_part_size = 0;
                 file_path = self.part_file_path(directory, self.last_part_sequence)?;
                 self.lines_per_part.push(1);
+                self.checksum_per_part.push(0);
+                self.values_per_part.push(self.values.len());
                 self.total_parts += 1;
-                OpenOptions::new().create(true).append(true).open(&file_path).with_context(|| format!("Could not open file part: {}", file_path))?
+                open_part(&file_path, true, io_retries)?
+            } else if sorted {
+                return self.merge_into_last_part(&file_path, io_retries);
             } else {
                 self.lines_per_part[self.last_part_sequence] += 1;
-                OpenOptions::new().append(true).open(&file_path).with_context(|| format!("Could not open file part: {}", file_path))?
+                self.values_per_part[self.last_part_sequence] += self.values.len();
+                open_part(&file_path, false, io_retries)?
             }
         };
         //write and reset
-        file.write_all(&json_line.as_bytes()).with_context(|| format!("Could not write to file part: {}", file_path))?;
-        file.sync_all().with_context(|| format!("Could not fsync file part: {}", file_path))?;
+        //a failed attempt can leave a partial line behind so the part is truncated back before every attempt
+        let part_size = file.metadata().with_context(|| format!("Could not read file part: {}", file_path))?.len();
+        with_retries(io_retries, || {
+            file.set_len(part_size).with_context(|| format!("Could not truncate file part: {}", file_path))?;
+            file.write_all(&json_line.as_bytes()).with_context(|| format!("Could not write to file part: {}", file_path))?;
+            file.sync_all().with_context(|| format!("Could not fsync file part: {}", file_path))
+        })?;
         self.last_part_size += json_line.len();
+        self.checksum_per_part[self.last_part_sequence] = chain_checksum(self.checksum_per_part[self.last_part_sequence], &json_line);
         self.values = Vec::new();
         self.buffered_size = 0;
 
-        Ok(())
+        Ok(json_line.len())
+    }
+
+    ///Flushes the indexed values to the single file of the key, a new part starts where the previous one ends
+    fn flush_to_single_file(&mut self, directory: &PathBuf, max_part_size: usize, max_parts: usize, io_retries: usize) -> Result<usize> {
+        let json_line = to_json_line(&self.values);
+        let file_path = self.single_file_path(directory);
+        let mut file = open_part(&file_path, true, io_retries)?;
+        let file_size = file.metadata().with_context(|| format!("Could not read file part: {}", file_path))?.len();
+        if self.total_parts == 0 || (json_line.len() + self.last_part_size >= max_part_size && self.total_parts < max_parts) {
+            if self.total_parts > 0 {
+                self.last_part_sequence += 1;
+                self.last_part_size = 0;
+            }
+            self.lines_per_part.push(1);
+            self.checksum_per_part.push(0);
+            self.values_per_part.push(self.values.len());
+            self.part_ranges.push((file_size, file_size));
+            self.total_parts += 1;
+        } else {
+            self.lines_per_part[self.last_part_sequence] += 1;
+            self.values_per_part[self.last_part_sequence] += self.values.len();
+        }
+        //a failed attempt can leave a partial line behind so the file is truncated back before every attempt
+        with_retries(io_retries, || {
+            file.set_len(file_size).with_context(|| format!("Could not truncate file part: {}", file_path))?;
+            file.write_all(&json_line.as_bytes()).with_context(|| format!("Could not write to file part: {}", file_path))?;
+            file.sync_all().with_context(|| format!("Could not fsync file part: {}", file_path))
+        })?;
+        self.last_part_size += json_line.len();
+        self.part_ranges[self.last_part_sequence].1 = file_size + json_line.len() as u64;
+        self.checksum_per_part[self.last_part_sequence] = chain_checksum(self.checksum_per_part[self.last_part_sequence], &json_line);
+        self.values = Vec::new();
+        self.buffered_size = 0;
+
+        Ok(json_line.len())
+    }
+
+    ///Merges the sorted indexed values with the sorted last part into a temp file that then replaces the part.  
+    ///The merged lines are about as large as the flushed values, only one line of the old part is kept in memory
+    fn merge_into_last_part(&mut self, file_path: &str, io_retries: usize) -> Result<usize> {
+        let temp_path = format!("{}.sorting", file_path);
+        let line_size = self.buffered_size.max(1);
+        let (lines, checksum, written) = with_retries(io_retries, || {
+            let part = BufReader::new(File::open(file_path).with_context(|| format!("Could not open file part: {}", file_path))?);
+            let mut merge = SortedMerge::new(&self.encoded_key, vec![Run::lines(part), Run::values(self.values.clone())])?;
+            let file = File::create(&temp_path).with_context(|| format!("Could not create file part: {}", temp_path))?;
+            let mut writer = BufWriter::new(file);
+            let (mut lines, mut checksum, mut written) = (0, 0, 0);
+            let mut line = vec![];
+            let mut line_bytes = 0;
+            loop {
+                let value = merge.next().with_context(|| format!("Could not read file part: {}", file_path))?;
+                let is_done = value.is_none();
+                if let Some(value) = value {
+                    line_bytes += value.len();
+                    line.push(value);
+                }
+                if (is_done && !line.is_empty()) || line_bytes >= line_size {
+                    let json_line = to_json_line(&line);
+                    writer.write_all(json_line.as_bytes()).with_context(|| format!("Could not write to file part: {}", temp_path))?;
+                    checksum = chain_checksum(checksum, &json_line);
+                    written += json_line.len();
+                    lines += 1;
+                    line.clear();
+                    line_bytes = 0;
+                }
+                if is_done {
+                    break;
+                }
+            }
+            let file = writer.into_inner().map_err(|err| anyhow!("Could not write to file part: {}: {}", temp_path, err))?;
+            file.sync_all().with_context(|| format!("Could not fsync file part: {}", temp_path))?;
+            Ok((lines, checksum, written))
+        })?;
+        fs::rename(&temp_path, file_path).with_context(|| format!("Could not replace file part: {}", file_path))?;
+        self.lines_per_part[self.last_part_sequence] = lines;
+        self.checksum_per_part[self.last_part_sequence] = checksum;
+        self.values_per_part[self.last_part_sequence] += self.values.len();
+        self.last_part_size = written;
+        self.values = Vec::new();
+        self.buffered_size = 0;
+
+        Ok(written)
     }
 }
 
+///The read buffer size of a part when none is given
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+///Opens a file part for appending, create is needed for a new part
+fn open_part(file_path: &str, create: bool, io_retries: usize) -> Result<File> {
+    with_retries(io_retries, || {
+        OpenOptions::new().create(create).append(true).open(file_path).with_context(|| format!("Could not open file part: {}", file_path))
+    })
+}
+
+///Keys up to this length are used in filenames directly
+const MAX_PERCENT_ENCODED_KEY: usize = 64;
+
+///How the key is encoded in the file part names
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+enum KeyEncoding {
+    ///only the bytes that are unsafe for filenames are percent-encoded
+    Percent,
+    ///the whole key is base64 encoded, used for long keys
+    Base64
+}
+
+///Encodes a key to a filename safe string.  
+///Percent-encoded keys never contain a '.' so they cannot collide with the ".b64" suffix of base64 encoded keys
+fn encode_key(key: &str) -> (String, KeyEncoding) {
+    if key.len() > MAX_PERCENT_ENCODED_KEY {
+        return (base64::encode_config(key, base64::URL_SAFE), KeyEncoding::Base64);
+    }
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    (encoded, KeyEncoding::Percent)
+}
+
+///Chains the checksum of a part with a line appended to it
+fn chain_checksum(checksum: u64, line: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    checksum.hash(&mut hasher);
+    line.hash(&mut hasher);
+    hasher.finish()
+}
+
 pub struct Parts {
     current: usize,
     total: usize
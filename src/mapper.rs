@@ -1,46 +1,320 @@
-use std::io::BufRead;
-use anyhow::Result;
-use std::sync::{mpsc::Sender, Arc};
+use std::io::{BufRead, Write};
+use std::fmt;
+use std::error::Error;
+use std::collections::BTreeMap;
+use anyhow::{Result, Context as _, anyhow};
+use std::sync::{mpsc::{Sender, channel}, Arc};
 use super::thread_pool::ThreadPool;
-use super::indexer::IndexGuard;
-use super::js::{MapResult, ContextBuilder};
+use super::indexer::{IndexGuard, take_ready};
+use super::semaphore::Semaphore;
+use super::js::{MapResult, ContextBuilder, Context, ChunkPosition};
+use super::empty_trace::EmptyTrace;
+use super::line_transform::LineTransform;
+use super::progress::Progress;
+use super::printer::OutputTerminator;
 
-///Reads from reader -> runs map -> sends results to the indexing channel
+///Reads from reader -> runs map -> sends results to the indexing channel.  
+///Returns the number of lines read
 pub fn map<T: BufRead>(
     reader: &mut T,
     pool: ThreadPool,
-    sender: Sender<Vec<MapResult>>,
+    sender: Sender<(usize, Vec<MapResult>)>,
     index_guard: IndexGuard,
     context_builder: Arc<ContextBuilder>,
-    read_buffer_size: usize
-) -> Result<()> {
-    let mut buf = String::with_capacity(read_buffer_size);
+    options: MapOptions
+) -> Result<usize> {
+    let MapOptions { chunk_size, keep_empty_lines, empty_trace, line_transforms, input_format, progress, offset_mode } = options;
+    let mut buf = chunk_size.new_buffer();
     let mut current_line = 0;
-    while reader.read_line(& mut buf)? > 0 {
+    let mut buffered_lines = 0;
+    let mut sequence = 0;
+    let mut bytes_read = 0;
+    let mut chunk_start = 0;
+    loop {
+        let read = input_format.read_record(reader, &mut buf)?;
+        if read == 0 {
+            break;
+        }
         current_line += 1;
-        if buf.len() >= read_buffer_size {
+        buffered_lines += 1;
+        bytes_read += read;
+        if chunk_size.is_full(&buf, buffered_lines) {
+            buffered_lines = 0;
+            let position = offset_mode.position(current_line, chunk_start);
+            chunk_start = bytes_read;
             let current_buf: String = buf.drain(..).collect();
             let context_builder = context_builder.clone();
             let sender = sender.clone();
+            let empty_trace = empty_trace.clone();
+            let line_transforms = line_transforms.clone();
+            if let Some(progress) = &progress {
+                progress.lines_read(current_line);
+            }
             index_guard.wait_while_indexing();
             pool.execute(move|| {
                 //create 1 js context per thread
                 context_builder.reuse(|context| {
-                    let result = context.run_map(current_line, &current_buf).unwrap();
-                    sender.send(result).unwrap();
+                    let result = context.run_map(position, &current_buf, keep_empty_lines, &line_transforms).unwrap();
+                    if let Some(empty_trace) = &empty_trace {
+                        empty_trace.record(result.is_empty(), || chunk_lines(current_line, &current_buf));
+                    }
+                    sender.send((sequence, result)).unwrap();
                 });
             });
+            sequence += 1;
         }
     }
+    if let Some(progress) = &progress {
+        progress.lines_read(current_line);
+    }
     //leftovers
     if buf.len() > 0 {
         let context_builder = context_builder.clone();
+        let position = offset_mode.position(current_line, chunk_start);
         pool.execute(move|| {
             //create js context
             let context = context_builder.build().unwrap();
-            sender.send(context.run_map(current_line, &buf).unwrap()).unwrap();
+            let result = context.run_map(position, &buf, keep_empty_lines, &line_transforms).unwrap();
+            if let Some(empty_trace) = &empty_trace {
+                empty_trace.record(result.is_empty(), || chunk_lines(current_line, &buf));
+            }
+            sender.send((sequence, result)).unwrap();
         });
     }
 
+    Ok(current_line)
+}
+
+///Settings of the map tasks
+pub struct MapOptions {
+    pub chunk_size: ChunkSize,
+    ///pass empty input lines to map() instead of skipping them
+    pub keep_empty_lines: bool,
+    ///count the map tasks that emitted nothing
+    pub empty_trace: Option<EmptyTrace>,
+    ///applied in order to every line before map() sees it
+    pub line_transforms: Arc<Vec<LineTransform>>,
+    pub input_format: InputFormat,
+    pub progress: Option<Progress>,
+    pub offset_mode: OffsetMode
+}
+
+///What map() gets as the key of each line
+#[derive(Clone, Copy)]
+pub enum OffsetMode {
+    ///the line number, starting from 1
+    Lines,
+    ///the byte offset of the start of the line in the input, starting from 0
+    Bytes
+}
+
+impl OffsetMode {
+    pub fn new(mode: &str) -> OffsetMode {
+        if mode == "bytes" {
+            OffsetMode::Bytes
+        } else {
+            OffsetMode::Lines
+        }
+    }
+
+    ///The position of a chunk that ends at last_line and starts at first_byte of the input
+    fn position(self, last_line: usize, first_byte: usize) -> ChunkPosition {
+        match self {
+            OffsetMode::Lines => ChunkPosition::LastLine(last_line),
+            OffsetMode::Bytes => ChunkPosition::FirstByte(first_byte)
+        }
+    }
+}
+
+///How the input is split into the records passed to map()
+#[derive(Clone, Copy)]
+pub enum InputFormat {
+    ///newline delimited text
+    Lines,
+    ///a 4-byte big-endian length followed by that many bytes, map() gets each record base64 encoded
+    LengthPrefixed
+}
+
+impl InputFormat {
+    pub fn new(format: &str) -> InputFormat {
+        match format {
+            "length-prefixed" => InputFormat::LengthPrefixed,
+            _ => InputFormat::Lines
+        }
+    }
+
+    ///Appends the next record to buf as a single line.  
+    ///Returns the number of bytes read, 0 at the end of the input
+    fn read_record<T: BufRead>(self, reader: &mut T, buf: &mut String) -> Result<usize> {
+        match self {
+            InputFormat::Lines => Ok(reader.read_line(buf)?),
+            InputFormat::LengthPrefixed => {
+                if reader.fill_buf()?.is_empty() {
+                    return Ok(0);
+                }
+                let mut header = [0; 4];
+                reader.read_exact(&mut header).context("Truncated record length")?;
+                let length = u32::from_be_bytes(header) as usize;
+                let mut record = vec![0; length];
+                reader.read_exact(&mut record).map_err(|err| anyhow!("Truncated record of {} bytes: {}", length, err))?;
+                //base64 never contains a newline so the chunking and line numbering of lines still apply
+                base64::encode_config_buf(&record, base64::STANDARD, buf);
+                buf.push('\n');
+                Ok(header.len() + length)
+            }
+        }
+    }
+}
+
+///Describes the input lines of a map task that ends at last_line
+fn chunk_lines(last_line: usize, buf: &str) -> String {
+    let first_line = last_line + 1 - buf.lines().count();
+    format!("lines {}-{}", first_line, last_line)
+}
+
+///How much of the input is passed to each map task
+#[derive(Clone, Copy)]
+pub enum ChunkSize {
+    ///at least this many bytes, the last line of a chunk is always read whole
+    Bytes(usize),
+    ///exactly this many lines, gives more even tasks when the line lengths vary a lot
+    Lines(usize)
+}
+
+impl ChunkSize {
+    fn new_buffer(self) -> String {
+        match self {
+            ChunkSize::Bytes(read_buffer_size) => String::with_capacity(read_buffer_size),
+            ChunkSize::Lines(_) => String::new()
+        }
+    }
+
+    fn is_full(self, buf: &str, buffered_lines: usize) -> bool {
+        match self {
+            ChunkSize::Bytes(read_buffer_size) => buf.len() >= read_buffer_size,
+            ChunkSize::Lines(lines_per_task) => buffered_lines >= lines_per_task
+        }
+    }
+}
+
+///Reads from reader -> runs map -> writes the lines that map() kept to writer, used with --passthrough.  
+///The lines are written in the input order and ended by the terminator, nothing is indexed or reduced.  
+///Returns the number of lines read
+pub fn filter<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    pool: ThreadPool,
+    context_builder: Arc<ContextBuilder>,
+    options: FilterOptions
+) -> Result<usize> {
+    let FilterOptions { chunk_size, keep_empty_lines, max_inflight, terminator } = options;
+    let (sender, receiver) = channel();
+    //limits how much of the input is read ahead of the written lines
+    let inflight = Semaphore::new(max_inflight);
+    let mut pending = BTreeMap::new();
+    let mut next_sequence = 0;
+    let mut buf = chunk_size.new_buffer();
+    let mut current_line = 0;
+    let mut buffered_lines = 0;
+    let mut sequence = 0;
+    loop {
+        let has_read = reader.read_line(&mut buf)? > 0;
+        if has_read {
+            current_line += 1;
+            buffered_lines += 1;
+        }
+        //the leftovers are sent after the last line
+        if (has_read && chunk_size.is_full(&buf, buffered_lines)) || (!has_read && !buf.is_empty()) {
+            buffered_lines = 0;
+            let current_buf: String = buf.drain(..).collect();
+            let context_builder = context_builder.clone();
+            let sender = sender.clone();
+            let permit = inflight.acquire();
+            pool.execute(move|| {
+                let _permit = permit;
+                context_builder.reuse(|context| {
+                    //the receiver is gone if an earlier task failed
+                    let _ = sender.send((sequence, context.run_filter(current_line, &current_buf, keep_empty_lines)));
+                });
+            });
+            sequence += 1;
+        }
+        if !has_read {
+            break;
+        }
+        for (sequence, kept) in receiver.try_iter() {
+            write_kept(writer, &terminator, take_ready(&mut pending, &mut next_sequence, sequence, kept))?;
+        }
+    }
+    drop(sender);
+    for (sequence, kept) in receiver.iter() {
+        write_kept(writer, &terminator, take_ready(&mut pending, &mut next_sequence, sequence, kept))?;
+    }
+
+    Ok(current_line)
+}
+
+///Settings of the --passthrough filter
+pub struct FilterOptions {
+    pub chunk_size: ChunkSize,
+    ///pass empty input lines to map() instead of skipping them
+    pub keep_empty_lines: bool,
+    ///how many map tasks can be read ahead of the written lines
+    pub max_inflight: usize,
+    ///ends each kept line
+    pub terminator: OutputTerminator
+}
+
+fn write_kept<W: Write>(writer: &mut W, terminator: &OutputTerminator, ready: Vec<Result<Vec<String>>>) -> Result<()> {
+    for kept in ready {
+        for line in kept? {
+            terminator.write_row(writer, &line)?;
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+///Runs map() on each of the first max_lines lines and writes the errors it throws to stderr, the emitted results are discarded.  
+///Returns the number of lines read and how many of them failed
+pub fn dry_parse<T: BufRead>(
+    reader: &mut T,
+    context: &Context,
+    max_lines: usize,
+    keep_empty_lines: bool,
+    line_transforms: &[LineTransform],
+    input_format: InputFormat,
+    offset_mode: OffsetMode
+) -> Result<(usize, usize)> {
+    let mut buf = String::new();
+    let mut current_line = 0;
+    let mut failed = 0;
+    let mut bytes_read = 0;
+    while current_line < max_lines {
+        let read = input_format.read_record(reader, &mut buf)?;
+        if read == 0 {
+            break;
+        }
+        current_line += 1;
+        let position = offset_mode.position(current_line, bytes_read);
+        bytes_read += read;
+        if let Err(err) = context.run_map(position, &buf, keep_empty_lines, line_transforms) {
+            eprintln!("Line {}: {:#}", current_line, err);
+            failed += 1;
+        }
+        buf.clear();
+    }
+
+    Ok((current_line, failed))
+}
+
+///The error of an empty input with --abort-on-empty-input
+#[derive(Debug)]
+pub struct EmptyInput;
+
+impl fmt::Display for EmptyInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "No input lines were read")
+    }
+}
+
+impl Error for EmptyInput {}
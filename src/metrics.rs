@@ -0,0 +1,47 @@
+use std::io::Write;
+use std::time::Duration;
+use anyhow::Result;
+
+///Counters and phase durations collected during a run
+pub struct Metrics {
+    pub lines_read: usize,
+    pub keys: usize,
+    pub file_parts: usize,
+    pub spilled_bytes: usize,
+    pub phases: Vec<(&'static str, Duration)>
+}
+
+///Writes the metrics of every stage in the prometheus text exposition format.  
+///The samples get a stage label if there is more than one stage, so the stages of a chain can share a file
+pub fn write_prometheus<T: Write>(stages: &[Metrics], writer: &mut T) -> Result<()> {
+    let labels: Vec<String> = (1..=stages.len())
+        .map(|stage| if stages.len() > 1 { format!("stage=\"{}\"", stage) } else { String::new() })
+        .collect();
+    let values = |value: fn(&Metrics) -> usize| -> Vec<usize> { stages.iter().map(value).collect() };
+    write_metric(writer, "omnimap_lines_read_total", "counter", "Input lines read", &labels, &values(|m| m.lines_read))?;
+    write_metric(writer, "omnimap_keys", "gauge", "Distinct keys emitted by map()", &labels, &values(|m| m.keys))?;
+    write_metric(writer, "omnimap_file_parts", "gauge", "File parts created for spilled keys", &labels, &values(|m| m.file_parts))?;
+    write_metric(writer, "omnimap_spilled_bytes_total", "counter", "Bytes written to file parts", &labels, &values(|m| m.spilled_bytes))?;
+    writeln!(writer, "# HELP omnimap_phase_duration_seconds Duration of each phase of the run")?;
+    writeln!(writer, "# TYPE omnimap_phase_duration_seconds gauge")?;
+    for (metrics, label) in stages.iter().zip(&labels) {
+        let label = if label.is_empty() { String::new() } else { format!("{},", label) };
+        for (phase, duration) in &metrics.phases {
+            writeln!(writer, "omnimap_phase_duration_seconds{{{}phase=\"{}\"}} {}", label, phase, duration.as_secs_f64())?;
+        }
+    }
+    Ok(())
+}
+
+fn write_metric<T: Write>(writer: &mut T, name: &str, kind: &str, help: &str, labels: &[String], values: &[usize]) -> Result<()> {
+    writeln!(writer, "# HELP {} {}", name, help)?;
+    writeln!(writer, "# TYPE {} {}", name, kind)?;
+    for (label, value) in labels.iter().zip(values) {
+        if label.is_empty() {
+            writeln!(writer, "{} {}", name, value)?;
+        } else {
+            writeln!(writer, "{}{{{}}} {}", name, label, value)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,242 @@
+use std::path::Path;
+use serde_json::Value;
+use anyhow::{Result, anyhow};
+
+///How many rows are buffered into each row group of the parquet file
+#[cfg(feature = "parquet")]
+const ROW_GROUP_SIZE: usize = 10000;
+
+///A value column of the parquet output, read from the field of the same name of each json result
+#[derive(Clone)]
+pub struct ParquetColumn {
+    pub name: String,
+    pub kind: ColumnKind
+}
+
+#[derive(Clone, Copy)]
+pub enum ColumnKind {
+    ///a string field, other json values are written as json strings
+    String,
+    Int64,
+    Double,
+    Boolean,
+    ///the whole result as it is, the column of the results when no columns are declared
+    Result
+}
+
+///A single value of a row
+#[cfg_attr(not(feature = "parquet"), allow(dead_code))]
+enum Cell {
+    String(String),
+    Int64(i64),
+    Double(f64),
+    Boolean(bool)
+}
+
+///Parses the --parquet-columns spec, a comma separated list of name:type with types string, int64, double and boolean
+pub fn parse_columns(spec: &str) -> Result<Vec<ParquetColumn>> {
+    let mut columns: Vec<ParquetColumn> = vec![];
+    for column in spec.split(',') {
+        let mut parts = column.splitn(2, ':');
+        let name = parts.next().unwrap().trim();
+        let kind = match parts.next().map(str::trim) {
+            Some("string") => ColumnKind::String,
+            Some("int64") => ColumnKind::Int64,
+            Some("double") => ColumnKind::Double,
+            Some("boolean") => ColumnKind::Boolean,
+            _ => return Err(anyhow!("Invalid column {}, expected name:type with a string, int64, double or boolean type", column))
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(anyhow!("Invalid column name {}, only letters, digits and _ are allowed", name));
+        }
+        if name == "key" || columns.iter().any(|c| c.name == name) {
+            return Err(anyhow!("Duplicate column name {}", name));
+        }
+        columns.push(ParquetColumn { name: name.to_owned(), kind });
+    }
+    Ok(columns)
+}
+
+///The columns that are written besides the key, a single column with the whole result if none are declared
+#[cfg_attr(not(feature = "parquet"), allow(dead_code))]
+fn value_columns(columns: &[ParquetColumn]) -> Vec<ParquetColumn> {
+    if columns.is_empty() {
+        vec![ParquetColumn { name: String::from("value"), kind: ColumnKind::Result }]
+    } else {
+        columns.to_vec()
+    }
+}
+
+///The cells of the value columns of a row, a missing or null field is a null cell
+#[cfg_attr(not(feature = "parquet"), allow(dead_code))]
+fn row_cells(columns: &[ParquetColumn], key: &str, result: &str) -> Result<Vec<Option<Cell>>> {
+    if let [ParquetColumn { kind: ColumnKind::Result, .. }] = columns {
+        return Ok(vec![Some(Cell::String(result.to_owned()))]);
+    }
+    let fields = match serde_json::from_str(result) {
+        Ok(Value::Object(fields)) => fields,
+        _ => return Err(anyhow!("--parquet-columns needs json object results, key {} has: {}", key, result))
+    };
+    columns.iter().map(|column| {
+        let cell = match (column.kind, fields.get(&column.name)) {
+            (_, None) | (_, Some(Value::Null)) => None,
+            (ColumnKind::String, Some(Value::String(value))) => Some(Cell::String(value.clone())),
+            (ColumnKind::String, Some(value)) => Some(Cell::String(value.to_string())),
+            (ColumnKind::Int64, Some(value)) if value.is_i64() => value.as_i64().map(Cell::Int64),
+            (ColumnKind::Double, Some(value)) if value.is_number() => value.as_f64().map(Cell::Double),
+            (ColumnKind::Boolean, Some(Value::Bool(value))) => Some(Cell::Boolean(*value)),
+            (_, Some(value)) => return Err(anyhow!("Field {} of key {} does not match its column type: {}", column.name, key, value))
+        };
+        Ok(cell)
+    }).collect()
+}
+
+///Writes the rows to a parquet file at path with a required key column and an optional column for each of columns.  
+///The rows are written in row groups of ROW_GROUP_SIZE so only a single group is kept in memory
+#[cfg(feature = "parquet")]
+pub fn write_parquet<I: Iterator<Item = Result<(String, String)>>>(path: &Path, columns: &[ParquetColumn], rows: I) -> Result<()> {
+    use std::fs::File;
+    use std::sync::Arc;
+    use anyhow::Context;
+    use parquet::file::writer::{FileWriter, RowGroupWriter, SerializedFileWriter};
+    use parquet::file::properties::WriterProperties;
+    use parquet::schema::parser::parse_message_type;
+
+    let columns = value_columns(columns);
+    let file = File::create(path).with_context(|| format!("Could not create parquet output file: {}", path.display()))?;
+    let schema = Arc::new(parse_message_type(&message_type(&columns)).context("Invalid parquet schema")?);
+    let mut writer = SerializedFileWriter::new(file, schema, Arc::new(WriterProperties::builder().build()))?;
+    let mut rows = rows.peekable();
+    while rows.peek().is_some() {
+        let mut keys = vec![];
+        let mut cells: Vec<Vec<Option<Cell>>> = columns.iter().map(|_| vec![]).collect();
+        for row in rows.by_ref().take(ROW_GROUP_SIZE) {
+            let (key, result) = row?;
+            for (column, cell) in cells.iter_mut().zip(row_cells(&columns, &key, &result)?) {
+                column.push(cell);
+            }
+            keys.push(Some(Cell::String(key)));
+        }
+        let mut row_group = writer.next_row_group()?;
+        let mut column_cells = Some(keys).into_iter().chain(cells);
+        let mut required = true;
+        while let Some(mut column_writer) = row_group.next_column()? {
+            let cells = column_cells.next().ok_or_else(|| anyhow!("Parquet schema has more columns than the rows"))?;
+            write_column(&mut column_writer, &cells, required)?;
+            row_group.close_column(column_writer)?;
+            //only the key column is required
+            required = false;
+        }
+        writer.close_row_group(row_group)?;
+    }
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "parquet"))]
+pub fn write_parquet<I: Iterator<Item = Result<(String, String)>>>(_path: &Path, _columns: &[ParquetColumn], _rows: I) -> Result<()> {
+    Err(anyhow!("omnimap was built without parquet support, build it with --features parquet to use --output-format parquet"))
+}
+
+#[cfg(feature = "parquet")]
+fn message_type(columns: &[ParquetColumn]) -> String {
+    let mut message = String::from("message omnimap {\n  REQUIRED BYTE_ARRAY key (UTF8);\n");
+    for column in columns {
+        let column_type = match column.kind {
+            ColumnKind::String | ColumnKind::Result => "BYTE_ARRAY",
+            ColumnKind::Int64 => "INT64",
+            ColumnKind::Double => "DOUBLE",
+            ColumnKind::Boolean => "BOOLEAN"
+        };
+        let annotation = match column.kind {
+            ColumnKind::String | ColumnKind::Result => " (UTF8)",
+            _ => ""
+        };
+        message.push_str(&format!("  OPTIONAL {} {}{};\n", column_type, column.name, annotation));
+    }
+    message.push('}');
+    message
+}
+
+///Writes the cells of a column, the null cells only get a definition level
+#[cfg(feature = "parquet")]
+fn write_column(column_writer: &mut parquet::column::writer::ColumnWriter, cells: &[Option<Cell>], required: bool) -> Result<()> {
+    use parquet::column::writer::ColumnWriter;
+    use parquet::data_type::ByteArray;
+
+    let def_levels: Vec<i16> = cells.iter().map(|cell| if cell.is_some() { 1 } else { 0 }).collect();
+    let def_levels = if required { None } else { Some(&def_levels[..]) };
+    match column_writer {
+        ColumnWriter::ByteArrayColumnWriter(writer) => {
+            let values: Vec<ByteArray> = cells.iter().filter_map(|cell| match cell {
+                Some(Cell::String(value)) => Some(ByteArray::from(value.as_str())),
+                _ => None
+            }).collect();
+            writer.write_batch(&values, def_levels, None)?;
+        },
+        ColumnWriter::Int64ColumnWriter(writer) => {
+            let values: Vec<i64> = cells.iter().filter_map(|cell| match cell {
+                Some(Cell::Int64(value)) => Some(*value),
+                _ => None
+            }).collect();
+            writer.write_batch(&values, def_levels, None)?;
+        },
+        ColumnWriter::DoubleColumnWriter(writer) => {
+            let values: Vec<f64> = cells.iter().filter_map(|cell| match cell {
+                Some(Cell::Double(value)) => Some(*value),
+                _ => None
+            }).collect();
+            writer.write_batch(&values, def_levels, None)?;
+        },
+        ColumnWriter::BoolColumnWriter(writer) => {
+            let values: Vec<bool> = cells.iter().filter_map(|cell| match cell {
+                Some(Cell::Boolean(value)) => Some(*value),
+                _ => None
+            }).collect();
+            writer.write_batch(&values, def_levels, None)?;
+        },
+        _ => return Err(anyhow!("Unsupported parquet column type"))
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parquet_columns() {
+        let columns = parse_columns("name:string,count:int64,avg:double,ok:boolean").unwrap();
+        assert_eq!(columns.len(), 4);
+        assert!(parse_columns("count:int32").is_err());
+        assert!(parse_columns("key:string").is_err());
+        assert!(parse_columns("a:string,a:int64").is_err());
+        let cells = row_cells(&columns, "k", r#"{"name": 1, "count": 2, "avg": 3, "ok": null}"#).unwrap();
+        assert!(matches!(&cells[0], Some(Cell::String(name)) if name == "1"));
+        assert!(matches!(cells[1], Some(Cell::Int64(2))));
+        assert!(matches!(cells[2], Some(Cell::Double(avg)) if avg == 3.0));
+        assert!(cells[3].is_none());
+        assert!(row_cells(&columns, "k", r#"{"count": 1.5}"#).is_err());
+        assert!(row_cells(&columns, "k", "5").is_err());
+        let cells = row_cells(&value_columns(&[]), "k", "5").unwrap();
+        assert!(matches!(&cells[0], Some(Cell::String(value)) if value == "5"));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_write_parquet() {
+        use std::fs::{self, File};
+        use std::env::temp_dir;
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+        use uuid::Uuid;
+
+        let path = temp_dir().join(format!("omnimap-test-{}.parquet", Uuid::new_v4()));
+        let columns = parse_columns("count:int64,name:string").unwrap();
+        let rows = (0..25000).map(|n| Ok((format!("key{}", n), format!("{{\"count\": {}}}", n))));
+        write_parquet(&path, &columns, rows).unwrap();
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 25000);
+        assert_eq!(reader.metadata().num_row_groups(), 3);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,11 +1,274 @@
 use std::io::Write;
+use std::cell::Cell;
+use std::collections::BTreeMap;
 use anyhow::Result;
 use super::result_table::{ResultTable, ResultsOrdering};
+use super::index::Index;
+use super::map_container::ContainerState;
+use super::json_line::from_json;
+use super::js::Context;
 
-///Writes the entries in ResultTable to the writer in the format of "key\tvalue\n"
-pub fn print<T: Write>(writer: &mut T, result_table: &ResultTable, order: &str) -> Result<()> {
-    for (key, result) in result_table.iter(ResultsOrdering::new(order)) {
-        writer.write_all(format!("{}\t{}\n", key, result).as_bytes())?;
+///Writes the entries in ResultTable to the writer in the format of "key\tvalue" and the row terminator.  
+///Empty values are replaced with null_value
+pub fn print<T: Write>(writer: &mut T, result_table: &ResultTable, order: &str, null_value: &str, format: &ResultFormat) -> Result<()> {
+    for pair in result_table.iter(ResultsOrdering::new(order)) {
+        let (key, result) = pair?;
+        let result = format.apply(result)?;
+        let result = if result.is_empty() { null_value } else { &result };
+        format.terminator.write_row(writer, &format!("{}\t{}", key, result))?;
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+///Writes the entries in ResultTable to the writer as json lines of {key, result, values}.  
+///The entries are expected to hold the result along its retained values
+pub fn print_with_values<T: Write>(writer: &mut T, result_table: &ResultTable, order: &str, format: &ResultFormat) -> Result<()> {
+    for pair in result_table.iter(ResultsOrdering::new(order)) {
+        let (key, entry) = pair?;
+        format.terminator.write_row(writer, &with_values_json(&key, &entry, format)?.to_string())?;
+    }
+    Ok(())
+}
+
+///Writes the --top-k or --order custom entries in the order they are given, as key\tresult lines or as json lines with their values.  
+///Empty values are replaced with null_value
+pub fn print_entries<T: Write>(
+    writer: &mut T,
+    entries: Vec<(String, String)>,
+    with_values: bool,
+    null_value: &str,
+    format: &ResultFormat
+) -> Result<()> {
+    for (key, entry) in entries {
+        if with_values {
+            format.terminator.write_row(writer, &with_values_json(&key, &entry, format)?.to_string())?;
+        } else {
+            let entry = format.apply(entry)?;
+            let entry = if entry.is_empty() { null_value } else { &entry };
+            format.terminator.write_row(writer, &format!("{}\t{}", key, entry))?;
+        }
+    }
+    Ok(())
+}
+
+///The entries of each bucket sorted by bucket name
+pub type Buckets = BTreeMap<String, Vec<(String, String)>>;
+
+///Writes the entries of each bucket under a "# bucket" header line, with values the json lines get a bucket field instead
+pub fn print_buckets<T: Write>(
+    writer: &mut T,
+    buckets: Buckets,
+    with_values: bool,
+    null_value: &str,
+    format: &ResultFormat
+) -> Result<()> {
+    for (bucket, entries) in buckets {
+        if with_values {
+            for (key, entry) in entries {
+                let mut line = with_values_json(&key, &entry, format)?;
+                line["bucket"] = serde_json::json!(bucket);
+                format.terminator.write_row(writer, &line.to_string())?;
+            }
+        } else {
+            format.terminator.write_row(writer, &format!("# {}", bucket))?;
+            print_entries(writer, entries, false, null_value, format)?;
+        }
+    }
+    Ok(())
+}
+
+fn with_values_json(key: &str, entry: &str, format: &ResultFormat) -> Result<serde_json::Value> {
+    let mut entry: serde_json::Value = from_json(entry)?;
+    let result = if format.is_raw() {
+        entry["result"].clone()
+    } else {
+        serde_json::json!(format.apply(entry["result"].as_str().unwrap_or_default().to_owned())?)
+    };
+    let mut line = serde_json::json!({"key": key, "result": result});
+    //the retained values and the provenance fields are only there if they were saved
+    for field in &["values", "partition", "parts"] {
+        if let Some(value) = entry.get_mut(*field) {
+            line[*field] = value.take();
+        }
+    }
+    Ok(line)
+}
+
+///How the results are formatted when they are printed, the stored results are not changed
+pub struct ResultFormat<'a> {
+    ///the js transform() if it is defined
+    pub transform: Option<&'a Context>,
+    ///round the float results, and the floats inside json results, to this many decimals
+    pub float_precision: Option<usize>,
+    pub terminator: &'a OutputTerminator
+}
+
+///How each printed row ends, set with --output-terminator
+pub enum TerminatorMode {
+    Newline,
+    ///a NUL byte, for tools that split on it like `xargs -0`
+    Nul,
+    ///the rows are separated by newlines but the last one has no terminator
+    None
+}
+
+///Ends the printed rows, shared by every printer of a run so a separator is only written between rows
+pub struct OutputTerminator {
+    mode: TerminatorMode,
+    printed: Cell<bool>
+}
+
+impl OutputTerminator {
+    pub fn new(mode: &str) -> OutputTerminator {
+        let mode = match mode {
+            "nul" => TerminatorMode::Nul,
+            "none" => TerminatorMode::None,
+            _ => TerminatorMode::Newline
+        };
+        OutputTerminator {
+            mode,
+            printed: Cell::new(false)
+        }
+    }
+
+    ///Writes a row and its terminator
+    pub fn write_row<T: Write>(&self, writer: &mut T, row: &str) -> Result<()> {
+        match self.mode {
+            TerminatorMode::Newline => writer.write_all(format!("{}\n", row).as_bytes())?,
+            TerminatorMode::Nul => writer.write_all(format!("{}\0", row).as_bytes())?,
+            TerminatorMode::None => {
+                if self.printed.replace(true) {
+                    writer.write_all(b"\n")?;
+                }
+                writer.write_all(row.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ResultFormat<'a> {
+    ///Checks if the results are printed as they are stored
+    fn is_raw(&self) -> bool {
+        self.transform.is_none() && self.float_precision.is_none()
+    }
+
+    ///Applies the optional js transform() to a result and then rounds its floats
+    fn apply(&self, result: String) -> Result<String> {
+        let result = match self.transform {
+            Some(context) => context.run_transform(&result)?,
+            None => result
+        };
+        match self.float_precision {
+            Some(precision) => Ok(round_floats(result, precision)),
+            None => Ok(result)
+        }
+    }
+}
+
+///Rounds a float result to precision decimals, or the floats inside a json object or array.  
+///Integers and other results are returned as they are
+fn round_floats(result: String, precision: usize) -> String {
+    let trimmed = result.trim();
+    if trimmed.contains(&['.', 'e', 'E'][..]) {
+        if let Ok(number) = trimmed.parse::<f64>() {
+            if number.is_finite() {
+                return format!("{:.*}", precision, number);
+            }
+        }
+    }
+    if trimmed.starts_with('{') || trimmed.starts_with('[') {
+        if let Ok(mut value) = serde_json::from_str::<serde_json::Value>(trimmed) {
+            //only reserialized if something was rounded, which also sorts the keys of the objects
+            if round_json_floats(&mut value, precision) {
+                return value.to_string();
+            }
+        }
+    }
+    result
+}
+
+///Rounds the float numbers of a json value in place, returns true if it found any
+fn round_json_floats(value: &mut serde_json::Value, precision: usize) -> bool {
+    match value {
+        serde_json::Value::Number(number) if number.is_f64() => {
+            let scale = 10f64.powi(precision as i32);
+            let rounded = number.as_f64().map(|n| (n * scale).round() / scale).and_then(serde_json::Number::from_f64);
+            if let Some(rounded) = rounded {
+                *number = rounded;
+            }
+            true
+        },
+        //every item is visited, not only up to the first float
+        serde_json::Value::Array(items) => {
+            let mut found = false;
+            for item in items {
+                found |= round_json_floats(item, precision);
+            }
+            found
+        },
+        serde_json::Value::Object(fields) => {
+            let mut found = false;
+            for field in fields.values_mut() {
+                found |= round_json_floats(field, precision);
+            }
+            found
+        },
+        _ => false
+    }
+}
+
+///Writes the finalReduce() result in the same format as the key results, it has no values.  
+///An empty result is replaced with null_value
+pub fn print_final_result<T: Write>(
+    writer: &mut T,
+    key: &str,
+    result: &str,
+    with_values: bool,
+    null_value: &str,
+    format: &ResultFormat
+) -> Result<()> {
+    let result = &format.apply(result.to_owned())?;
+    if with_values {
+        let line = serde_json::json!({"key": key, "result": result, "values": []});
+        format.terminator.write_row(writer, &line.to_string())?;
+    } else {
+        let result = if result.is_empty() { null_value } else { result };
+        format.terminator.write_row(writer, &format!("{}\t{}", key, result))?;
+    }
+    Ok(())
+}
+
+///Returns the result of a ResultTable entry, with values the entry also holds the input values of the key
+pub fn entry_result(entry: String, with_values: bool) -> Result<String> {
+    if with_values {
+        let entry: serde_json::Value = from_json(&entry)?;
+        Ok(entry["result"].as_str().unwrap_or_default().to_owned())
+    } else {
+        Ok(entry)
+    }
+}
+
+///Writes the distinct keys in Index to the writer, one per row
+pub fn print_keys<T: Write>(writer: &mut T, index: &Index, order: &str, terminator: &OutputTerminator) -> Result<()> {
+    for pair in index.iter_ordered(ResultsOrdering::new(order)) {
+        let (key, container) = pair?;
+        if let ContainerState::NoData = container.state() {
+            continue;
+        }
+        terminator.write_row(writer, container.display_key(&key))?;
+    }
+    Ok(())
+}
+
+///Writes the keys of the in memory groups or results to the writer, one per row
+pub fn print_memory_keys<T: Write, V>(writer: &mut T, groups: &BTreeMap<String, V>, order: &str, terminator: &OutputTerminator) -> Result<()> {
+    let keys: Box<dyn Iterator<Item = &String>> = match ResultsOrdering::new(order) {
+        ResultsOrdering::Asc => Box::new(groups.keys()),
+        ResultsOrdering::Desc => Box::new(groups.keys().rev())
+    };
+    for key in keys {
+        terminator.write_row(writer, key)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::io::{stderr, Write};
+use std::fs::OpenOptions;
+use std::sync::{Arc, Mutex, atomic::{AtomicUsize, AtomicU64, Ordering}};
+use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{Result, Context};
+
+///The same counter event is written at most once per interval
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+///The last write time of a counter event that was never written
+const NEVER_WRITTEN: u64 = u64::MAX;
+
+///Writes the progress of the run as json lines, used with --progress-format json.  
+///The mapper, indexer and reducer update the counters, a stage event always carries all of them
+pub struct Progress {
+    state: Arc<ProgressState>
+}
+
+struct ProgressState {
+    output: Mutex<Box<dyn Write + Send>>,
+    started: Instant,
+    ///the last write time of each counter event in ms since started, updated without a lock since the counters change on every chunk and key
+    last_map_event: AtomicU64,
+    last_index_event: AtomicU64,
+    last_reduce_event: AtomicU64,
+    lines_read: AtomicUsize,
+    index_cycles: AtomicUsize,
+    keys_reduced: AtomicUsize
+}
+
+impl Clone for Progress {
+    fn clone(&self) -> Progress {
+        Progress {
+            state: self.state.clone()
+        }
+    }
+}
+
+impl Progress {
+    ///Writes to the file descriptor fd, 2 is stderr
+    pub fn new(fd: u32) -> Result<Progress> {
+        let output: Box<dyn Write + Send> = if fd == 2 {
+            Box::new(stderr())
+        } else {
+            let path = format!("/dev/fd/{}", fd);
+            Box::new(OpenOptions::new().append(true).open(&path).with_context(|| format!("Could not open progress fd: {}", fd))?)
+        };
+        Ok(Progress {
+            state: Arc::new(ProgressState {
+                output: Mutex::new(output),
+                started: Instant::now(),
+                last_map_event: AtomicU64::new(NEVER_WRITTEN),
+                last_index_event: AtomicU64::new(NEVER_WRITTEN),
+                last_reduce_event: AtomicU64::new(NEVER_WRITTEN),
+                lines_read: AtomicUsize::new(0),
+                index_cycles: AtomicUsize::new(0),
+                keys_reduced: AtomicUsize::new(0)
+            })
+        })
+    }
+
+    ///Marks the start of a stage (map, reduce, output or done)
+    pub fn stage(&self, stage: &str) {
+        self.write(serde_json::json!({
+            "event": "stage",
+            "stage": stage,
+            "lines_read": self.state.lines_read.load(Ordering::SeqCst),
+            "index_cycles": self.state.index_cycles.load(Ordering::SeqCst),
+            "keys_reduced": self.state.keys_reduced.load(Ordering::SeqCst)
+        }));
+    }
+
+    pub fn lines_read(&self, lines: usize) {
+        self.state.lines_read.store(lines, Ordering::SeqCst);
+        self.throttled(&self.state.last_map_event, "map", "lines_read", lines);
+    }
+
+    pub fn index_cycle(&self) {
+        let cycles = self.state.index_cycles.fetch_add(1, Ordering::SeqCst) + 1;
+        self.throttled(&self.state.last_index_event, "index", "index_cycles", cycles);
+    }
+
+    pub fn key_reduced(&self) {
+        let keys = self.state.keys_reduced.fetch_add(1, Ordering::SeqCst) + 1;
+        self.throttled(&self.state.last_reduce_event, "reduce", "keys_reduced", keys);
+    }
+
+    ///Writes the event unless it was written less than PROGRESS_INTERVAL ago, only the thread that swaps in the new write time writes it
+    fn throttled(&self, last_event: &AtomicU64, event: &str, counter: &str, value: usize) {
+        let now = self.state.started.elapsed().as_millis() as u64;
+        let last = last_event.load(Ordering::Relaxed);
+        if last != NEVER_WRITTEN && now.saturating_sub(last) < PROGRESS_INTERVAL.as_millis() as u64 {
+            return;
+        }
+        if last_event.compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+            return;
+        }
+        let mut line = serde_json::json!({"event": event});
+        line[counter] = serde_json::json!(value);
+        self.write(line);
+    }
+
+    ///Progress is best effort, a failed write does not fail the run
+    fn write(&self, mut line: serde_json::Value) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis()).unwrap_or_default();
+        line["time_ms"] = serde_json::json!(time as u64);
+        let _ = self.state.output.lock().unwrap().write_all(format!("{}\n", line).as_bytes());
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use anyhow::Result;
+use super::combiner::assign_partition;
+use super::js::ContextBuilder;
+
+///Where the keys that are being reduced came from, used with --with-provenance.  
+///The consumer records each key before it is sent to the reducer, which takes it back when the key is saved
+pub struct Provenance {
+    partitions: usize,
+    partition_fn: Option<Arc<ContextBuilder>>,
+    keys: Arc<Mutex<HashMap<String, KeyProvenance>>>
+}
+
+///The indexer partition of a key and the number of file parts it was spilled to
+#[derive(Clone, Copy)]
+pub struct KeyProvenance {
+    pub partition: usize,
+    pub parts: usize
+}
+
+impl Clone for Provenance {
+    fn clone(&self) -> Provenance {
+        Provenance {
+            partitions: self.partitions,
+            partition_fn: self.partition_fn.clone(),
+            keys: self.keys.clone()
+        }
+    }
+}
+
+impl Provenance {
+    ///partitions is the number of indexer partitions the keys were assigned to, by their hash or the js partition()
+    pub fn new(partitions: usize, partition_fn: Option<Arc<ContextBuilder>>) -> Provenance {
+        Provenance {
+            partitions,
+            partition_fn,
+            keys: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    ///index_key is the key the values were grouped by, key is the one they are reduced and printed as
+    pub fn record(&self, index_key: &str, key: &str, parts: usize) -> Result<()> {
+        let provenance = KeyProvenance {
+            partition: assign_partition(index_key, self.partitions, self.partition_fn.as_deref())?,
+            parts
+        };
+        self.keys.lock().unwrap().insert(key.to_owned(), provenance);
+        Ok(())
+    }
+
+    pub fn take(&self, key: &str) -> Option<KeyProvenance> {
+        self.keys.lock().unwrap().remove(key)
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+///How many of the largest keys are listed in a report
+const REPORTED_KEYS: usize = 10;
+
+///Periodically writes the keys that hold the most buffered bytes in the reducer to stderr, used with --reduce-memory-report
+pub struct ReduceMemoryReport {
+    stop: Sender<()>,
+    handle: JoinHandle<()>
+}
+
+impl ReduceMemoryReport {
+    ///read returns the buffered bytes of each in flight key
+    pub fn spawn<F>(interval: Duration, read: F) -> ReduceMemoryReport
+        where F: Fn() -> Vec<(String, usize)> + Send + 'static
+    {
+        let (stop, receiver) = channel::<()>();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                eprintln!("{}", summary(read()));
+            }
+        });
+
+        ReduceMemoryReport { stop, handle }
+    }
+
+    ///Waits for the reporting thread to exit, nothing is reported after the reduce is done
+    pub fn stop(self) {
+        drop(self.stop);
+        self.handle.join().unwrap();
+    }
+}
+
+fn summary(mut sizes: Vec<(String, usize)>) -> String {
+    let total: usize = sizes.iter().map(|(_, bytes)| bytes).sum();
+    sizes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let largest: Vec<String> = sizes.iter()
+        .take(REPORTED_KEYS)
+        .map(|(key, bytes)| format!("{} {:.1}MB", key, to_megabytes(*bytes)))
+        .collect();
+    let mut summary = format!("Reduce memory: {} keys in flight, {:.1}MB buffered", sizes.len(), to_megabytes(total));
+    if !largest.is_empty() {
+        summary.push_str(&format!(", largest: {}", largest.join(", ")));
+    }
+    summary
+}
+
+fn to_megabytes(bytes: usize) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mb = 1024 * 1024;
+        let sizes = vec![(String::from("small"), mb / 2), (String::from("big"), 3 * mb), (String::from("tie"), mb / 2)];
+        assert_eq!(summary(sizes), "Reduce memory: 3 keys in flight, 4.0MB buffered, largest: big 3.0MB, small 0.5MB, tie 0.5MB");
+        assert_eq!(summary(vec![]), "Reduce memory: 0 keys in flight, 0.0MB buffered");
+        let many: Vec<(String, usize)> = (0..20).map(|n| (format!("k{}", n), n * mb)).collect();
+        assert!(summary(many).ends_with("k11 11.0MB, k10 10.0MB"));
+    }
+}
@@ -1,19 +1,34 @@
-use std::thread::{spawn, JoinHandle};
+use std::thread::{Builder, JoinHandle};
 use std::sync::{mpsc::{SyncSender, sync_channel}, Arc, Mutex};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, BTreeMap, BinaryHeap};
+use std::cmp::{self, Reverse};
+use std::mem;
 use std::path::PathBuf;
-use anyhow::Result;
+use std::fs::File;
+use std::io::Write;
+use std::time::Duration;
+use anyhow::{Result, Error, Context as ErrorContext, anyhow};
 
 use super::thread_pool::ThreadPool;
 use super::json_line::from_json;
-use super::js::ContextBuilder;
-use super::result_table::ResultTable;
+use super::js::{ContextBuilder, Context};
+use super::result_table::{ResultTable, ResultEncoding, ResultColumnFamily};
+use super::semaphore::Semaphore;
+use super::sorted_merge::sort_numeric;
+use super::progress::Progress;
+use super::provenance::{Provenance, KeyProvenance};
+use super::sorted_stream::SortedStream;
+use super::reduce_memory::ReduceMemoryReport;
 
 pub enum Reduction {
     KeyInit(Arc<String>, usize),
     FilePartInit(Arc<String>),
     FileLineInit(Arc<String>, usize, usize),
-    FileLine(Arc<String>, usize, ReduceValue)
+    FileLine(Arc<String>, usize, ReduceValue),
+    ///small keys with all of their values in the index, reduced together in a single task
+    KeyBatch(Vec<(Arc<String>, Vec<String>)>),
+    ///keys that were already reduced by --streaming-reduce, only finalized and saved
+    Reduced(Vec<(Arc<String>, String)>)
 }
 
 pub enum ReduceValue {
@@ -24,102 +39,669 @@ pub enum ReduceValue {
 pub fn spawn_reducer(
     pool: ThreadPool,
     context_builder: Arc<ContextBuilder>,
-    workers: usize,
-    root_dir: &PathBuf
-) -> Result<(JoinHandle<()>, SyncSender<Reduction>, ResultTable)> {
-    let (reduction_sender, reduction_receiver) = sync_channel(workers);
-    let result_table = ResultTable::new(root_dir)?;
+    queue_size: usize,
+    root_dir: &PathBuf,
+    options: ReduceOptions
+) -> Result<(JoinHandle<Result<()>>, SyncSender<Reduction>, ResultTable)> {
+    let ReduceOptions { on_error, max_inflight, passes, max_values_retained, chunk_values, partial_writer, max_value_bytes, result_encoding, top_k, error_writer, collect_sorted, dedup_values, single_value_reduce, progress, provenance, result_column_family, sorted_stream, memory_report, ordered } = options;
+    let (reduction_sender, reduction_receiver) = sync_channel(queue_size);
+    let result_table = ResultTable::new(root_dir, result_encoding, result_column_family.as_ref())?;
     let thread_result_table = result_table.clone();
-    let reducer = spawn(move|| {
-        let tracker = Tracker::new();
+    let sink = ResultSink {
+        results_table: result_table.clone(),
+        partial_writer: partial_writer.clone(),
+        error_writer,
+        on_error,
+        max_values_retained,
+        max_value_bytes,
+        top_k,
+        progress,
+        provenance,
+        sorted_stream
+    };
+    let reducer = Builder::new().name(String::from("omnimap-reducer")).spawn(move|| -> Result<()> {
+        let tracker = Tracker::new(ordered);
+        let memory_report = memory_report.map(|interval| {
+            let tracker = tracker.clone();
+            ReduceMemoryReport::spawn(interval, move || tracker.buffered_bytes())
+        });
+        let inflight = Semaphore::new(max_inflight);
+        //the lines of a part are sent one after the other so they can be numbered here
+        let mut next_line = 0;
+        //the reductions of a key are never interleaved with another key so its size is known before its lines arrive
+        let mut key_parts = 0;
+        let mut is_single_line = false;
         for reduction in reduction_receiver.iter() {
             match reduction {
                 Reduction::KeyInit(key, total_parts) => {
+                    key_parts = total_parts;
                     tracker.new_key(key, total_parts);
                 },
                 Reduction::FilePartInit(key) => {
                     tracker.new_part(key);
                 },
                 Reduction::FileLineInit(key, current_part, total_lines) => {
+                    is_single_line = key_parts == 1 && total_lines == 1;
                     tracker.new_line(key, current_part, total_lines);
+                    next_line = 0;
+                },
+                Reduction::KeyBatch(batch) => {
+                    if tracker.has_aborted() {
+                        break;
+                    }
+                    let context_builder = context_builder.clone();
+                    let tracker = tracker.clone();
+                    let sink = sink.clone();
+                    let permit = inflight.acquire();
+                    pool.execute(move|| {
+                        let _permit = permit;
+                        context_builder.reuse(|context| {
+                            for (key, values) in batch {
+                                if let Some(max_values) = max_values_retained {
+                                    tracker.retain_values(key.clone(), &values, max_values);
+                                }
+                                let values = dedup(values, dedup_values);
+                                //the same reduce levels as a key with a single part of a single line
+                                let reduced = match passes {
+                                    ReducePasses::Multi if single_value_reduce.is_initial(&values) => context.run_reduce(&key, &values, false),
+                                    ReducePasses::Multi => context.run_reduce(&key, &values, false)
+                                        .and_then(|line_result| context.run_reduce(&key, &vec![line_result], true))
+                                        .and_then(|part_result| context.run_reduce(&key, &vec![part_result], true)),
+                                    ReducePasses::Single => check_collected(&key, values.iter().map(|value| value.len()).sum(), collect_sorted)
+                                        .and_then(|_| sort_collected(&key, values, collect_sorted))
+                                        .and_then(|values| context.run_reduce(&key, &values, false))
+                                };
+                                let reduced = reduced.and_then(|reduced| context.run_finalize(&key, &reduced));
+                                sink.save(&tracker, &key, reduced);
+                            }
+                        });
+                    });
+                },
+                Reduction::Reduced(batch) => {
+                    if tracker.has_aborted() {
+                        break;
+                    }
+                    let context_builder = context_builder.clone();
+                    let tracker = tracker.clone();
+                    let sink = sink.clone();
+                    let permit = inflight.acquire();
+                    pool.execute(move|| {
+                        let _permit = permit;
+                        context_builder.reuse(|context| {
+                            for (key, reduced) in batch {
+                                let reduced = context.run_finalize(&key, &reduced);
+                                sink.save(&tracker, &key, reduced);
+                            }
+                        });
+                    });
                 },
                 Reduction::FileLine(key, part, result) => {
+                    if tracker.has_aborted() {
+                        break;
+                    }
+                    let line = next_line;
+                    next_line += 1;
+                    //only a key of a single line can have a single value
+                    let check_single_value = is_single_line && passes == ReducePasses::Multi && single_value_reduce == SingleValueReduce::Initial;
                     let context_builder = context_builder.clone();
                     let tracker = tracker.clone();
-                    let results_table = thread_result_table.clone();
+                    let partial_writer = partial_writer.clone();
+                    let sink = sink.clone();
+                    let permit = inflight.acquire();
                     pool.execute(move|| {
+                        let _permit = permit;
                         context_builder.reuse(|context| {
+                            let mut is_single_value = false;
                             let reduced = match result {
-                                ReduceValue::FromFile(result) => {
-                                    let values = from_json(&result).unwrap();
-                                    context.run_reduce(&key, &values, false).unwrap()
+                                //a file line is already a json array of the values so it is passed to reduce() without parsing it here
+                                ReduceValue::FromFile(result) if passes == ReducePasses::Multi && max_values_retained.is_none() && !dedup_values && !check_single_value => {
+                                    context.run_reduce_json(&key, &result, false).map(|r| vec![r])
                                 },
-                                ReduceValue::FromIndex(result) => {
-                                    context.run_reduce(&key, &result, false).unwrap()
+                                result => {
+                                    let values = match result {
+                                        ReduceValue::FromFile(result) => from_json(&result),
+                                        ReduceValue::FromIndex(result) => Ok(result)
+                                    };
+                                    if let (Some(max_values), Ok(values)) = (max_values_retained, &values) {
+                                        tracker.retain_values(key.clone(), values, max_values);
+                                    }
+                                    //a single pass only collects the values until the whole key is available
+                                    match passes {
+                                        ReducePasses::Multi => values.and_then(|values| {
+                                            let values = dedup(values, dedup_values);
+                                            is_single_value = check_single_value && single_value_reduce.is_initial(&values);
+                                            context.run_reduce(&key, &values, false)
+                                        }).map(|r| vec![r]),
+                                        ReducePasses::Single => values.and_then(|values| tracker.collect(&key, &values, collect_sorted).map(|_| values))
+                                    }
                                 }
                             };
-                            let is_part_done = tracker.save_line_result(key.clone(), part, reduced);
-                            if !is_part_done {
-                                return;
+                            let mut is_part_done = tracker.save_line_result(key.clone(), part, line, reduced);
+                            //rereduce the finished lines in chunks so a huge part is not kept in memory until all of its lines are done
+                            while !is_part_done {
+                                let chunk = match (passes, chunk_values) {
+                                    (ReducePasses::Multi, Some(chunk_values)) => tracker.take_line_chunk(key.clone(), part, chunk_values),
+                                    _ => None
+                                };
+                                match chunk {
+                                    Some((last_line, values)) => {
+                                        let reduced = context.run_reduce(&key, &values, true).map(|r| vec![r]);
+                                        is_part_done = tracker.save_line_result(key.clone(), part, last_line, reduced);
+                                    },
+                                    None => return
+                                }
                             }
-                            let part_values = tracker.merge_line_results(key.clone());
-                            let reduced = context.run_reduce(&key, &part_values, true).unwrap();
-                            let are_all_parts_done = tracker.save_part_result(key.clone(), reduced);
+                            let reduced = tracker.merge_line_results(key.clone(), part)
+                                .and_then(|part_values| match passes {
+                                    //the result of a lone value is already final
+                                    ReducePasses::Multi if is_single_value => Ok(part_values),
+                                    ReducePasses::Multi => context.run_reduce(&key, &part_values, true)
+                                        .and_then(|r| sink.check_size(&key, r))
+                                        .map(|r| vec![r]),
+                                    ReducePasses::Single => Ok(part_values)
+                                });
+                            let are_all_parts_done = tracker.save_part_result(key.clone(), part, reduced);
                             if !are_all_parts_done {
+                                if let (ReducePasses::Multi, Some(partial_writer)) = (passes, &partial_writer) {
+                                    //a failed partial reduce is not reported, the final reduce will fail the same way
+                                    if let Some(Ok(partial)) = tracker.get_key_results(&key).map(|part_values| context.run_reduce(&key, &part_values, true)) {
+                                        if let Err(err) = tracker.if_pending(&key, || partial_writer.write(&key, false, &partial)) {
+                                            tracker.abort(err);
+                                        }
+                                    }
+                                }
                                 return;
                             }
-                            let key_values = tracker.get_and_clean_key_results(key.clone());
-                            let reduced = context.run_reduce(&key, &key_values, true).unwrap();
-                            results_table.add(&key, &reduced).unwrap();
+                            let reduced = tracker.get_and_clean_key_results(key.clone())
+                                //the collected values of a single pass are deduplicated across the whole key, the part results of multiple passes never are
+                                .map(|key_values| dedup(key_values, dedup_values && passes == ReducePasses::Single))
+                                .and_then(|key_values| sort_collected(&key, key_values, collect_sorted))
+                                .and_then(|mut key_values| match passes {
+                                    ReducePasses::Multi if is_single_value => key_values.pop().ok_or_else(|| anyhow!("Key {} has no result", key)),
+                                    _ => context.run_reduce(&key, &key_values, passes == ReducePasses::Multi)
+                                })
+                                .and_then(|reduced| context.run_finalize(&key, &reduced));
+                            sink.save(&tracker, &key, reduced);
                         });
                     });
                 }
             }
         }
-    });
+        //wait for the scheduled reductions so any error they raised is reported
+        pool.join();
+        if let Some(memory_report) = memory_report {
+            memory_report.stop();
+        }
+        match tracker.take_abort_error() {
+            Some(err) => Err(err),
+            None => thread_result_table.flush()
+        }
+    }).context("Could not spawn reducer thread")?;
     
     Ok((reducer, reduction_sender, result_table))
 }
 
+///Settings of the reduce phase
+#[derive(Clone)]
+pub struct ReduceOptions {
+    pub on_error: ReduceErrorPolicy,
+    ///how many reductions can be queued in the pool at once
+    pub max_inflight: usize,
+    pub passes: ReducePasses,
+    ///keep up to this many input values per key and save them along the result
+    pub max_values_retained: Option<usize>,
+    ///rereduce the line results of a part every this many lines, only used with multiple passes
+    pub chunk_values: Option<usize>,
+    ///write the partial result of a key every time one of its parts is reduced, only used with multiple passes
+    pub partial_writer: Option<PartialWriter>,
+    ///fail the keys with a part or final result larger than this many bytes
+    pub max_value_bytes: Option<usize>,
+    pub result_encoding: ResultEncoding,
+    ///also keep the results with the largest numeric values here
+    pub top_k: Option<TopK>,
+    ///write the keys that failed to reduce with their error
+    pub error_writer: Option<ErrorWriter>,
+    ///sort the values of each key numerically before the single pass reduce, keys that collect more bytes than this fail
+    pub collect_sorted: Option<usize>,
+    ///drop the repeated values before they are passed to reduce()
+    pub dedup_values: bool,
+    pub single_value_reduce: SingleValueReduce,
+    pub progress: Option<Progress>,
+    ///save the partition and file parts of each key along its result
+    pub provenance: Option<Provenance>,
+    ///write the results to a column family of an external db instead of the temp dir
+    pub result_column_family: Option<ResultColumnFamily>,
+    ///also pass the saved results to the stream that prints them in key order
+    pub sorted_stream: Option<SortedStream>,
+    ///report the keys with the most buffered bytes to stderr at this interval
+    pub memory_report: Option<Duration>,
+    ///merge the line results of each part on their own and in line order
+    pub ordered: bool
+}
+
+///Saves the final result of a key to the result table or handles its error based on the error policy
+struct ResultSink {
+    results_table: ResultTable,
+    partial_writer: Option<PartialWriter>,
+    error_writer: Option<ErrorWriter>,
+    on_error: ReduceErrorPolicy,
+    max_values_retained: Option<usize>,
+    max_value_bytes: Option<usize>,
+    top_k: Option<TopK>,
+    progress: Option<Progress>,
+    provenance: Option<Provenance>,
+    sorted_stream: Option<SortedStream>
+}
+
+impl Clone for ResultSink {
+    fn clone(&self) -> ResultSink {
+        ResultSink {
+            results_table: self.results_table.clone(),
+            partial_writer: self.partial_writer.clone(),
+            error_writer: self.error_writer.clone(),
+            on_error: self.on_error,
+            max_values_retained: self.max_values_retained,
+            max_value_bytes: self.max_value_bytes,
+            top_k: self.top_k.clone(),
+            progress: self.progress.clone(),
+            provenance: self.provenance.clone(),
+            sorted_stream: self.sorted_stream.clone()
+        }
+    }
+}
+
+impl ResultSink {
+    ///With retained values or provenance the result is saved in a json object along them
+    fn structured_entry(&self, reduced: &str, retained: Vec<String>, key_provenance: Option<KeyProvenance>) -> Option<String> {
+        if self.max_values_retained.is_none() && self.provenance.is_none() {
+            return None;
+        }
+        let mut entry = serde_json::json!({"result": reduced});
+        if self.max_values_retained.is_some() {
+            entry["values"] = serde_json::json!(retained);
+        }
+        if let Some(key_provenance) = key_provenance {
+            entry["partition"] = serde_json::json!(key_provenance.partition);
+            entry["parts"] = serde_json::json!(key_provenance.parts);
+        }
+        Some(entry.to_string())
+    }
+
+    ///Saves the reduce result of a done key, reduced is expected to be finalized
+    fn save(&self, tracker: &Tracker, key: &Arc<String>, reduced: Result<String>) {
+        let reduced = reduced.and_then(|reduced| self.check_size(key, reduced));
+        if let (Some(partial_writer), Ok(reduced)) = (&self.partial_writer, &reduced) {
+            if let Err(err) = partial_writer.write(key, true, reduced) {
+                tracker.abort(err);
+            }
+        }
+        let retained = tracker.take_values(key);
+        let key_provenance = self.provenance.as_ref().and_then(|provenance| provenance.take(key));
+        if let Some(progress) = &self.progress {
+            progress.key_reduced();
+        }
+        match reduced {
+            Ok(reduced) => {
+                let structured = self.structured_entry(&reduced, retained, key_provenance);
+                let entry = structured.as_deref().unwrap_or(&reduced);
+                if let Some(top_k) = &self.top_k {
+                    top_k.offer(key, &reduced, entry);
+                }
+                if let Err(err) = self.results_table.add(key, entry) {
+                    tracker.abort(err);
+                }
+                if let Some(sorted_stream) = &self.sorted_stream {
+                    sorted_stream.done(key, Some(entry.to_owned()));
+                }
+            },
+            Err(err) => {
+                if let Some(error_writer) = &self.error_writer {
+                    let values = self.max_values_retained.map(|_| retained);
+                    if let Err(err) = error_writer.write(key, &err, values) {
+                        tracker.abort(err);
+                    }
+                }
+                match self.on_error {
+                    ReduceErrorPolicy::Skip => {
+                        eprintln!("Skipping key {}: {:#}", key, err);
+                        if let Some(sorted_stream) = &self.sorted_stream {
+                            sorted_stream.done(key, None);
+                        }
+                    },
+                    ReduceErrorPolicy::Error => {
+                        tracker.abort(err.context(format!("Could not reduce key: {}", key)));
+                    }
+                }
+            }
+        }
+    }
+
+    ///Fails a reduced value that is over the size limit, it usually comes from a reduce that keeps growing on rereduce
+    fn check_size(&self, key: &str, reduced: String) -> Result<String> {
+        match self.max_value_bytes {
+            Some(max_bytes) if reduced.len() > max_bytes => {
+                Err(anyhow!("The reduced value of key {} is {} bytes, over the limit of {} bytes", key, reduced.len(), max_bytes))
+            },
+            _ => Ok(reduced)
+        }
+    }
+}
+
+///Keeps the k results with the largest numeric values, results that are not numbers are not ranked
+pub struct TopK {
+    k: usize,
+    heap: Arc<Mutex<BinaryHeap<Reverse<RankedResult>>>>
+}
+
+impl Clone for TopK {
+    fn clone(&self) -> TopK {
+        TopK {
+            k: self.k,
+            heap: self.heap.clone()
+        }
+    }
+}
+
+impl TopK {
+    pub fn new(k: usize) -> TopK {
+        TopK {
+            k,
+            heap: Arc::new(Mutex::new(BinaryHeap::with_capacity(k + 1)))
+        }
+    }
+
+    ///Ranks the result of a key, entry is what gets printed for it
+    fn offer(&self, key: &str, result: &str, entry: &str) {
+        let value = match result.trim().parse::<f64>() {
+            Ok(value) if value.is_finite() => value,
+            _ => return
+        };
+        let mut heap = self.heap.lock().unwrap();
+        heap.push(Reverse(RankedResult { value, key: key.to_owned(), entry: entry.to_owned() }));
+        //the smallest one is on top of the min-heap
+        if heap.len() > self.k {
+            heap.pop();
+        }
+    }
+
+    ///Returns the (key, entry) pairs from the largest value to the smallest
+    pub fn into_sorted(self) -> Vec<(String, String)> {
+        let heap = mem::take(&mut *self.heap.lock().unwrap());
+        heap.into_sorted_vec().into_iter().map(|Reverse(ranked)| (ranked.key, ranked.entry)).collect()
+    }
+}
+
+///A result ordered by its value, equal values are ordered by key so the smaller key ranks higher
+struct RankedResult {
+    value: f64,
+    key: String,
+    entry: String
+}
+
+impl Ord for RankedResult {
+    fn cmp(&self, other: &RankedResult) -> cmp::Ordering {
+        //values are always finite
+        self.value.partial_cmp(&other.value).unwrap().then_with(|| other.key.cmp(&self.key))
+    }
+}
+
+impl PartialOrd for RankedResult {
+    fn partial_cmp(&self, other: &RankedResult) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RankedResult {
+    fn eq(&self, other: &RankedResult) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for RankedResult {}
+
+///Appends the partial and final results of keys as "key\tpartial\tresult\n" or "key\tfinal\tresult\n" lines to a file
+pub struct PartialWriter {
+    file: Arc<Mutex<File>>
+}
+
+impl Clone for PartialWriter {
+    fn clone(&self) -> PartialWriter {
+        PartialWriter {
+            file: self.file.clone()
+        }
+    }
+}
+
+impl PartialWriter {
+    pub fn new(path: &PathBuf) -> Result<PartialWriter> {
+        let file = File::create(path).with_context(|| format!("Could not create partial results file: {}", path.display()))?;
+        Ok(PartialWriter {
+            file: Arc::new(Mutex::new(file))
+        })
+    }
+
+    fn write(&self, key: &str, is_final: bool, result: &str) -> Result<()> {
+        let state = if is_final { "final" } else { "partial" };
+        self.file.lock().unwrap()
+            .write_all(format!("{}\t{}\t{}\n", key, state, result).as_bytes())
+            .context("Could not write to partial results file")
+    }
+}
+
+///Drops the repeated values and keeps the first of each in place, values are returned as they are if enabled is false
+fn dedup(values: Vec<String>, enabled: bool) -> Vec<String> {
+    if !enabled {
+        return values;
+    }
+    let mut seen = HashSet::with_capacity(values.len());
+    values.into_iter().filter(|value| seen.insert(value.clone())).collect()
+}
+
+///Fails a key whose collected values are over the --collect-sorted limit
+fn check_collected(key: &str, bytes: usize, collect_sorted: Option<usize>) -> Result<()> {
+    match collect_sorted {
+        Some(max_bytes) if bytes > max_bytes => {
+            Err(anyhow!("The collected values of key {} are {} bytes, over the limit of {} bytes", key, bytes, max_bytes))
+        },
+        _ => Ok(())
+    }
+}
+
+///Sorts the collected values of a key numerically with --collect-sorted, fails if any of them is not a number
+fn sort_collected(key: &str, values: Vec<String>, collect_sorted: Option<usize>) -> Result<Vec<String>> {
+    if collect_sorted.is_none() {
+        return Ok(values);
+    }
+    sort_numeric(key, values)
+}
+
+///Writes the keys that failed to reduce as json lines
+pub struct ErrorWriter {
+    file: Arc<Mutex<File>>
+}
+
+impl Clone for ErrorWriter {
+    fn clone(&self) -> ErrorWriter {
+        ErrorWriter {
+            file: self.file.clone()
+        }
+    }
+}
+
+impl ErrorWriter {
+    pub fn new(path: &PathBuf) -> Result<ErrorWriter> {
+        let file = File::create(path).with_context(|| format!("Could not create reduce error file: {}", path.display()))?;
+        Ok(ErrorWriter {
+            file: Arc::new(Mutex::new(file))
+        })
+    }
+
+    ///The retained input values are only written if they were kept
+    fn write(&self, key: &str, err: &Error, values: Option<Vec<String>>) -> Result<()> {
+        let mut line = serde_json::json!({"key": key, "error": format!("{:#}", err)});
+        if let Some(values) = values {
+            line["values"] = serde_json::json!(values);
+        }
+        self.file.lock().unwrap()
+            .write_all(format!("{}\n", line).as_bytes())
+            .context("Could not write to reduce error file")
+    }
+}
+
+///How many levels of reduce() calls are used per key
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReducePasses {
+    ///reduce each file part line, rereduce each part and then rereduce the part results
+    Multi,
+    ///collect all the values of a key in memory and reduce them once
+    Single
+}
+
+impl ReducePasses {
+    pub fn new(passes: &str) -> ReducePasses {
+        if passes == "single" {
+            ReducePasses::Single
+        } else {
+            ReducePasses::Multi
+        }
+    }
+}
+
+///How the keys with a single value are reduced with multiple passes
+#[derive(Clone, Copy, PartialEq)]
+pub enum SingleValueReduce {
+    ///reduce the value once with rereduce=false, its result is final
+    Initial,
+    ///reduce the value and then rereduce its result like the part and key results of any other key
+    Rereduce
+}
+
+impl SingleValueReduce {
+    pub fn new(mode: &str) -> SingleValueReduce {
+        if mode == "initial" {
+            SingleValueReduce::Initial
+        } else {
+            SingleValueReduce::Rereduce
+        }
+    }
+
+    ///Checks if values are reduced only once
+    fn is_initial(self, values: &[String]) -> bool {
+        self == SingleValueReduce::Initial && values.len() == 1
+    }
+}
+
+///Checks if the multi pass reduce gives the same result as a single pass over a sample of values.  
+///The sample is split in two to simulate multiple lines of a part, the part result is rereduced again like a key result.
+pub fn is_rereduce_safe(context: &Context, key: &str, values: &[String]) -> Result<bool> {
+    let single_pass = context.run_reduce(key, &values.to_vec(), false)?;
+    let (first, second) = values.split_at(values.len() / 2);
+    let mut line_results = vec![];
+    for half in &[first, second] {
+        if !half.is_empty() {
+            line_results.push(context.run_reduce(key, &half.to_vec(), false)?);
+        }
+    }
+    let part_result = context.run_reduce(key, &line_results, true)?;
+    let multi_pass = context.run_reduce(key, &vec![part_result], true)?;
+
+    Ok(single_pass == multi_pass)
+}
+
+///What to do when reduce() fails for a key
+#[derive(Clone, Copy)]
+pub enum ReduceErrorPolicy {
+    Skip,
+    Error
+}
+
+impl ReduceErrorPolicy {
+    pub fn new(policy: &str) -> ReduceErrorPolicy {
+        if policy == "skip" {
+            ReduceErrorPolicy::Skip
+        } else {
+            ReduceErrorPolicy::Error
+        }
+    }
+}
+
+fn values_bytes(values: &[String]) -> usize {
+    values.iter().map(String::len).sum()
+}
+
+///Results are kept sorted by their part and line number, in ordered mode they are merged in the same order the values were read
 struct Tracker {
-    keys: Arc<Mutex<HashMap<Arc<String>, (usize, Vec<String>)>>>,
-    parts: Arc<Mutex<HashMap<Arc<String>, HashMap<usize, (usize, Vec<String>)>>>>,
+    ordered: bool,
+    keys: Arc<Mutex<HashMap<Arc<String>, (usize, BTreeMap<usize, Vec<String>>)>>>,
+    parts: Arc<Mutex<HashMap<Arc<String>, BTreeMap<usize, (usize, BTreeMap<usize, Vec<String>>)>>>>,
+    errors: Arc<Mutex<HashMap<Arc<String>, Error>>>,
+    values: Arc<Mutex<HashMap<Arc<String>, Vec<String>>>>,
+    ///the bytes of the values collected for each key with --collect-sorted
+    collected_bytes: Arc<Mutex<HashMap<Arc<String>, usize>>>,
+    abort_error: Arc<Mutex<Option<Error>>>,
 }
 
 impl Clone for Tracker {
     fn clone(&self) -> Tracker {
         Tracker {
+            ordered: self.ordered,
             keys: self.keys.clone(),
-            parts: self.parts.clone()
+            parts: self.parts.clone(),
+            errors: self.errors.clone(),
+            values: self.values.clone(),
+            collected_bytes: self.collected_bytes.clone(),
+            abort_error: self.abort_error.clone()
         }
     }
 }
 
 impl Tracker {
-    pub fn new() -> Tracker {
+    pub fn new(ordered: bool) -> Tracker {
         Tracker {
+            ordered,
             keys: Arc::new(Mutex::new(HashMap::new())),
-            parts: Arc::new(Mutex::new(HashMap::new()))
+            parts: Arc::new(Mutex::new(HashMap::new())),
+            errors: Arc::new(Mutex::new(HashMap::new())),
+            values: Arc::new(Mutex::new(HashMap::new())),
+            collected_bytes: Arc::new(Mutex::new(HashMap::new())),
+            abort_error: Arc::new(Mutex::new(None))
         }
     }
 
+    ///Returns the bytes of the part and line results and of the retained values of each in flight key.  
+    ///Each map is locked on its own so the reductions only wait for one scan at a time
+    pub fn buffered_bytes(&self) -> Vec<(String, usize)> {
+        let mut sizes: HashMap<Arc<String>, usize> = HashMap::new();
+        for (key, (_, part_results)) in self.keys.lock().unwrap().iter() {
+            *sizes.entry(key.clone()).or_default() += part_results.values().map(|values| values_bytes(values)).sum::<usize>();
+        }
+        for (key, parts) in self.parts.lock().unwrap().iter() {
+            *sizes.entry(key.clone()).or_default() += parts.values().flat_map(|(_, lines)| lines.values()).map(|values| values_bytes(values)).sum::<usize>();
+        }
+        for (key, values) in self.values.lock().unwrap().iter() {
+            *sizes.entry(key.clone()).or_default() += values_bytes(values);
+        }
+        sizes.into_iter().map(|(key, bytes)| (key.to_string(), bytes)).collect()
+    }
+
     ///Initializes a new key in the tracker with its total parts
     pub fn new_key(&self, key: Arc<String>, total_parts: usize) {
-        self.keys.lock().unwrap().insert(key, (total_parts, vec![]));
+        self.keys.lock().unwrap().insert(key, (total_parts, BTreeMap::new()));
     }
 
     ///Initializes a new part in the tracker
     pub fn new_part(&self, key: Arc<String>) {
-        self.parts.lock().unwrap().insert(key, HashMap::new());
+        self.parts.lock().unwrap().insert(key, BTreeMap::new());
     }
 
     ///Initializes a new line in the tracker with its total lines for a specific part
     pub fn new_line(&self, key: Arc<String>, current_part: usize, total_lines: usize) {
-        self.parts.lock().unwrap().get_mut(&key).unwrap().insert(current_part, (total_lines, vec![]));
+        self.parts.lock().unwrap().get_mut(&key).unwrap().insert(current_part, (total_lines, BTreeMap::new()));
     }
 
-    ///Saves the line result for a line, returns true if the whole part is reduced
-    pub fn save_line_result(&self, key: Arc<String>, part: usize, result: String) -> bool {
+    ///Saves the line result for a line, returns true if the whole part is reduced.  
+    ///A failed result marks the whole key as failed but still counts towards completion
+    pub fn save_line_result(&self, key: Arc<String>, part: usize, line: usize, result: Result<Vec<String>>) -> bool {
         //get a lock to the part
         let mut part_lock = self.parts.lock().unwrap();
         //get the entry to the key
@@ -127,7 +709,10 @@ impl Tracker {
         //get the sub-entry to the path
         let entry = entry.get_mut(&part).unwrap();
         //save our result
-        entry.1.push(result);
+        match result {
+            Ok(result) => { entry.1.insert(line, result); },
+            Err(err) => self.save_error(key.clone(), err)
+        }
         //decrement the counter of lines we need to reduce
         if entry.0 > 0 {
             entry.0 -= 1;
@@ -140,28 +725,80 @@ impl Tracker {
         }
     }
 
-    ///Merges multiple line results to a single Vector
-    pub fn merge_line_results(&self, key: Arc<String>) -> Vec<String> {
+    ///Takes a chunk of chunk_values line results of consecutive lines out of a part.  
+    ///Returns the last line of the chunk and the chunk values, the rereduced chunk must be saved back with save_line_result under that line.  
+    ///Until then the chunk counts as a pending line so the part is not considered done.
+    pub fn take_line_chunk(&self, key: Arc<String>, part: usize, chunk_values: usize) -> Option<(usize, Vec<String>)> {
+        if self.has_failed(&key) {
+            return None;
+        }
+        //get a lock to the part
+        let mut part_lock = self.parts.lock().unwrap();
+        //get the entry to the key
+        let entry = part_lock.get_mut(&key).unwrap();
+        //get the sub-entry to the path
+        let entry = entry.get_mut(&part).unwrap();
+        //find the first run of consecutive lines, a rereduced chunk is saved under its last line so it can continue a run
+        let mut run: Vec<usize> = vec![];
+        for line in entry.1.keys() {
+            if run.last().map(|last| last + 1) != Some(*line) {
+                run.clear();
+            }
+            run.push(*line);
+            if run.len() == chunk_values {
+                break;
+            }
+        }
+        if run.len() < chunk_values {
+            return None;
+        }
+        let mut chunk = vec![];
+        for line in &run {
+            chunk.append(&mut entry.1.remove(line).unwrap());
+        }
+        entry.0 += 1;
+        Some((*run.last().unwrap(), chunk))
+    }
+
+    ///Merges the line results of a part to a single Vector, returns an error if the key has failed.  
+    ///In ordered mode only the lines of part are merged and in line order, otherwise the finished lines of all the parts of the key are merged together
+    pub fn merge_line_results(&self, key: Arc<String>, part: usize) -> Result<Vec<String>> {
         //get a lock to the part
         let mut part_lock = self.parts.lock().unwrap();
         //get the entry to the key
         let entry = part_lock.get_mut(&key).unwrap();
         //combine all the values from the lines to a single vector
         let mut all_values = vec![];
-        for val in entry.values_mut() {
-            all_values.append(&mut val.1);
+        if self.ordered {
+            //get the sub-entry to the path
+            for val in entry.get_mut(&part).unwrap().1.values_mut() {
+                all_values.append(val);
+            }
+        } else {
+            for (_, lines) in entry.values_mut() {
+                for val in lines.values_mut() {
+                    all_values.append(val);
+                }
+            }
         }
-        all_values
+        if self.has_failed(&key) {
+            return Err(anyhow!("Key {} has failed", key));
+        }
+        Ok(all_values)
     }
 
-    ///Saves a part result, returns true if all parts for a key are reduced
-    pub fn save_part_result(&self, key: Arc<String>, result: String) -> bool {
+    ///Saves a part result, returns true if all parts for a key are reduced.  
+    ///A failed result marks the whole key as failed but still counts towards completion
+    pub fn save_part_result(&self, key: Arc<String>, part: usize, result: Result<Vec<String>>) -> bool {
         //get a lock to the key
         let mut key_lock = self.keys.lock().unwrap();
         //get the entry to the key
         let entry = key_lock.get_mut(&key).unwrap();
         //save our result
-        entry.1.push(result);
+        match result {
+            Ok(result) => { entry.1.insert(part, result); },
+            Err(err) => self.save_error(key.clone(), err)
+        }
         //decrement the counter of file parts we need to reduce
         if entry.0 > 0 {
             entry.0 -= 1;
@@ -174,15 +811,97 @@ impl Tracker {
         }
     }
 
-    ///Returns the final result for a key. Also removes the entries from the tracker
-    pub fn get_and_clean_key_results(&self, key: Arc<String>) -> Vec<String> {
+    ///Returns a copy of the part results of a key saved so far in part order, None if the key has failed
+    pub fn get_key_results(&self, key: &Arc<String>) -> Option<Vec<String>> {
+        if self.has_failed(key) {
+            return None;
+        }
+        let key_lock = self.keys.lock().unwrap();
+        let entry = key_lock.get(key)?;
+        Some(entry.1.values().flatten().cloned().collect())
+    }
+
+    ///Runs callback while holding the lock of the keys, only if the key is not yet done.  
+    ///The done key is removed under the same lock so nothing runs for it after its final result is taken
+    pub fn if_pending<F>(&self, key: &Arc<String>, callback: F) -> Result<()>
+        where F: FnOnce() -> Result<()>
+    {
+        let key_lock = self.keys.lock().unwrap();
+        if key_lock.contains_key(key) {
+            callback()
+        } else {
+            Ok(())
+        }
+    }
+
+    ///Returns the final result for a key or the first error it failed with. Also removes the entries from the tracker
+    pub fn get_and_clean_key_results(&self, key: Arc<String>) -> Result<Vec<String>> {
         //get a lock to the part
         let mut part_lock = self.parts.lock().unwrap();
         part_lock.remove_entry(&key).unwrap();
         //get a lock to the key
         let mut key_lock = self.keys.lock().unwrap();
         //get the entry to the key
-        let (_, entry) = key_lock.remove_entry(&key).unwrap();
-        entry.1
+        let (_, mut entry) = key_lock.remove_entry(&key).unwrap();
+        self.collected_bytes.lock().unwrap().remove(&key);
+        match self.errors.lock().unwrap().remove(&key) {
+            Some(err) => Err(err),
+            None => {
+                let mut all_values = vec![];
+                for val in entry.1.values_mut() {
+                    all_values.append(val);
+                }
+                Ok(all_values)
+            }
+        }
+    }
+
+    ///Counts the values collected for a key with --collect-sorted, fails once they are over max_bytes
+    pub fn collect(&self, key: &Arc<String>, values: &[String], collect_sorted: Option<usize>) -> Result<()> {
+        if collect_sorted.is_none() {
+            return Ok(());
+        }
+        let mut collected_lock = self.collected_bytes.lock().unwrap();
+        let collected = collected_lock.entry(key.clone()).or_default();
+        *collected += values.iter().map(|value| value.len()).sum::<usize>();
+        check_collected(key, *collected, collect_sorted)
+    }
+
+    ///Keeps the input values of a key until max_values are retained
+    pub fn retain_values(&self, key: Arc<String>, values: &[String], max_values: usize) {
+        let mut values_lock = self.values.lock().unwrap();
+        let retained = values_lock.entry(key).or_default();
+        let remaining = max_values.saturating_sub(retained.len());
+        retained.extend(values.iter().take(remaining).cloned());
+    }
+
+    ///Returns the retained input values of a key and removes them from the tracker
+    pub fn take_values(&self, key: &Arc<String>) -> Vec<String> {
+        self.values.lock().unwrap().remove(key).unwrap_or_default()
+    }
+
+    ///Records the error of a key, only the first error is kept
+    fn save_error(&self, key: Arc<String>, err: Error) {
+        self.errors.lock().unwrap().entry(key).or_insert(err);
+    }
+
+    fn has_failed(&self, key: &Arc<String>) -> bool {
+        self.errors.lock().unwrap().contains_key(key)
+    }
+
+    ///Stops the reducer from scheduling more work, only the first error is kept
+    pub fn abort(&self, err: Error) {
+        let mut abort_error = self.abort_error.lock().unwrap();
+        if abort_error.is_none() {
+            abort_error.replace(err);
+        }
+    }
+
+    pub fn has_aborted(&self) -> bool {
+        self.abort_error.lock().unwrap().is_some()
+    }
+
+    pub fn take_abort_error(&self) -> Option<Error> {
+        self.abort_error.lock().unwrap().take()
     }
 }
@@ -1,24 +1,43 @@
 use rocksdb;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::mem;
+use std::fmt;
+use std::error::Error;
+use serde::{Serialize, Deserialize};
 use anyhow::{Context, Result};
 
-///A persistent table holding the reduction results
+///How many results are buffered before they are written to the table
+const RESULT_BATCH_SIZE: usize = 1000;
+
+///A persistent table holding the reduction results.  
+///Results are buffered in a shared batch so ResultTable::flush needs to be called after the last add
 pub struct ResultTable {
-    db: Arc<rocksdb::DB>
+    db: Arc<rocksdb::DB>,
+    batch: Arc<Mutex<rocksdb::WriteBatch>>,
+    encoding: ResultEncoding,
+    ///the column family of an external db the results are written to, the default one otherwise
+    column_family: Option<String>
 }
 
 impl Clone for ResultTable {
     fn clone(&self) -> Self {
         ResultTable {
-            db: self.db.clone()
+            db: self.db.clone(),
+            batch: self.batch.clone(),
+            encoding: self.encoding,
+            column_family: self.column_family.clone()
         }
     }
 }
 
 impl ResultTable {
-    ///Creates the table under path
-    pub fn new(path: &PathBuf) -> Result<ResultTable> {
+    ///Creates the table under path, results are stored with encoding.  
+    ///With an external column family the table is created in that column family of the user's db instead
+    pub fn new(path: &PathBuf, encoding: ResultEncoding, external: Option<&ResultColumnFamily>) -> Result<ResultTable> {
+        if let Some(external) = external {
+            return ResultTable::open_column_family(external, encoding);
+        }
         let root_dir = path.clone();
         let mut index_path = root_dir.clone();
         index_path.push("results");
@@ -26,27 +45,104 @@ impl ResultTable {
         opts.create_if_missing(true);
         let db = rocksdb::DB::open_default(&index_path).with_context(|| format!("Could not create result table in: {}", index_path.display()))?;
 
-        Ok(ResultTable {db: Arc::new(db)})
+        Ok(ResultTable {db: Arc::new(db), batch: Arc::new(Mutex::new(rocksdb::WriteBatch::default())), encoding, column_family: None})
+    }
+
+    ///Opens the external db with all its column families, the results column family is created if it is missing.  
+    ///An existing results column family is only dropped and recreated if replace is set, otherwise the results are added to its entries
+    fn open_column_family(external: &ResultColumnFamily, encoding: ResultEncoding) -> Result<ResultTable> {
+        let ResultColumnFamily { db_path, name, replace } = external;
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        //a new db has no column families to list
+        let column_families = if db_path.join("CURRENT").exists() {
+            rocksdb::DB::list_cf(&opts, db_path).with_context(|| format!("Could not list the column families of result db: {}", db_path.display()))?
+        } else {
+            vec![String::from("default")]
+        };
+        let mut db = rocksdb::DB::open_cf(&opts, db_path, &column_families).with_context(|| format!("Could not open result db: {}", db_path.display()))?;
+        let exists = column_families.contains(name);
+        if exists && *replace {
+            db.drop_cf(name).with_context(|| format!("Could not clear column family {} in: {}", name, db_path.display()))?;
+        }
+        if !exists || *replace {
+            db.create_cf(name, &rocksdb::Options::default()).with_context(|| format!("Could not create column family {} in: {}", name, db_path.display()))?;
+        }
+
+        Ok(ResultTable {db: Arc::new(db), batch: Arc::new(Mutex::new(rocksdb::WriteBatch::default())), encoding, column_family: Some(name.clone())})
+    }
+
+    ///The handle of the external column family, None for the table's own db
+    fn cf(&self) -> Option<&rocksdb::ColumnFamily> {
+        //the column family is created when the table is opened and never dropped
+        self.column_family.as_ref().map(|name| self.db.cf_handle(name).expect("Result column family is missing"))
     }
 
-    ///Adds a new entry to the table
+    ///Adds a new entry to the table, the batch is written once it reaches RESULT_BATCH_SIZE entries
     pub fn add(&self, key: &str, result: &str) -> Result<()> {
-        self.db.put(key, result).context("Could not save result")
+        let result = self.encoding.encode(result)?;
+        let mut batch = self.batch.lock().unwrap();
+        match self.cf() {
+            Some(cf) => batch.put_cf(cf, key, result),
+            None => batch.put(key, result)
+        }
+        if batch.len() >= RESULT_BATCH_SIZE {
+            self.db.write(mem::take(&mut *batch)).context("Could not save results")?;
+        }
+        Ok(())
+    }
+
+    ///Writes any buffered entries to the table
+    pub fn flush(&self) -> Result<()> {
+        let mut batch = self.batch.lock().unwrap();
+        if !batch.is_empty() {
+            self.db.write(mem::take(&mut *batch)).context("Could not save results")?;
+        }
+        Ok(())
+    }
+
+    ///Checks if the table has no entries, only the first one is read
+    pub fn is_empty(&self) -> bool {
+        self.raw_iter(rocksdb::IteratorMode::Start).next().is_none()
     }
 
     ///Creates an iterator over the table entries
     pub fn iter(&self, order: ResultsOrdering) -> ResultTableIterator {
-        match order {
-            ResultsOrdering::Asc => {
-                ResultTableIterator { iterator: self.db.iterator(rocksdb::IteratorMode::Start) }
-            },
-            ResultsOrdering::Desc => {
-                ResultTableIterator { iterator: self.db.iterator(rocksdb::IteratorMode::End) }
-            }
+        let mode = match order {
+            ResultsOrdering::Asc => rocksdb::IteratorMode::Start,
+            ResultsOrdering::Desc => rocksdb::IteratorMode::End
+        };
+        ResultTableIterator { iterator: self.raw_iter(mode), encoding: self.encoding }
+    }
+
+    fn raw_iter(&self, mode: rocksdb::IteratorMode) -> rocksdb::DBIterator {
+        match self.cf() {
+            Some(cf) => self.db.iterator_cf(cf, mode),
+            None => self.db.iterator(mode)
         }
     }
 }
 
+///A column family of a user provided rocksdb the results are written to, with --result-db and --result-cf
+pub struct ResultColumnFamily {
+    pub db_path: PathBuf,
+    pub name: String,
+    ///drop an existing column family instead of adding to it, with --replace-result-cf
+    pub replace: bool
+}
+
+///The error of an empty result table with --require-output
+#[derive(Debug)]
+pub struct EmptyOutput;
+
+impl fmt::Display for EmptyOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "No results were produced")
+    }
+}
+
+impl Error for EmptyOutput {}
+
 pub enum ResultsOrdering {
     Asc,
     Desc
@@ -64,21 +160,151 @@ impl ResultsOrdering {
 
 ///Wrapper around the rocksdb iterator to create a higher level iterator that also deserializes the entries
 pub struct ResultTableIterator<'r> {
-    iterator: rocksdb::DBIterator<'r>
+    iterator: rocksdb::DBIterator<'r>,
+    encoding: ResultEncoding
 }
 
 impl<'r> Iterator for ResultTableIterator<'r> {
-    type Item = (String, String);
+    type Item = Result<(String, String)>;
 
-    fn next(&mut self) -> Option<(String, String)> {
+    ///A result that can not be decoded is returned as an error with its key
+    fn next(&mut self) -> Option<Result<(String, String)>> {
         let result = self.iterator.next();
         match result {
             Some((key, value)) => {
-                let key = String::from_utf8_lossy(&key);
-                let value = String::from_utf8_lossy(&value);
-                Some((key.into_owned(), value.into_owned()))
+                let key = String::from_utf8_lossy(&key).into_owned();
+                match self.encoding.decode(&value) {
+                    Ok(value) => Some(Ok((key, value))),
+                    Err(err) => Some(Err(err.context(format!("Could not decode the result of key {}", key))))
+                }
             },
             None => None
         }
     }
 }
+
+///How the results are stored in the table
+#[derive(Clone, Copy)]
+pub enum ResultEncoding {
+    ///the result string as is
+    Json,
+    ///results that are json are stored as a bincode value tree, which is smaller for large numeric results
+    Bincode
+}
+
+impl ResultEncoding {
+    pub fn new(encoding: &str) -> ResultEncoding {
+        if encoding == "bincode" {
+            ResultEncoding::Bincode
+        } else {
+            ResultEncoding::Json
+        }
+    }
+
+    fn encode(self, result: &str) -> Result<Vec<u8>> {
+        match self {
+            ResultEncoding::Json => Ok(result.as_bytes().to_vec()),
+            ResultEncoding::Bincode => {
+                //only results that serialize back to the exact same string are stored as a tree, so decoding never changes the output
+                let stored = match serde_json::from_str::<serde_json::Value>(result) {
+                    Ok(value) if serde_json::to_string(&value).ok().as_deref() == Some(result) => StoredResult::Tree(BinaryValue::from(value)),
+                    _ => StoredResult::Text(result.to_owned())
+                };
+                bincode::serialize(&stored).context("Could not encode result")
+            }
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> Result<String> {
+        match self {
+            ResultEncoding::Json => Ok(String::from_utf8_lossy(bytes).into_owned()),
+            ResultEncoding::Bincode => {
+                match bincode::deserialize(bytes).context("Could not decode result")? {
+                    StoredResult::Tree(value) => Ok(serde_json::Value::from(value).to_string()),
+                    StoredResult::Text(result) => Ok(result)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum StoredResult {
+    Tree(BinaryValue),
+    Text(String)
+}
+
+///A json value that bincode can serialize, serde_json::Value can only be deserialized from self describing formats
+#[derive(Serialize, Deserialize)]
+enum BinaryValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    String(String),
+    Array(Vec<BinaryValue>),
+    Object(Vec<(String, BinaryValue)>)
+}
+
+impl From<serde_json::Value> for BinaryValue {
+    fn from(value: serde_json::Value) -> BinaryValue {
+        match value {
+            serde_json::Value::Null => BinaryValue::Null,
+            serde_json::Value::Bool(b) => BinaryValue::Bool(b),
+            serde_json::Value::Number(n) => {
+                if let Some(n) = n.as_i64() {
+                    BinaryValue::Int(n)
+                } else if let Some(n) = n.as_u64() {
+                    BinaryValue::UInt(n)
+                } else {
+                    BinaryValue::Float(n.as_f64().unwrap_or_default())
+                }
+            },
+            serde_json::Value::String(s) => BinaryValue::String(s),
+            serde_json::Value::Array(items) => BinaryValue::Array(items.into_iter().map(BinaryValue::from).collect()),
+            serde_json::Value::Object(fields) => BinaryValue::Object(fields.into_iter().map(|(k, v)| (k, BinaryValue::from(v))).collect())
+        }
+    }
+}
+
+impl From<BinaryValue> for serde_json::Value {
+    fn from(value: BinaryValue) -> serde_json::Value {
+        match value {
+            BinaryValue::Null => serde_json::Value::Null,
+            BinaryValue::Bool(b) => serde_json::Value::Bool(b),
+            BinaryValue::Int(n) => serde_json::Value::from(n),
+            BinaryValue::UInt(n) => serde_json::Value::from(n),
+            BinaryValue::Float(n) => serde_json::Value::from(n),
+            BinaryValue::String(s) => serde_json::Value::String(s),
+            BinaryValue::Array(items) => serde_json::Value::Array(items.into_iter().map(serde_json::Value::from).collect()),
+            BinaryValue::Object(fields) => serde_json::Value::Object(fields.into_iter().map(|(k, v)| (k, serde_json::Value::from(v))).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_undecodable_result() {
+        let mut dir = temp_dir();
+        dir.push(format!("omnimap-test-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let table = ResultTable::new(&dir, ResultEncoding::Bincode, None).unwrap();
+        table.add("a", "{\"total\":1}").unwrap();
+        table.flush().unwrap();
+        table.db.put("b", [0xff, 0xff, 0xff]).unwrap();
+        let mut entries = table.iter(ResultsOrdering::Asc);
+        assert_eq!(entries.next().unwrap().unwrap(), (String::from("a"), String::from("{\"total\":1}")));
+        let err = entries.next().unwrap().unwrap_err();
+        assert!(format!("{:#}", err).contains("Could not decode the result of key b"));
+        drop(entries);
+        drop(table);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
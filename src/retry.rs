@@ -0,0 +1,47 @@
+use std::thread;
+use std::time::Duration;
+use anyhow::Result;
+
+///The wait before the first retry, it is doubled on every next one
+const FIRST_BACKOFF_MS: u64 = 100;
+
+///Runs f and retries it up to `retries` times with an exponential backoff while it fails.  
+///Returns the error of the last attempt when all of them fail
+pub fn with_retries<T, F: FnMut() -> Result<T>>(retries: usize, mut f: F) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < retries => {
+                let backoff = FIRST_BACKOFF_MS << attempt.min(10);
+                eprintln!("Retrying in {}ms after error: {:#}", backoff, err);
+                thread::sleep(Duration::from_millis(backoff));
+                attempt += 1;
+            },
+            Err(err) => return Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_with_retries() {
+        let mut attempts = 0;
+        let result = with_retries(2, || {
+            attempts += 1;
+            if attempts < 3 { Err(anyhow!("failed")) } else { Ok(attempts) }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut attempts = 0;
+        let result: Result<()> = with_retries(1, || {
+            attempts += 1;
+            Err(anyhow!("failed {}", attempts))
+        });
+        assert_eq!(result.unwrap_err().to_string(), "failed 2");
+    }
+}
@@ -0,0 +1,52 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::path::PathBuf;
+use anyhow::{Result, Context};
+
+use super::cli::CLIOptions;
+use super::error_output::ErrorOutput;
+use super::reducer::{PartialWriter, ErrorWriter};
+use super::metrics::{Metrics, write_prometheus};
+
+///The files a run writes besides its results.  
+///They are opened once before the first stage so every stage of a chain writes to the same files instead of replacing them
+pub struct RunOutputs {
+    pub error_output: ErrorOutput,
+    pub partial_writer: Option<PartialWriter>,
+    pub error_writer: Option<ErrorWriter>,
+    metrics_file: Option<PathBuf>,
+    ///the metrics of each finished stage, written together at the end
+    metrics: RefCell<Vec<Metrics>>
+}
+
+impl RunOutputs {
+    pub fn open(options: &CLIOptions) -> Result<RunOutputs> {
+        Ok(RunOutputs {
+            error_output: ErrorOutput::new(options.error_output.as_ref())?,
+            partial_writer: match &options.emit_partial {
+                Some(path) => Some(PartialWriter::new(path)?),
+                None => None
+            },
+            error_writer: match &options.reduce_error_output {
+                Some(path) => Some(ErrorWriter::new(path)?),
+                None => None
+            },
+            metrics_file: options.metrics_file.clone(),
+            metrics: RefCell::new(vec![])
+        })
+    }
+
+    ///Keeps the metrics of a finished stage
+    pub fn record_metrics(&self, metrics: Metrics) {
+        self.metrics.borrow_mut().push(metrics);
+    }
+
+    ///Writes the metrics of all the stages to the metrics file if there is one
+    pub fn finish(self) -> Result<()> {
+        if let Some(path) = &self.metrics_file {
+            let mut file = File::create(path).with_context(|| format!("Could not create metrics file: {}", path.display()))?;
+            write_prometheus(&self.metrics.borrow(), &mut file).with_context(|| format!("Could not write metrics file: {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde_json::{Value, Map, json};
+use anyhow::Result;
+use super::result_table::{ResultTable, ResultsOrdering};
+use super::printer::entry_result;
+
+///How many results are sampled to infer the schema
+const SCHEMA_SAMPLE_SIZE: usize = 1000;
+
+///Infers a JSON Schema from a sample of the results in ResultTable.  
+///Results that are not valid json are treated as strings
+pub fn infer_schema(result_table: &ResultTable, with_values: bool) -> Result<Value> {
+    let mut results = vec![];
+    for pair in result_table.iter(ResultsOrdering::Asc).take(SCHEMA_SAMPLE_SIZE) {
+        let (_, entry) = pair?;
+        results.push(entry_result(entry, with_values)?);
+    }
+    Ok(infer(results))
+}
+
+fn infer<I: IntoIterator<Item = String>>(results: I) -> Value {
+    let mut schema = Schema::default();
+    for result in results {
+        let value = serde_json::from_str(&result).unwrap_or(Value::String(result));
+        schema.add(&value);
+    }
+    let mut root = schema.to_json();
+    root.insert(String::from("$schema"), json!("http://json-schema.org/draft-07/schema#"));
+    Value::Object(root)
+}
+
+///The merged shape of all the values seen at the same position
+#[derive(Default)]
+struct Schema {
+    types: BTreeSet<&'static str>,
+    ///how many of the values were objects, a property is required if it was seen in all of them
+    objects: usize,
+    ///how many times the value was seen as a property of an object
+    seen: usize,
+    properties: BTreeMap<String, Schema>,
+    items: Option<Box<Schema>>
+}
+
+impl Schema {
+    fn add(&mut self, value: &Value) {
+        let value_type = match value {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(n) if n.is_f64() => "number",
+            Value::Number(_) => "integer",
+            Value::String(_) => "string",
+            Value::Array(items) => {
+                let items_schema = self.items.get_or_insert_with(Default::default);
+                for item in items {
+                    items_schema.add(item);
+                }
+                "array"
+            },
+            Value::Object(fields) => {
+                self.objects += 1;
+                for (name, field) in fields {
+                    let property = self.properties.entry(name.clone()).or_default();
+                    property.seen += 1;
+                    property.add(field);
+                }
+                "object"
+            }
+        };
+        self.types.insert(value_type);
+    }
+
+    fn to_json(&self) -> Map<String, Value> {
+        let mut schema = Map::new();
+        //an integer is also a number
+        let types: Vec<&str> = self.types.iter()
+            .filter(|t| **t != "integer" || !self.types.contains("number"))
+            .cloned()
+            .collect();
+        match types.len() {
+            //an array that was always empty can have any items
+            0 => {},
+            1 => { schema.insert(String::from("type"), json!(types[0])); },
+            _ => { schema.insert(String::from("type"), json!(types)); }
+        }
+        if !self.properties.is_empty() {
+            let properties: Map<String, Value> = self.properties.iter()
+                .map(|(name, property)| (name.clone(), Value::Object(property.to_json())))
+                .collect();
+            let required: Vec<&String> = self.properties.iter()
+                .filter(|(_, property)| property.seen == self.objects)
+                .map(|(name, _)| name)
+                .collect();
+            schema.insert(String::from("properties"), Value::Object(properties));
+            schema.insert(String::from("required"), json!(required));
+        }
+        if let Some(items) = &self.items {
+            schema.insert(String::from("items"), Value::Object(items.to_json()));
+        }
+        schema
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn infer_from(results: &[&str]) -> Value {
+        infer(results.iter().map(|result| result.to_string()))
+    }
+
+    #[test]
+    fn test_scalars() {
+        assert_eq!(infer_from(&["1", "2"])["type"], json!("integer"));
+        assert_eq!(infer_from(&["1", "2.5"])["type"], json!("number"));
+        assert_eq!(infer_from(&["abc", "1"])["type"], json!(["integer", "string"]));
+        assert_eq!(infer_from(&["null", "true"])["type"], json!(["boolean", "null"]));
+    }
+
+    #[test]
+    fn test_objects() {
+        let schema = infer_from(&[
+            r#"{"count": 1, "tags": ["a"], "user": {"name": "x"}}"#,
+            r#"{"count": 2.5, "tags": [], "user": {"name": "y", "age": 3}}"#
+        ]);
+        assert_eq!(schema, json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "object",
+            "properties": {
+                "count": {"type": "number"},
+                "tags": {"type": "array", "items": {"type": "string"}},
+                "user": {
+                    "type": "object",
+                    "properties": {
+                        "age": {"type": "integer"},
+                        "name": {"type": "string"}
+                    },
+                    "required": ["name"]
+                }
+            },
+            "required": ["count", "tags", "user"]
+        }));
+    }
+}
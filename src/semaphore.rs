@@ -0,0 +1,57 @@
+use std::sync::{Arc, Mutex, Condvar};
+
+///A counting semaphore that hands out permits which are released when dropped
+pub struct Semaphore {
+    permits: Arc<(Mutex<usize>, Condvar)>
+}
+
+impl Clone for Semaphore {
+    fn clone(&self) -> Semaphore {
+        Semaphore {
+            permits: self.permits.clone()
+        }
+    }
+}
+
+impl Semaphore {
+    ///Creates a semaphore with `permits` available permits
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: Arc::new((Mutex::new(permits), Condvar::new()))
+        }
+    }
+
+    ///Blocks until a permit is available and takes it
+    pub fn acquire(&self) -> Permit {
+        let (lock, cvar) = &*self.permits;
+        let mut available = cvar.wait_while(
+            lock.lock().unwrap(),
+            |available| {
+                *available == 0
+            }
+        ).unwrap();
+        *available -= 1;
+
+        Permit {
+            semaphore: self.clone()
+        }
+    }
+
+    fn release(&self) {
+        let (lock, cvar) = &*self.permits;
+        let mut available = lock.lock().unwrap();
+        *available += 1;
+        cvar.notify_one();
+    }
+}
+
+///A taken permit, gets returned to its semaphore on drop
+pub struct Permit {
+    semaphore: Semaphore
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
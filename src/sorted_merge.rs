@@ -0,0 +1,143 @@
+use std::io::BufRead;
+use std::collections::{BinaryHeap, VecDeque};
+use std::cmp::Ordering;
+use anyhow::{Result, anyhow};
+use super::json_line::from_json;
+
+///Sorts the values of a key numerically, fails if any of them is not a number.  
+///The sort is stable so equal numbers keep their order
+pub fn sort_numeric(key: &str, values: Vec<String>) -> Result<Vec<String>> {
+    let mut numbers = Vec::with_capacity(values.len());
+    for value in values {
+        numbers.push((to_number(key, &value)?, value));
+    }
+    numbers.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    Ok(numbers.into_iter().map(|(_, value)| value).collect())
+}
+
+fn to_number(key: &str, value: &str) -> Result<f64> {
+    match value.trim().parse::<f64>() {
+        Ok(number) if !number.is_nan() => Ok(number),
+        _ => Err(anyhow!("Key {} has a value that is not a number: {}", key, value))
+    }
+}
+
+///A numerically sorted sequence of values, either json lines of values or values in memory
+pub struct Run<'a> {
+    lines: Option<Box<dyn BufRead + 'a>>,
+    pending: VecDeque<String>
+}
+
+impl<'a> Run<'a> {
+    ///A run read from json lines, each line is an array of values that continues the sorted order of the previous one
+    pub fn lines<R: BufRead + 'a>(reader: R) -> Run<'a> {
+        Run { lines: Some(Box::new(reader)), pending: VecDeque::new() }
+    }
+
+    ///A run of values that are already sorted
+    pub fn values(values: Vec<String>) -> Run<'a> {
+        Run { lines: None, pending: values.into() }
+    }
+
+    ///Returns the next value, only one line of the run is kept in memory
+    fn next(&mut self) -> Result<Option<String>> {
+        while self.pending.is_empty() {
+            let lines = match &mut self.lines {
+                Some(lines) => lines,
+                None => return Ok(None)
+            };
+            let mut line = String::new();
+            if lines.read_line(&mut line)? == 0 {
+                self.lines = None;
+                return Ok(None);
+            }
+            let values: Vec<String> = from_json(&line)?;
+            self.pending = values.into();
+        }
+        Ok(self.pending.pop_front())
+    }
+}
+
+///The next value of a run in the merge heap
+struct Head {
+    number: f64,
+    run: usize,
+    value: String
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Head) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Head) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    //reversed so the max-heap pops the smallest number, equal numbers are taken from the earlier run first
+    fn cmp(&self, other: &Head) -> Ordering {
+        other.number.partial_cmp(&self.number).unwrap().then_with(|| other.run.cmp(&self.run))
+    }
+}
+
+///K-way merge of sorted runs, used with --sort-values.  
+///Only the next value of each run is compared so the runs are never loaded whole
+pub struct SortedMerge<'a> {
+    key: String,
+    runs: Vec<Run<'a>>,
+    heads: BinaryHeap<Head>
+}
+
+impl<'a> SortedMerge<'a> {
+    pub fn new(key: &str, mut runs: Vec<Run<'a>>) -> Result<SortedMerge<'a>> {
+        let mut heads = BinaryHeap::with_capacity(runs.len());
+        for (run, values) in runs.iter_mut().enumerate() {
+            if let Some(value) = values.next()? {
+                heads.push(Head { number: to_number(key, &value)?, run, value });
+            }
+        }
+        Ok(SortedMerge { key: key.to_owned(), runs, heads })
+    }
+
+    ///Returns the smallest of the remaining values
+    pub fn next(&mut self) -> Result<Option<String>> {
+        let head = match self.heads.pop() {
+            Some(head) => head,
+            None => return Ok(None)
+        };
+        if let Some(value) = self.runs[head.run].next()? {
+            self.heads.push(Head { number: to_number(&self.key, &value)?, run: head.run, value });
+        }
+        Ok(Some(head.value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_sorted_merge() {
+        let lines = Cursor::new("[\"-1\",\"2\"]\n[\"2.5\",\"10\"]\n");
+        let values = sort_numeric("k", strings(&["3", "2", "-7"])).unwrap();
+        assert_eq!(values, strings(&["-7", "2", "3"]));
+        let mut merge = SortedMerge::new("k", vec![Run::lines(lines), Run::values(values), Run::values(vec![])]).unwrap();
+        let mut merged = vec![];
+        while let Some(value) = merge.next().unwrap() {
+            merged.push(value);
+        }
+        assert_eq!(merged, strings(&["-7", "-1", "2", "2", "2.5", "3", "10"]));
+        assert!(sort_numeric("k", strings(&["1", "one"])).is_err());
+    }
+}
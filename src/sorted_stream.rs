@@ -0,0 +1,90 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, mpsc::{Sender, Receiver, channel}};
+
+///Streams the results in key order while the keys are still being reduced, used with --stream-sorted.  
+///The consumer registers the keys in the sorted order it visits them and the reducer marks them done, a result is sent once every key before it is done
+pub struct SortedStream {
+    state: Arc<Mutex<StreamState>>
+}
+
+struct StreamState {
+    ///the registered keys that are not sent yet, in key order
+    pending: VecDeque<Arc<String>>,
+    ///the entries of the done keys that wait for an earlier key, None for a failed key
+    done: HashMap<Arc<String>, Option<String>>,
+    sender: Sender<(String, String)>
+}
+
+impl Clone for SortedStream {
+    fn clone(&self) -> SortedStream {
+        SortedStream {
+            state: self.state.clone()
+        }
+    }
+}
+
+impl SortedStream {
+    ///The receiver gets the (key, entry) pairs in key order, it ends once every clone of the stream is dropped
+    pub fn new() -> (SortedStream, Receiver<(String, String)>) {
+        let (sender, receiver) = channel();
+        let stream = SortedStream {
+            state: Arc::new(Mutex::new(StreamState {
+                pending: VecDeque::new(),
+                done: HashMap::new(),
+                sender
+            }))
+        };
+        (stream, receiver)
+    }
+
+    ///Registers the next key that is sent to the reducer, keys must be registered in their output order
+    pub fn register(&self, key: Arc<String>) {
+        self.state.lock().unwrap().pending.push_back(key);
+    }
+
+    ///Marks a registered key as reduced and sends every done key that no longer waits for an earlier one.  
+    ///A failed key has no entry, it is skipped
+    pub fn done(&self, key: &Arc<String>, entry: Option<String>) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.done.insert(key.clone(), entry);
+        while let Some(first) = state.pending.front() {
+            let entry = match state.done.remove(first) {
+                Some(entry) => entry,
+                None => break
+            };
+            let first = state.pending.pop_front().unwrap();
+            if let Some(entry) = entry {
+                //the receiver is only gone if printing failed, that error is reported by the printer
+                let _ = state.sender.send((first.to_string(), entry));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sorted_stream() {
+        let (stream, receiver) = SortedStream::new();
+        let keys: Vec<Arc<String>> = ["a", "b", "c", "d"].iter().map(|key| Arc::new(key.to_string())).collect();
+        for key in &keys {
+            stream.register(key.clone());
+        }
+        //c and b wait for a, the failed b is skipped
+        stream.done(&keys[2], Some(String::from("3")));
+        stream.done(&keys[1], None);
+        assert!(receiver.try_recv().is_err());
+        stream.done(&keys[0], Some(String::from("1")));
+        stream.done(&keys[3], Some(String::from("4")));
+        drop(stream);
+        let sent: Vec<(String, String)> = receiver.iter().collect();
+        assert_eq!(sent, vec![
+            (String::from("a"), String::from("1")),
+            (String::from("c"), String::from("3")),
+            (String::from("d"), String::from("4"))
+        ]);
+    }
+}
@@ -0,0 +1,114 @@
+use std::thread::{Builder, JoinHandle};
+use std::collections::{HashMap, BTreeMap};
+use std::sync::{Arc, RwLock, mpsc::{Sender, channel}};
+use anyhow::{Context, Result, anyhow};
+
+use super::combiner::{combine_map_results, KeyGrouping};
+use super::js::{MapResult, ContextBuilder, Context as JsContext};
+use super::indexer::{IndexGuard, take_ready};
+
+///The running reduce result of each key sorted by key
+pub type Accumulated = BTreeMap<String, String>;
+
+///Spawns the thread that reduces the map results as they arrive, used instead of the indexer with --streaming-reduce.  
+///Every key only keeps its running result, the values of each map task are reduced and then rereduced together with the result so far.  
+///Fails if reduce() is not rereduce safe on the first key that is merged with its result so far.  
+///Returns the result of each key sorted by key when joined.
+pub fn spawn_streaming_reducer(
+    context_builder: Arc<ContextBuilder>,
+    ordered: bool,
+    grouping: KeyGrouping
+) -> Result<(JoinHandle<Result<Accumulated>>, Sender<(usize, Vec<MapResult>)>, IndexGuard)> {
+    let (sender, receiver) = channel();
+    let handle = Builder::new().name(String::from("omnimap-streaming-reducer")).spawn(move|| -> Result<Accumulated> {
+        let context = context_builder.build()?;
+        let mut accumulator = Accumulator::new(&context, grouping);
+        let mut pending = BTreeMap::new();
+        let mut next_sequence = 0;
+        //the first error, the rest of the map results are only drained after it
+        let mut reduce_error = None;
+        for (sequence, results) in receiver.iter() {
+            if reduce_error.is_some() {
+                continue;
+            }
+            let ready = if ordered {
+                take_ready(&mut pending, &mut next_sequence, sequence, results)
+            } else {
+                vec![results]
+            };
+            for results in ready {
+                if let Err(err) = accumulator.add(results) {
+                    reduce_error = Some(err);
+                    break;
+                }
+            }
+        }
+        if let Some(err) = reduce_error {
+            return Err(err);
+        }
+        for (_, results) in pending {
+            accumulator.add(results)?;
+        }
+        Ok(accumulator.finish())
+    }).context("Could not spawn streaming reducer thread")?;
+
+    //nothing is indexed so the mapper never has to wait
+    Ok((handle, sender, IndexGuard::new()))
+}
+
+///The running results of the keys, keyed by their grouping key
+struct Accumulator<'c> {
+    context: &'c JsContext,
+    grouping: KeyGrouping,
+    ///the key in its first seen form and its result so far
+    results: HashMap<String, (String, String)>,
+    ///the values of each key until the first merge checks that reduce() is rereduce safe, None after it
+    unchecked_values: Option<HashMap<String, Vec<String>>>
+}
+
+impl<'c> Accumulator<'c> {
+    fn new(context: &'c JsContext, grouping: KeyGrouping) -> Accumulator<'c> {
+        Accumulator {
+            context,
+            grouping,
+            results: HashMap::new(),
+            unchecked_values: Some(HashMap::new())
+        }
+    }
+
+    ///Reduces the values of a map task by key and merges them into the running results
+    fn add(&mut self, map_results: Vec<MapResult>) -> Result<()> {
+        let mut bucket_list = vec![Arc::new(RwLock::new(HashMap::new()))];
+        combine_map_results(&mut bucket_list, map_results, 1, &self.grouping)?;
+        let mut bucket = bucket_list[0].write().unwrap();
+        for (group_key, container) in bucket.drain() {
+            let key = match self.results.get(&group_key) {
+                Some((key, _)) => key.clone(),
+                None => container.display_key(&group_key).to_owned()
+            };
+            let reduced = self.context.run_reduce(&key, &container.values, false)?;
+            let result = match self.results.remove(&group_key) {
+                Some((_, result)) => self.context.run_reduce(&key, &vec![result, reduced], true)?,
+                None => reduced
+            };
+            if let Some(unchecked_values) = &mut self.unchecked_values {
+                let values = unchecked_values.entry(group_key.clone()).or_default();
+                let merged = !values.is_empty();
+                values.extend(container.values);
+                //the result in steps has to match reducing all the values so far at once
+                if merged {
+                    if self.context.run_reduce(&key, values, false)? != result {
+                        return Err(anyhow!("reduce() is not rereduce safe so it can not be used with --streaming-reduce, key {} gives a different result when its values are reduced in steps", key));
+                    }
+                    self.unchecked_values = None;
+                }
+            }
+            self.results.insert(group_key, (key, result));
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Accumulated {
+        self.results.into_iter().map(|(_, (key, result))| (key, result)).collect()
+    }
+}
@@ -79,6 +79,7 @@ impl<'a> Drop for Sentinel<'a> {
 pub struct Builder {
     num_threads: Option<usize>,
     thread_name: Option<String>,
+    number_thread_names: bool,
     thread_stack_size: Option<usize>,
 }
 
@@ -96,6 +97,7 @@ impl Builder {
         Builder {
             num_threads: None,
             thread_name: None,
+            number_thread_names: false,
             thread_stack_size: None,
         }
     }
@@ -159,6 +161,24 @@ impl Builder {
         self
     }
 
+    /// Append a sequence number to the thread name of each spawned thread, like "foo-0", "foo-1".
+    /// Threads spawned to replace panicked ones get the next number. Has no effect if no thread
+    /// name is set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let pool = threadpool::Builder::new()
+    ///     .num_threads(2)
+    ///     .thread_name("foo".into())
+    ///     .number_thread_names()
+    ///     .build();
+    /// ```
+    pub fn number_thread_names(mut self) -> Builder {
+        self.number_thread_names = true;
+        self
+    }
+
     /// Set the stack size (in bytes) for each of the threads spawned by the built [`ThreadPool`].
     /// If not specified, threads spawned by the threadpool will have a stack size [as specified in
     /// the `std::thread` documentation][thread].
@@ -205,6 +225,8 @@ impl Builder {
 
         let shared_data = Arc::new(ThreadPoolSharedData {
             name: self.thread_name,
+            number_names: self.number_thread_names,
+            spawned_count: AtomicUsize::new(0),
             job_receiver: Mutex::new(rx),
             empty_condvar: Condvar::new(),
             empty_trigger: Mutex::new(()),
@@ -230,6 +252,8 @@ impl Builder {
 
 struct ThreadPoolSharedData {
     name: Option<String>,
+    number_names: bool,
+    spawned_count: AtomicUsize,
     job_receiver: Mutex<Receiver<Thunk<'static>>>,
     empty_trigger: Mutex<()>,
     empty_condvar: Condvar,
@@ -650,7 +674,12 @@ impl Eq for ThreadPool {}
 fn spawn_in_pool(shared_data: Arc<ThreadPoolSharedData>) {
     let mut builder = thread::Builder::new();
     if let Some(ref name) = shared_data.name {
-        builder = builder.name(name.clone());
+        if shared_data.number_names {
+            let number = shared_data.spawned_count.fetch_add(1, Ordering::SeqCst);
+            builder = builder.name(format!("{}-{}", name, number));
+        } else {
+            builder = builder.name(name.clone());
+        }
     }
     if let Some(ref stack_size) = shared_data.stack_size {
         builder = builder.stack_size(stack_size.to_owned());
@@ -928,6 +957,32 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_numbered_names() {
+        let pool = Builder::new()
+            .num_threads(2)
+            .thread_name("test".into())
+            .number_thread_names()
+            .build();
+        let (tx, rx) = sync_channel(0);
+        let barrier = Arc::new(Barrier::new(2));
+
+        // block both threads so each job runs on a different one
+        for _ in 0..2 {
+            let tx = tx.clone();
+            let barrier = barrier.clone();
+            pool.execute(move || {
+                barrier.wait();
+                let name = thread::current().name().unwrap().to_owned();
+                tx.send(name).unwrap();
+            });
+        }
+
+        let mut names: Vec<String> = rx.iter().take(2).collect();
+        names.sort();
+        assert_eq!(names, vec!["test-0", "test-1"]);
+    }
+
     #[test]
     fn test_debug() {
         let pool = ThreadPool::new(4);